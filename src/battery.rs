@@ -0,0 +1,24 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+// Battery saves live next to the ROM: path/to/game.nes -> path/to/game.sav
+pub fn save_path(rom_path: &str) -> PathBuf {
+    Path::new(rom_path).with_extension("sav")
+}
+
+pub fn load(path: &Path) -> Option<Vec<u8>> {
+    fs::read(path).ok()
+}
+
+// Write to a temporary file and rename it over the old save, so a crash
+// mid-write never leaves a truncated .sav behind
+pub fn save(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("sav.tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
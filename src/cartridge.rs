@@ -1,55 +1,119 @@
-use crate::utils::{bit_0, bit_1, bit_3, byte_from_nibbles, hi_nibble};
-use std::{fs, iter};
+mod mapper;
+
+use crate::region::Region;
+use crate::utils::{
+    bit_0, bit_1, bit_3, byte_from_nibbles, hi_nibble, lo_nibble,
+};
+use mapper::{CpuTarget, Mapper, PpuTarget};
+use std::{iter, ops::Range};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
-    ALTERNATIVE,
+    // both quadrant pairs point at the lower (A) or upper (B) CIRAM page
+    SINGLE_SCREEN_A,
+    SINGLE_SCREEN_B,
+    // the cartridge adds 2 kB of VRAM so every quadrant is its own page
+    FOUR_SCREEN,
 }
 
 const NES_MAGIC: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 
-// Currently only supports iNES file format, mapper 0.
+// Currently supports iNES and NES 2.0 file formats, mappers 0, 19 and 69.
 // Validation is not rigorous yet, so be careful with rom selection.
 pub fn validate_rom(rom: &Vec<u8>) -> Result<u8, String> {
     // No magic number
+    if rom.len() < 16 {
+        return Err("error: not a nes rom".to_string());
+    }
     for i in 0..4 {
         if rom[i] != NES_MAGIC[i] {
             return Err("error: not a nes rom".to_string());
         }
     }
 
-    // Not iNES file format
-    if rom[7] & 0xc != 0 {
+    // Neither iNES nor NES 2.0 file format
+    if rom[7] & 0xc != 0 && !is_nes2(rom) {
         return Err("error: unsupported file format".to_string());
     }
 
-    // Not mapper 0
+    // Not a supported mapper, NES 2.0 mapper numbers go up to 4095
     let lo = hi_nibble(rom[6]);
     let hi = hi_nibble(rom[7]);
     let mapper = byte_from_nibbles(lo, hi);
-    if mapper != 0 {
-        return Err("error: unsupported mapper".to_string());
+    if is_nes2(rom) && lo_nibble(rom[8]) != 0 {
+        let mapper = (lo_nibble(rom[8]) as u16) << 8 | mapper as u16;
+        return Err(format!("error: unsupported mapper {}", mapper));
+    }
+    if !mapper::is_supported(mapper) {
+        return Err(format!("error: unsupported mapper {}", mapper));
+    }
+
+    // NES 2.0 exponent-multiplier ROM sizes
+    if is_nes2(rom) && (lo_nibble(rom[9]) == 0xF || hi_nibble(rom[9]) == 0xF) {
+        return Err("error: unsupported rom size".to_string());
+    }
+
+    // Shorter than the header says
+    let (prg_rom_size, chr_rom_size) = rom_sizes(rom);
+    if rom.len() < 16 + trainer_size(rom) + prg_rom_size + chr_rom_size {
+        return Err("error: rom file is truncated".to_string());
     }
 
     Ok(0)
 }
 
+fn is_nes2(rom: &[u8]) -> bool {
+    rom[7] & 0xc == 0x8
+}
+
+fn trainer_size(rom: &[u8]) -> usize {
+    if rom[6] & 0b100 != 0 {
+        512
+    } else {
+        0
+    }
+}
+
+// PRG and CHR ROM sizes in bytes. NES 2.0 adds upper bits to the bank
+// counts, the exponent form (MSB nibble 0xF) is rejected by validate_rom.
+fn rom_sizes(rom: &[u8]) -> (usize, usize) {
+    let (prg_msb, chr_msb) = if is_nes2(rom) {
+        (lo_nibble(rom[9]) as usize, hi_nibble(rom[9]) as usize)
+    } else {
+        (0, 0)
+    };
+    let prg_banks = prg_msb << 8 | rom[4] as usize;
+    let chr_banks = chr_msb << 8 | rom[5] as usize;
+    (0x4000 * usize::max(1, prg_banks), 0x2000 * chr_banks)
+}
+
+// The one copy of everything on the cartridge. The CPU bus and the PPU both
+// share it and go through the cpu_*/ppu_* methods below, so they always see
+// the same banks and the same CHR RAM.
 pub struct Cartridge {
-    pub has_trainer: bool,
-    pub has_sram: bool,
+    pub has_battery: bool,
 
+    // The iNES file as loaded, PRG and CHR ROM are ranges into it
+    rom: Vec<u8>,
+    prg_rom: Range<usize>,
+    chr_rom: Range<usize>,
+    chr_ram: Vec<u8>,
     pub sram: Vec<u8>,
-    pub prg_rom: Vec<u8>,
-    pub chr_rom: Vec<u8>,
-    pub mapper: u8,
+    // Battery backed bytes at the start of sram
+    prg_nvram_size: usize,
+    // Extra nametable RAM for four-screen boards
+    vram: Vec<u8>,
+    mapper: Box<dyn Mapper>,
 
     pub mirroring: Mirroring,
+    pub region: Region,
 }
 
 impl Cartridge {
-    pub fn new(rom: &Vec<u8>) -> Self {
+    pub fn new(rom: Vec<u8>) -> Result<Self, String> {
         /*  iNES file sections, in order:
             - Header,               16 B
             - Trainer,              0 or 512 B
@@ -71,9 +135,9 @@ impl Cartridge {
 
             Flags 6 bits:
             - 0         = 0b0: horizontal mirroring, 0b1: vertical mirroring
-            - 1         = 0b1: contains SRAM at [0x6000, 0x8000)
+            - 1         = 0b1: SRAM at [0x6000, 0x8000) is battery backed
             - 2         = 0b1: contains trainer at [0x7000, 0x7200)
-            - 3         = 0b1: alternative nametable layout
+            - 3         = 0b1: four-screen VRAM, overrides bit 0
             - [7,4]    = lower nibble of mapper number
 
             Flags 7 bits:
@@ -83,7 +147,7 @@ impl Cartridge {
             - [7,4]     = upper nibble of mapper number
 
             Flags 8 bits:
-            - [7,0] * 8 kB  = size of SRAM
+            - [7,0] * 8 kB  = size of SRAM, 0 infers 8 kB
 
             Flags 9 bits:
             - 0         = TV system (0: NTSC, 1: PAL)
@@ -93,12 +157,50 @@ impl Cartridge {
             - [1,0]     = TV system (0: NTSC, 2: PAL, 1/3: dual compatible)
             - 4         = 0b1: contains SRAM at [0x6000, 0x8000)
             - 5         = 0b1: has bus conflicts
+
+            NES 2.0 reuses bytes [8,15]:
+            - 8         = [3,0] mapper number bits [11,8], [7,4] submapper
+            - 9         = [3,0] PRG ROM size MSB, [7,4] CHR ROM size MSB
+            - 10        = PRG RAM (lo nibble) and PRG NVRAM (hi nibble),
+                          64 << n B each, 0 for none
+            - 11        = the same for CHR RAM and CHR NVRAM
+            - 12        = [1,0] timing (0: NTSC, 1: PAL, 2: multi, 3: Dendy)
         */
+        let nes2 = is_nes2(&rom);
 
-        let prg_rom_size = 0x4000 * usize::max(1, rom[4] as usize);
-        let chr_rom_size = 0x2000 * usize::max(1, rom[5] as usize);
-        let sram_size = 0x2000;
-        let trainer_size = if rom[6] & 0b100 != 0 { 512 } else { 0 };
+        let has_battery = bit_1(rom[6]) != 0;
+        let shift_size = |n: u8| if n == 0 { 0 } else { 64 << n };
+
+        let (prg_rom_size, chr_rom_size) = rom_sizes(&rom);
+        // CHR RAM only stands in when there is no CHR ROM. iNES leaves its
+        // size out, so it gets 8 kB, as do NES 2.0 headers that list none.
+        // CHR NVRAM is only found on boards without a supported mapper, so
+        // it counts as plain CHR RAM.
+        let chr_ram_size = if chr_rom_size != 0 {
+            0
+        } else if nes2 {
+            let size = shift_size(lo_nibble(rom[11]))
+                + shift_size(hi_nibble(rom[11]));
+            if size == 0 {
+                0x2000
+            } else {
+                size
+            }
+        } else {
+            0x2000
+        };
+        // At least 8 kB of SRAM either way, the trainer lives in it. NES 2.0
+        // NVRAM comes first and is the only part saved. iNES can't tell the
+        // two apart, so all of it is saved on battery boards.
+        let (sram_size, prg_nvram_size) = if nes2 {
+            let ram = shift_size(lo_nibble(rom[10]));
+            let nvram = shift_size(hi_nibble(rom[10]));
+            (usize::max(0x2000, ram + nvram), nvram)
+        } else {
+            let size = 0x2000 * usize::max(1, rom[8] as usize);
+            (size, if has_battery { size } else { 0 })
+        };
+        let trainer_size = trainer_size(&rom);
         let prg_start = 16 + trainer_size;
         let chr_start = prg_start + prg_rom_size;
         let mapper_lo = hi_nibble(rom[6]);
@@ -109,28 +211,417 @@ impl Cartridge {
             .chain(rom[16..16 + trainer_size].iter().cloned())
             .chain(iter::repeat(0u8).take(sram_size - 0x1000 - trainer_size))
             .collect();
-        let prg_rom = rom[prg_start..prg_start + prg_rom_size].to_vec();
-        let chr_rom = rom[chr_start..chr_start + chr_rom_size].to_vec();
-        let mapper = byte_from_nibbles(mapper_lo, mapper_hi);
+        let prg_rom = prg_start..prg_start + prg_rom_size;
+        let chr_rom = chr_start..chr_start + chr_rom_size;
+        let chr_ram = vec![0; chr_ram_size];
+        let mapper_id = byte_from_nibbles(mapper_lo, mapper_hi);
+        let mapper = mapper::new_mapper(
+            mapper_id,
+            prg_rom_size,
+            chr_rom_size + chr_ram_size,
+        )?;
 
         let mirroring = if bit_3(rom[6]) == 1 {
-            Mirroring::ALTERNATIVE
+            Mirroring::FOUR_SCREEN
         } else if bit_0(rom[6]) == 0 {
             Mirroring::HORIZONTAL
         } else {
             Mirroring::VERTICAL
         };
 
-        Cartridge {
-            has_trainer: trainer_size != 0,
-            has_sram: bit_1(rom[6]) != 0,
+        let region = if nes2 {
+            match rom[12] & 0b11 {
+                1 => Region::PAL,
+                3 => Region::DENDY,
+                // multi-region carts run on NTSC
+                _ => Region::NTSC,
+            }
+        } else if bit_0(rom[9]) == 1 {
+            Region::PAL
+        } else {
+            Region::NTSC
+        };
 
-            sram,
+        let vram = if mirroring == Mirroring::FOUR_SCREEN {
+            vec![0; 0x800]
+        } else {
+            Vec::new()
+        };
+
+        Ok(Cartridge {
+            has_battery,
+
+            rom,
             prg_rom,
             chr_rom,
+            chr_ram,
+            sram,
+            prg_nvram_size,
+            vram,
             mapper,
 
             mirroring,
+            region,
+        })
+    }
+
+    // CPU side, [0x4020, 0x10000). None leaves the bus open.
+    pub fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        let target = self.mapper.cpu_read(addr);
+        self.resolve_cpu(target)
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        // ROM writes land in the mapper's registers
+        if let CpuTarget::PrgRam(offset) = self.mapper.cpu_peek(addr) {
+            if self.mapper.prg_ram_writable(addr) {
+                let len = self.sram.len();
+                self.sram[offset % len] = data;
+            }
+        }
+        self.mapper.cpu_write(addr, data);
+    }
+
+    pub fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        self.resolve_cpu(self.mapper.cpu_peek(addr))
+    }
+
+    // PPU side, [0x0000, 0x3F00). The cartridge decides whether an access
+    // goes to CHR or to the console's CIRAM, which the PPU passes in.
+    pub fn ppu_read(&self, addr: u16, ciram: &[u8]) -> u8 {
+        match self.ppu_map(addr) {
+            PpuTarget::Chr(offset) => self.chr()[offset],
+            PpuTarget::Vram(offset) if offset < 0x800 => ciram[offset],
+            PpuTarget::Vram(offset) => self.vram[offset - 0x800],
         }
     }
+
+    pub fn ppu_write(&mut self, addr: u16, data: u8, ciram: &mut [u8]) {
+        match self.ppu_map(addr) {
+            PpuTarget::Chr(offset) => {
+                // CHR ROM ignores writes
+                if !self.chr_ram.is_empty() {
+                    self.chr_ram[offset] = data;
+                }
+            }
+            PpuTarget::Vram(offset) if offset < 0x800 => ciram[offset] = data,
+            PpuTarget::Vram(offset) => self.vram[offset - 0x800] = data,
+        }
+    }
+
+    // CHR offset a pattern table address lands on after banking, None when
+    // the mapper puts nametable RAM there
+    pub fn chr_offset(&self, addr: u16) -> Option<usize> {
+        match self.ppu_map(addr) {
+            PpuTarget::Chr(offset) => Some(offset),
+            PpuTarget::Vram(_) => None,
+        }
+    }
+
+    pub fn has_chr_ram(&self) -> bool {
+        !self.chr_ram.is_empty()
+    }
+
+    pub fn watches_ppu_fetches(&self) -> bool {
+        self.mapper.watches_ppu_fetches()
+    }
+
+    pub fn ppu_fetch(&mut self, addr: u16) {
+        self.mapper.ppu_fetch(addr);
+    }
+
+    // Mapper hardware clocked alongside the CPU
+    pub fn cpu_tick(&mut self) {
+        self.mapper.cpu_tick();
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.mapper.irq_pending()
+    }
+
+    pub fn set_expansion_audio_multiplex(&mut self, enabled: bool) {
+        self.mapper.set_expansion_audio_multiplex(enabled);
+    }
+
+    // Expansion audio from the mapper, on the 2A03 mixer's scale
+    pub fn audio_output(&self) -> f32 {
+        self.mapper.audio_output()
+    }
+
+    // PRG NVRAM followed by any battery backed RAM inside the mapper
+    pub fn battery_ram(&self) -> Vec<u8> {
+        let mut data = self.sram[..self.prg_nvram_size].to_vec();
+        data.extend_from_slice(self.mapper.battery_ram());
+        data
+    }
+
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        let len = usize::min(self.prg_nvram_size, data.len());
+        self.sram[..len].copy_from_slice(&data[..len]);
+        self.mapper.load_battery_ram(&data[len..]);
+    }
+
+    // Helpers
+    fn prg_rom(&self) -> &[u8] {
+        &self.rom[self.prg_rom.clone()]
+    }
+
+    fn chr(&self) -> &[u8] {
+        if self.chr_ram.is_empty() {
+            &self.rom[self.chr_rom.clone()]
+        } else {
+            &self.chr_ram
+        }
+    }
+
+    fn resolve_cpu(&self, target: CpuTarget) -> Option<u8> {
+        match target {
+            CpuTarget::PrgRom(offset) => Some(self.prg_rom()[offset]),
+            CpuTarget::PrgRam(offset) => {
+                Some(self.sram[offset % self.sram.len()])
+            }
+            CpuTarget::Data(data) => Some(data),
+            CpuTarget::Unmapped => None,
+        }
+    }
+
+    fn ppu_map(&self, addr: u16) -> PpuTarget {
+        // let the mapper bank the address first, else use the console wiring
+        match self.mapper.ppu_map(addr) {
+            Some(target) => target,
+            None if addr < 0x2000 => PpuTarget::Chr(addr as usize),
+            None => PpuTarget::Vram(self.get_vram_addr(addr) as usize),
+        }
+    }
+
+    fn get_vram_addr(&self, mut addr: u16) -> u16 {
+        addr &= 0xFFF;
+        let table = addr / 0x400;
+        let offset = addr & 0x3FF;
+
+        // four-screen boards hardwire all quadrants, otherwise the mapper
+        // may have changed the mirroring since the last access
+        let mirroring = match self.mirroring {
+            Mirroring::FOUR_SCREEN => Mirroring::FOUR_SCREEN,
+            _ => self.mapper.mirroring().unwrap_or(self.mirroring),
+        };
+
+        let mirrored = match mirroring {
+            Mirroring::VERTICAL => table & 1, // 0,2 -> 0 (NT1), 1,3 -> 1 (NT2)
+            Mirroring::HORIZONTAL => table >> 1, // 0,1 -> 0 (NT1), 2,3 -> 1 (NT2)
+            Mirroring::SINGLE_SCREEN_A => 0,     // 0,1,2,3 -> 0 (NT1)
+            Mirroring::SINGLE_SCREEN_B => 1,     // 0,1,2,3 -> 1 (NT2)
+            Mirroring::FOUR_SCREEN => table, // 0,1 CIRAM, 2,3 cartridge VRAM
+        };
+
+        (mirrored << 10) + offset
+    }
+}
+
+#[cfg(test)]
+impl Cartridge {
+    // Swaps the mapper for NROM that watches and counts the PPU's fetches
+    pub fn count_ppu_fetches(&mut self) -> std::rc::Rc<std::cell::Cell<u32>> {
+        let counter = mapper::FetchCounter::new(self.prg_rom.len());
+        let fetches = counter.fetches.clone();
+        self.mapper = Box::new(counter);
+        fetches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rom_sizes, Cartridge, NES_MAGIC};
+
+    // iNES image from header bytes 4 onwards, each PRG and CHR byte holds
+    // the low byte of its offset
+    fn image(header: &[u8], trainer: &[u8]) -> Vec<u8> {
+        let mut rom = NES_MAGIC.to_vec();
+        rom.extend_from_slice(header);
+        rom.resize(16, 0);
+        rom.extend_from_slice(trainer);
+        let (prg_rom_size, chr_rom_size) = rom_sizes(&rom);
+        rom.extend((0..prg_rom_size + chr_rom_size).map(|i| i as u8));
+        rom
+    }
+
+    #[test]
+    fn chr_ram_size_from_nes2_byte_11() {
+        // 64 << 9 B of CHR RAM plus 64 << 7 B of CHR NVRAM
+        let cartridge =
+            Cartridge::new(image(&[1, 0, 0, 0x08, 0, 0, 0, 0x79], &[]))
+                .unwrap();
+        assert_eq!(cartridge.chr_ram.len(), 0x8000 + 0x2000);
+
+        // iNES, and NES 2.0 without a size, get 8 kB
+        let cartridge = Cartridge::new(image(&[1, 0], &[])).unwrap();
+        assert_eq!(cartridge.chr_ram.len(), 0x2000);
+        let cartridge = Cartridge::new(image(&[1, 0, 0, 0x08], &[])).unwrap();
+        assert_eq!(cartridge.chr_ram.len(), 0x2000);
+
+        // CHR ROM means no CHR RAM
+        let cartridge =
+            Cartridge::new(image(&[1, 1, 0, 0x08, 0, 0, 0, 0x07], &[]))
+                .unwrap();
+        assert!(!cartridge.has_chr_ram());
+    }
+
+    #[test]
+    fn only_nes2_prg_nvram_is_saved() {
+        // battery, 8 kB of NVRAM followed by 8 kB of RAM
+        let mut cartridge =
+            Cartridge::new(image(&[1, 1, 0x02, 0x08, 0, 0, 0x77], &[]))
+                .unwrap();
+        assert_eq!(cartridge.sram.len(), 0x4000);
+        cartridge.cpu_write(0x6000, 0xAB);
+        cartridge.sram[0x2000] = 0xCD;
+        let saved = cartridge.battery_ram();
+        assert_eq!(saved.len(), 0x2000);
+        assert_eq!(saved[0], 0xAB);
+
+        cartridge.load_battery_ram(&[0x5A; 0x4000]);
+        assert_eq!(cartridge.cpu_read(0x6000), Some(0x5A));
+        assert_eq!(cartridge.sram[0x2000], 0xCD);
+
+        // volatile RAM only: nothing to save
+        let cartridge =
+            Cartridge::new(image(&[1, 1, 0x02, 0x08, 0, 0, 0x07], &[]))
+                .unwrap();
+        assert!(cartridge.battery_ram().is_empty());
+    }
+
+    #[test]
+    fn ines_battery_saves_all_prg_ram() {
+        let cartridge =
+            Cartridge::new(image(&[1, 1, 0x02, 0, 2], &[])).unwrap();
+        assert_eq!(cartridge.battery_ram().len(), 0x4000);
+    }
+
+    // Where the same offset lands in each of the four nametables
+    fn nametable_pages(cartridge: &Cartridge) -> [u16; 4] {
+        [0x2000, 0x2400, 0x2800, 0x2C00]
+            .map(|addr| cartridge.get_vram_addr(addr + 0x123))
+    }
+
+    #[test]
+    fn header_mirroring() {
+        let vertical = Cartridge::new(image(&[1, 1, 0x01], &[])).unwrap();
+        assert_eq!(nametable_pages(&vertical), [0x123, 0x523, 0x123, 0x523]);
+        let horizontal = Cartridge::new(image(&[1, 1, 0x00], &[])).unwrap();
+        assert_eq!(nametable_pages(&horizontal), [0x123, 0x123, 0x523, 0x523]);
+        // [0x3000, 0x3F00) mirrors the nametables
+        assert_eq!(vertical.get_vram_addr(0x3523), 0x523);
+    }
+
+    #[test]
+    fn single_screen_from_the_mapper() {
+        // FME-7, command 0xC selects the mirroring
+        let mut cartridge =
+            Cartridge::new(image(&[1, 1, 0x51, 0x40], &[])).unwrap();
+        cartridge.cpu_write(0x8000, 0xC);
+        cartridge.cpu_write(0xA000, 2);
+        assert_eq!(nametable_pages(&cartridge), [0x123; 4]);
+        cartridge.cpu_write(0xA000, 3);
+        assert_eq!(nametable_pages(&cartridge), [0x523; 4]);
+        cartridge.cpu_write(0xA000, 1);
+        assert_eq!(nametable_pages(&cartridge), [0x123, 0x123, 0x523, 0x523]);
+    }
+
+    #[test]
+    fn four_screen_uses_cartridge_vram() {
+        // the header's four-screen bit wins over the mapper's mirroring
+        let mut cartridge =
+            Cartridge::new(image(&[1, 1, 0x58, 0x40], &[])).unwrap();
+        cartridge.cpu_write(0x8000, 0xC);
+        cartridge.cpu_write(0xA000, 2);
+        assert_eq!(nametable_pages(&cartridge), [0x123, 0x523, 0x923, 0xD23]);
+
+        // the lower two nametables are CIRAM, the upper two the extra 2 kB
+        let mut ciram = [0u8; 0x800];
+        cartridge.ppu_write(0x2400, 0x11, &mut ciram);
+        cartridge.ppu_write(0x2800, 0x22, &mut ciram);
+        cartridge.ppu_write(0x2FFF, 0x33, &mut ciram);
+        assert_eq!(ciram[0x400], 0x11);
+        assert_eq!(ciram.iter().filter(|&&b| b != 0).count(), 1);
+        assert_eq!(cartridge.ppu_read(0x2800, &ciram), 0x22);
+        assert_eq!(cartridge.ppu_read(0x2FFF, &ciram), 0x33);
+        assert_eq!(cartridge.vram.len(), 0x800);
+    }
+
+    #[test]
+    fn trainer_at_7000() {
+        let trainer: Vec<u8> = (0..0x200).map(|i| !(i as u8)).collect();
+        let mut cartridge =
+            Cartridge::new(image(&[1, 1, 0x04], &trainer)).unwrap();
+        assert_eq!(cartridge.cpu_read(0x6FFF), Some(0));
+        assert_eq!(cartridge.cpu_read(0x7000), Some(0xFF));
+        assert_eq!(cartridge.cpu_read(0x71FF), Some(0x00));
+        assert_eq!(cartridge.cpu_read(0x7100), Some(0xFF));
+        assert_eq!(cartridge.cpu_read(0x7200), Some(0));
+        // PRG ROM starts after the trainer
+        assert_eq!(cartridge.cpu_read(0x8000), Some(0));
+        assert_eq!(cartridge.cpu_read(0x8001), Some(1));
+    }
+
+    #[test]
+    fn mapper_write_protects_prg_ram() {
+        // N163 only lets writes through with 0b0100 in the top of $F800
+        let mut cartridge =
+            Cartridge::new(image(&[2, 1, 0x30, 0x10], &[])).unwrap();
+        cartridge.cpu_write(0x6000, 0x11);
+        assert_eq!(cartridge.cpu_read(0x6000), Some(0));
+        cartridge.cpu_write(0xF800, 0x40);
+        cartridge.cpu_write(0x6000, 0x11);
+        assert_eq!(cartridge.cpu_read(0x6000), Some(0x11));
+        // bit 0 protects [0x6000, 0x6800) again
+        cartridge.cpu_write(0xF800, 0x41);
+        cartridge.cpu_write(0x6000, 0x22);
+        cartridge.cpu_write(0x6800, 0x22);
+        assert_eq!(cartridge.cpu_read(0x6000), Some(0x11));
+        assert_eq!(cartridge.cpu_read(0x6800), Some(0x22));
+    }
+
+    #[test]
+    fn chr_rom_ignores_writes() {
+        let mut ciram = [0u8; 0x800];
+        let mut cartridge = Cartridge::new(image(&[1, 1], &[])).unwrap();
+        cartridge.ppu_write(0x0005, 0xEE, &mut ciram);
+        assert_eq!(cartridge.ppu_read(0x0005, &ciram), 0x05);
+
+        let mut cartridge = Cartridge::new(image(&[1, 0], &[])).unwrap();
+        cartridge.ppu_write(0x0005, 0xEE, &mut ciram);
+        assert_eq!(cartridge.ppu_read(0x0005, &ciram), 0xEE);
+    }
+
+    #[test]
+    fn mapper_battery_ram_follows_prg_ram() {
+        // N163 with a battery saves its 128 B of sound RAM after PRG RAM
+        let mut cartridge =
+            Cartridge::new(image(&[2, 1, 0x32, 0x10], &[])).unwrap();
+        // unlock PRG RAM and point the sound port at 0x40
+        cartridge.cpu_write(0xF800, 0x40);
+        cartridge.cpu_write(0x6000, 0x11);
+        cartridge.cpu_write(0x4800, 0x22);
+        let saved = cartridge.battery_ram();
+        assert_eq!(saved.len(), 0x2000 + 0x80);
+        assert_eq!(saved[0], 0x11);
+        assert_eq!(saved[0x2000 + 0x40], 0x22);
+
+        let mut data = vec![0xAA; 0x2000];
+        data.extend([0xBB; 0x80]);
+        cartridge.load_battery_ram(&data);
+        cartridge.cpu_write(0xF800, 0x00);
+        assert_eq!(cartridge.cpu_read(0x6000), Some(0xAA));
+        assert_eq!(cartridge.cpu_read(0x4800), Some(0xBB));
+    }
+
+    #[test]
+    fn short_saves_load_what_they_have() {
+        let mut cartridge =
+            Cartridge::new(image(&[2, 1, 0x32, 0x10], &[])).unwrap();
+        cartridge.load_battery_ram(&[0xAA; 0x10]);
+        assert_eq!(cartridge.cpu_read(0x600F), Some(0xAA));
+        assert_eq!(cartridge.cpu_read(0x6010), Some(0));
+        assert_eq!(cartridge.cpu_read(0x4800), Some(0));
+    }
 }
@@ -0,0 +1,135 @@
+mod fme7;
+mod namco163;
+mod nrom;
+
+use super::Mirroring;
+use fme7::Fme7;
+use namco163::Namco163;
+use nrom::Nrom;
+#[cfg(test)]
+use std::{cell::Cell, rc::Rc};
+
+// Where a CPU access in [0x4020, 0x10000) ends up after banking
+pub enum CpuTarget {
+    PrgRom(usize),
+    PrgRam(usize),
+    Data(u8),
+    Unmapped,
+}
+
+// Where a PPU access in [0x0000, 0x3000) ends up after banking. Vram
+// offsets cover the 2 kB of CIRAM plus 2 kB of four-screen cartridge VRAM.
+pub enum PpuTarget {
+    Chr(usize),
+    Vram(usize),
+}
+
+// A mapper only owns banking registers and chip-internal state. The
+// cartridge holds the data and asks the mapper where each access lands.
+pub trait Mapper {
+    fn cpu_read(&mut self, addr: u16) -> CpuTarget;
+    fn cpu_write(&mut self, addr: u16, data: u8);
+    fn cpu_peek(&self, addr: u16) -> CpuTarget;
+
+    // None means the console's default wiring: identity for pattern tables,
+    // mirroring() for nametables. Mappers that bank nametables per quadrant
+    // (or out of CHR) return them from here instead.
+    fn ppu_map(&self, _addr: u16) -> Option<PpuTarget> {
+        None
+    }
+
+    // Mappers clocked by the PPU's fetches (MMC3's A12, MMC2's latches)
+    // need each fetch at its dot, which rules out the fast PPU lines
+    fn watches_ppu_fetches(&self) -> bool {
+        false
+    }
+    // Called for every background and sprite fetch while rendering, in dot
+    // order, when watches_ppu_fetches() is set
+    fn ppu_fetch(&mut self, _addr: u16) {}
+
+    // Mirroring selected at runtime, None keeps the header's
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    // Writes to PRG RAM that the mapper lets through
+    fn prg_ram_writable(&self, _addr: u16) -> bool {
+        true
+    }
+
+    // Battery backed memory inside the mapper chip, saved after PRG RAM
+    fn battery_ram(&self) -> &[u8] {
+        &[]
+    }
+    fn load_battery_ram(&mut self, _data: &[u8]) {}
+
+    // Called once per CPU cycle
+    fn cpu_tick(&mut self) {}
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // Chips that time-multiplex their audio channels can either output them
+    // as the hardware does, with its whine, or smooth them into a mix
+    fn set_expansion_audio_multiplex(&mut self, _enabled: bool) {}
+
+    // Expansion audio, on the same scale as the 2A03 mixer output. Sampled
+    // once per CPU cycle, after cpu_tick.
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+}
+
+pub fn is_supported(mapper: u8) -> bool {
+    matches!(mapper, 0 | 19 | 69)
+}
+
+pub fn new_mapper(
+    mapper: u8,
+    prg_rom_size: usize,
+    chr_size: usize,
+) -> Result<Box<dyn Mapper>, String> {
+    match mapper {
+        0 => Ok(Box::new(Nrom::new(prg_rom_size))),
+        19 => Ok(Box::new(Namco163::new(prg_rom_size, chr_size))),
+        69 => Ok(Box::new(Fme7::new(prg_rom_size, chr_size))),
+        _ => Err(format!("error: unsupported mapper {}", mapper)),
+    }
+}
+
+// NROM that watches the PPU's fetches and counts them, standing in for
+// MMC3-like mappers in tests
+#[cfg(test)]
+pub struct FetchCounter {
+    nrom: Nrom,
+    pub fetches: Rc<Cell<u32>>,
+}
+
+#[cfg(test)]
+impl FetchCounter {
+    pub fn new(prg_rom_size: usize) -> Self {
+        FetchCounter {
+            nrom: Nrom::new(prg_rom_size),
+            fetches: Rc::new(Cell::new(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Mapper for FetchCounter {
+    fn cpu_read(&mut self, addr: u16) -> CpuTarget {
+        self.nrom.cpu_read(addr)
+    }
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        self.nrom.cpu_write(addr, data);
+    }
+    fn cpu_peek(&self, addr: u16) -> CpuTarget {
+        self.nrom.cpu_peek(addr)
+    }
+    fn watches_ppu_fetches(&self) -> bool {
+        true
+    }
+    fn ppu_fetch(&mut self, _addr: u16) {
+        self.fetches.set(self.fetches.get() + 1);
+    }
+}
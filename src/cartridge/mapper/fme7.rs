@@ -0,0 +1,557 @@
+use super::{CpuTarget, Mapper, Mirroring, PpuTarget};
+
+// The 5B runs its tone and noise counters off CPU / 16
+const CYCLES_PER_TONE_STEP: u8 = 16;
+
+// Output scale, after the relative level given on the nesdev wiki's
+// "Sunsoft 5B audio" page: the loudest level of one channel comes out at
+// 1.6 full-volume 2A03 pulses. A full-volume pulse is 95.88 / (8128 / 15 +
+// 100) = 0.149 of the 2A03 mixer's range ("APU Mixer" page).
+const MIX_LEVEL: f32 = 0.149 * 1.6;
+
+lazy_static! {
+    // The envelope has 32 levels 1.5 dB apart, the 4 bit channel volumes
+    // land on every other level. Level 0 is silent.
+    static ref VOLUME_TABLE: [f32; 32] = {
+        let mut table = [0.0; 32];
+        for (level, volume) in table.iter_mut().enumerate().skip(1) {
+            *volume = 10f32.powf((level as f32 - 31.0) * 1.5 / 20.0);
+        }
+        table
+    };
+}
+
+struct Tone {
+    period: u16,
+    counter: u16,
+    output: bool,
+}
+
+struct Envelope {
+    period: u16,
+    counter: u32,
+    // CAAH: continue, attack, alternate, hold
+    shape: u8,
+    level: u8,
+    attack: bool,
+    holding: bool,
+}
+
+impl Envelope {
+    fn restart(&mut self, shape: u8) {
+        self.shape = shape & 0xF;
+        self.counter = 0;
+        self.attack = self.shape & 0b0100 != 0;
+        self.level = if self.attack { 0 } else { 31 };
+        self.holding = false;
+    }
+
+    fn step(&mut self) {
+        if self.holding {
+            return;
+        }
+        self.counter += 1;
+        // one of 32 levels every 16 * period CPU cycles
+        if self.counter < 16 * u32::max(1, self.period as u32) {
+            return;
+        }
+        self.counter = 0;
+
+        let at_end = if self.attack {
+            self.level == 31
+        } else {
+            self.level == 0
+        };
+        if !at_end {
+            if self.attack {
+                self.level += 1;
+            } else {
+                self.level -= 1;
+            }
+            return;
+        }
+
+        let cont = self.shape & 0b1000 != 0;
+        let alternate = self.shape & 0b0010 != 0;
+        let hold = self.shape & 0b0001 != 0;
+        if !cont {
+            // single ramp, then silence
+            self.level = 0;
+            self.holding = true;
+        } else if hold {
+            if alternate {
+                self.level = if self.attack { 0 } else { 31 };
+            }
+            self.holding = true;
+        } else if alternate {
+            self.attack = !self.attack;
+        } else {
+            self.level = if self.attack { 0 } else { 31 };
+        }
+    }
+}
+
+// Mapper 69: Sunsoft FME-7 and 5A/5B
+pub struct Fme7 {
+    prg_rom_size: usize,
+    chr_size: usize,
+
+    // Command/parameter interface
+    command: u8,
+
+    // Banking registers
+    chr_banks: [u8; 8],
+    prg_banks: [u8; 4],
+    prg_ram_select: bool,
+    prg_ram_enabled: bool,
+    mirroring: u8,
+
+    // IRQ: 16 bit down counter, fires when it wraps past 0
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_pending: bool,
+
+    // 5B audio, an AY-3-8910 with a few differences
+    audio_register: u8,
+    audio_regs: [u8; 0x10],
+    audio_cycle: u8,
+    tones: [Tone; 3],
+    noise_period: u8,
+    noise_counter: u8,
+    noise_lfsr: u32,
+    envelope: Envelope,
+}
+
+impl Fme7 {
+    pub fn new(prg_rom_size: usize, chr_size: usize) -> Self {
+        let tone = || Tone {
+            period: 0,
+            counter: 0,
+            output: false,
+        };
+        Fme7 {
+            prg_rom_size,
+            chr_size,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            prg_ram_select: false,
+            prg_ram_enabled: false,
+            mirroring: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_pending: false,
+            audio_register: 0,
+            audio_regs: [0; 0x10],
+            audio_cycle: 0,
+            tones: [tone(), tone(), tone()],
+            noise_period: 0,
+            noise_counter: 0,
+            noise_lfsr: 1,
+            envelope: Envelope {
+                period: 0,
+                counter: 0,
+                shape: 0,
+                level: 0,
+                attack: false,
+                holding: true,
+            },
+        }
+    }
+
+    fn prg_offset(&self, slot: usize, addr: u16) -> usize {
+        // 8 kB banks: [0x6000, 0xE000) switchable, [0xE000, 0x10000) fixed
+        // to the last bank
+        let bank = if slot < 4 {
+            self.prg_banks[slot] as usize
+        } else {
+            self.prg_rom_size / 0x2000 - 1
+        };
+        (bank * 0x2000 + (addr as usize & 0x1FFF)) % self.prg_rom_size
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+            0x8 => {
+                // ERBB BBBB: RAM enable, RAM select, bank
+                self.prg_banks[0] = data & 0x3F;
+                self.prg_ram_select = data & 0x40 != 0;
+                self.prg_ram_enabled = data & 0x80 != 0;
+            }
+            0x9..=0xB => {
+                self.prg_banks[self.command as usize - 0x8] = data & 0x3F;
+            }
+            0xC => self.mirroring = data & 0b11,
+            0xD => {
+                self.irq_enabled = data & 0x01 != 0;
+                self.irq_counter_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xE => {
+                self.irq_counter = (self.irq_counter & 0xFF00) | data as u16;
+            }
+            0xF => {
+                self.irq_counter =
+                    (self.irq_counter & 0x00FF) | (data as u16) << 8;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_audio(&mut self, data: u8) {
+        let reg = self.audio_register as usize;
+        self.audio_regs[reg] = data;
+        match reg {
+            0x0..=0x5 => {
+                // 12 bit tone periods, lo byte then hi nibble
+                let tone = &mut self.tones[reg / 2];
+                tone.period = self.audio_regs[reg & !1] as u16
+                    | (self.audio_regs[reg | 1] as u16 & 0xF) << 8;
+            }
+            0x6 => self.noise_period = data & 0x1F,
+            0xB | 0xC => {
+                self.envelope.period = self.audio_regs[0xB] as u16
+                    | (self.audio_regs[0xC] as u16) << 8;
+            }
+            0xD => self.envelope.restart(data),
+            _ => {}
+        }
+    }
+
+    fn channel_output(&self, channel: usize) -> f32 {
+        // mixer bits are active low: 0 lets tone/noise gate the channel
+        let mixer = self.audio_regs[0x7];
+        let tone_off = mixer & (1 << channel) != 0;
+        let noise_off = mixer & (0b1000 << channel) != 0;
+        let tone = self.tones[channel].output || tone_off;
+        let noise = self.noise_lfsr & 1 != 0 || noise_off;
+        if !(tone && noise) {
+            return 0.0;
+        }
+
+        let volume = self.audio_regs[0x8 + channel];
+        let level = if volume & 0x10 != 0 {
+            self.envelope.level
+        } else if volume & 0xF == 0 {
+            0
+        } else {
+            (volume & 0xF) * 2 + 1
+        };
+        VOLUME_TABLE[level as usize]
+    }
+}
+
+impl Mapper for Fme7 {
+    fn cpu_read(&mut self, addr: u16) -> CpuTarget {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.command = data & 0xF,
+            0xA000..=0xBFFF => self.write_parameter(data),
+            0xC000..=0xDFFF => self.audio_register = data & 0xF,
+            0xE000..=0xFFFF => self.write_audio(data),
+            _ => {}
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> CpuTarget {
+        match addr {
+            // disabled PRG RAM leaves the bus open
+            0x6000..=0x7FFF if self.prg_ram_select => {
+                if self.prg_ram_enabled {
+                    CpuTarget::PrgRam(addr as usize - 0x6000)
+                } else {
+                    CpuTarget::Unmapped
+                }
+            }
+            0x6000..=0x7FFF => CpuTarget::PrgRom(self.prg_offset(0, addr)),
+            0x8000..=0xFFFF => {
+                let slot = (addr as usize - 0x6000) / 0x2000;
+                CpuTarget::PrgRom(self.prg_offset(slot, addr))
+            }
+            _ => CpuTarget::Unmapped,
+        }
+    }
+
+    fn ppu_map(&self, addr: u16) -> Option<PpuTarget> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[addr as usize / 0x400] as usize;
+                let offset = bank * 0x400 + (addr as usize & 0x3FF);
+                Some(PpuTarget::Chr(offset % self.chr_size))
+            }
+            _ => None,
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match self.mirroring {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::SINGLE_SCREEN_A,
+            _ => Mirroring::SINGLE_SCREEN_B,
+        })
+    }
+
+    fn cpu_tick(&mut self) {
+        if self.irq_counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+
+        self.envelope.step();
+
+        self.audio_cycle += 1;
+        if self.audio_cycle < CYCLES_PER_TONE_STEP {
+            return;
+        }
+        self.audio_cycle = 0;
+
+        for tone in self.tones.iter_mut() {
+            tone.counter += 1;
+            if tone.counter >= u16::max(1, tone.period) {
+                tone.counter = 0;
+                tone.output = !tone.output;
+            }
+        }
+
+        // noise steps at half the tone rate
+        self.noise_counter += 1;
+        if self.noise_counter >= 2 * u8::max(1, self.noise_period) {
+            self.noise_counter = 0;
+            // 17 bit LFSR, taps at bits 0 and 3
+            let feedback = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 1;
+            self.noise_lfsr = (self.noise_lfsr >> 1) | (feedback << 16);
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        let sum: f32 =
+            (0..3).map(|channel| self.channel_output(channel)).sum();
+        sum * MIX_LEVEL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CpuTarget, Mapper, Mirroring, PpuTarget};
+    use super::Fme7;
+
+    // 256 kB PRG, 256 kB CHR
+    fn mapper() -> Fme7 {
+        Fme7::new(0x40000, 0x40000)
+    }
+
+    fn command(mapper: &mut Fme7, command: u8, parameter: u8) {
+        mapper.cpu_write(0x8000, command);
+        mapper.cpu_write(0xA000, parameter);
+    }
+
+    fn prg(mapper: &Fme7, addr: u16) -> usize {
+        match mapper.cpu_peek(addr) {
+            CpuTarget::PrgRom(offset) => offset,
+            _ => panic!("{:04X} is not PRG ROM", addr),
+        }
+    }
+
+    #[test]
+    fn irq_fires_when_counter_wraps() {
+        let mut mapper = mapper();
+        command(&mut mapper, 0xE, 0x01);
+        command(&mut mapper, 0xF, 0x00);
+        command(&mut mapper, 0xD, 0x81);
+        // 1 -> 0, then 0 -> 0xFFFF fires
+        mapper.cpu_tick();
+        assert!(!mapper.irq_pending());
+        mapper.cpu_tick();
+        assert!(mapper.irq_pending());
+        // any write to the control register acknowledges it
+        command(&mut mapper, 0xD, 0x81);
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn irq_counter_runs_without_irqs() {
+        let mut mapper = mapper();
+        command(&mut mapper, 0xE, 0x00);
+        command(&mut mapper, 0xF, 0x00);
+        // counting, but IRQs off
+        command(&mut mapper, 0xD, 0x80);
+        mapper.cpu_tick();
+        assert!(!mapper.irq_pending());
+        // the counter kept going, so it takes 0x10000 more cycles
+        command(&mut mapper, 0xD, 0x81);
+        for _ in 0..0xFFFF {
+            mapper.cpu_tick();
+        }
+        assert!(!mapper.irq_pending());
+        mapper.cpu_tick();
+        assert!(mapper.irq_pending());
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut mapper = mapper();
+        command(&mut mapper, 0x9, 0x01);
+        command(&mut mapper, 0xA, 0x02);
+        // only 6 bits of bank number
+        command(&mut mapper, 0xB, 0xC3);
+        assert_eq!(prg(&mapper, 0x8000), 0x2000);
+        assert_eq!(prg(&mapper, 0xA123), 0x4123);
+        assert_eq!(prg(&mapper, 0xC000), 0x6000);
+        // the last 8 kB are fixed
+        assert_eq!(prg(&mapper, 0xFFFF), 0x3FFFF);
+    }
+
+    #[test]
+    fn prg_ram_or_rom_at_6000() {
+        let mut mapper = mapper();
+        command(&mut mapper, 0x8, 0x05);
+        assert_eq!(prg(&mapper, 0x6010), 0xA010);
+        // RAM selected but disabled leaves the bus open
+        command(&mut mapper, 0x8, 0x40);
+        assert!(matches!(mapper.cpu_peek(0x6000), CpuTarget::Unmapped));
+        command(&mut mapper, 0x8, 0xC0);
+        assert!(matches!(mapper.cpu_peek(0x7FFF), CpuTarget::PrgRam(0x1FFF)));
+    }
+
+    #[test]
+    fn chr_banks() {
+        let mut mapper = mapper();
+        command(&mut mapper, 0x0, 0x10);
+        command(&mut mapper, 0x7, 0xFF);
+        let chr = |mapper: &Fme7, addr| match mapper.ppu_map(addr) {
+            Some(PpuTarget::Chr(offset)) => offset,
+            _ => panic!("{:04X} is not CHR", addr),
+        };
+        assert_eq!(chr(&mapper, 0x0012), 0x4012);
+        assert_eq!(chr(&mapper, 0x1FFF), 0x3FFFF);
+        // nametables keep the console's wiring
+        assert!(mapper.ppu_map(0x2000).is_none());
+    }
+
+    #[test]
+    fn mirroring() {
+        let mut mapper = mapper();
+        for (value, mirroring) in [
+            (0, Mirroring::VERTICAL),
+            (1, Mirroring::HORIZONTAL),
+            (2, Mirroring::SINGLE_SCREEN_A),
+            (3, Mirroring::SINGLE_SCREEN_B),
+        ] {
+            command(&mut mapper, 0xC, value);
+            assert_eq!(mapper.mirroring(), Some(mirroring));
+        }
+    }
+
+    fn audio(mapper: &mut Fme7, register: u8, data: u8) {
+        mapper.cpu_write(0xC000, register);
+        mapper.cpu_write(0xE000, data);
+    }
+
+    fn ticks(mapper: &mut Fme7, cycles: usize) {
+        for _ in 0..cycles {
+            mapper.cpu_tick();
+        }
+    }
+
+    // In full-volume channels
+    fn output(mapper: &Fme7) -> f32 {
+        mapper.audio_output() / super::MIX_LEVEL
+    }
+
+    #[test]
+    fn tone_toggles_every_16_period_cycles() {
+        let mut mapper = mapper();
+        audio(&mut mapper, 0x0, 2);
+        audio(&mut mapper, 0x1, 0);
+        // tone A only, volume 15
+        audio(&mut mapper, 0x7, 0b111_110);
+        audio(&mut mapper, 0x8, 0xF);
+        ticks(&mut mapper, 31);
+        assert_eq!(output(&mapper), 0.0);
+        ticks(&mut mapper, 1);
+        assert_eq!(output(&mapper), 1.0);
+        ticks(&mut mapper, 32);
+        assert_eq!(output(&mapper), 0.0);
+    }
+
+    #[test]
+    fn volumes_step_3_db() {
+        let mut mapper = mapper();
+        // with tone and noise off a channel outputs its volume
+        audio(&mut mapper, 0x7, 0b111_111);
+        audio(&mut mapper, 0x8, 0xF);
+        assert_eq!(output(&mapper), 1.0);
+        audio(&mut mapper, 0x8, 0xE);
+        assert!((output(&mapper) - 0.708).abs() < 0.001);
+        audio(&mut mapper, 0x9, 0xE);
+        assert!((output(&mapper) - 2.0 * 0.708).abs() < 0.001);
+        audio(&mut mapper, 0x8, 0);
+        audio(&mut mapper, 0x9, 0);
+        assert_eq!(output(&mapper), 0.0);
+    }
+
+    #[test]
+    fn noise_shifts_every_32_period_cycles() {
+        let mut mapper = mapper();
+        audio(&mut mapper, 0x6, 1);
+        // noise A only, volume 15
+        audio(&mut mapper, 0x7, 0b110_111);
+        audio(&mut mapper, 0x8, 0xF);
+        assert_eq!(output(&mapper), 1.0);
+        ticks(&mut mapper, 31);
+        assert_eq!(output(&mapper), 1.0);
+        ticks(&mut mapper, 1);
+        assert_eq!(mapper.noise_lfsr, 1 << 16);
+        assert_eq!(output(&mapper), 0.0);
+    }
+
+    #[test]
+    fn envelope_decays_then_holds() {
+        let mut mapper = mapper();
+        audio(&mut mapper, 0x7, 0b111_111);
+        audio(&mut mapper, 0x8, 0x10);
+        audio(&mut mapper, 0xB, 1);
+        audio(&mut mapper, 0xC, 0);
+        audio(&mut mapper, 0xD, 0b0000);
+        assert_eq!(output(&mapper), 1.0);
+        // 1.5 dB per level
+        ticks(&mut mapper, 16);
+        assert_eq!(mapper.envelope.level, 30);
+        assert!((output(&mapper) - 0.841).abs() < 0.001);
+        ticks(&mut mapper, 30 * 16);
+        assert_eq!(mapper.envelope.level, 0);
+        ticks(&mut mapper, 64 * 16);
+        assert_eq!(output(&mapper), 0.0);
+    }
+
+    #[test]
+    fn envelope_shapes() {
+        // the level after each ramp of 31 steps, with a step in between to
+        // turn around, for attack + alternate (a triangle) and attack +
+        // alternate + hold (up once, then silent)
+        for (shape, levels) in [(0b1110, [31, 0, 31]), (0b1111, [31, 0, 0])] {
+            let mut mapper = mapper();
+            audio(&mut mapper, 0xB, 1);
+            audio(&mut mapper, 0xD, shape);
+            assert_eq!(mapper.envelope.level, 0);
+            for level in levels {
+                ticks(&mut mapper, 31 * 16);
+                assert_eq!(mapper.envelope.level, level, "{:04b}", shape);
+                ticks(&mut mapper, 16);
+            }
+        }
+    }
+}
@@ -0,0 +1,527 @@
+use super::{CpuTarget, Mapper, PpuTarget};
+
+// Sound RAM is shared between wave samples and channel registers, which live
+// in the top 64 bytes (8 bytes per channel, channel 8 at the very top).
+const SOUND_RAM_SIZE: usize = 0x80;
+const CHANNEL_REGS_START: usize = 0x40;
+
+// The sound unit steps one channel every 15 CPU cycles
+const CYCLES_PER_CHANNEL: u8 = 15;
+
+// Output scale: a channel at full volume and full swing (+-120) comes out
+// at 1.5 full-volume 2A03 pulses, each 0.149 of the 2A03 mixer's range
+const MIX_LEVEL: f32 = 0.149 * 1.5 / 120.0;
+
+// Bank values at or above this select a CIRAM page instead of CHR ROM
+const CIRAM_BANK: u8 = 0xE0;
+
+// Mapper 19: Namco 129/163
+pub struct Namco163 {
+    prg_rom_size: usize,
+    chr_size: usize,
+
+    // Banking registers
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    no_ciram_lo: bool,
+    no_ciram_hi: bool,
+    // KKKK DCBA: writes are allowed only with K = 0b0100, and then only to
+    // the 2 kB PRG RAM windows whose bit is clear
+    ram_protect: u8,
+
+    // IRQ: 15 bit up counter, fires and stops at 0x7FFF
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    // Internal sound RAM and its address port
+    sound_ram: [u8; SOUND_RAM_SIZE],
+    sound_addr: u8,
+    auto_increment: bool,
+
+    // Wavetable synthesizer
+    sound_disabled: bool,
+    sound_cycle: u8,
+    curr_channel: u8,
+    channel_out: [i16; 8],
+    // true: output whichever channel is being stepped, which is what the
+    // chip does and produces its high pitched multiplexing noise.
+    // false: output the average of all enabled channels.
+    multiplex: bool,
+}
+
+impl Namco163 {
+    pub fn new(prg_rom_size: usize, chr_size: usize) -> Self {
+        Namco163 {
+            prg_rom_size,
+            chr_size,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            nametable_banks: [0; 4],
+            no_ciram_lo: false,
+            no_ciram_hi: false,
+            ram_protect: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            sound_ram: [0; SOUND_RAM_SIZE],
+            sound_addr: 0,
+            auto_increment: false,
+            sound_disabled: false,
+            sound_cycle: 0,
+            curr_channel: 7,
+            channel_out: [0; 8],
+            multiplex: false,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        // 8 kB banks: [0x8000, 0xE000) switchable, [0xE000, 0x10000) fixed
+        // to the last bank
+        let slot = (addr as usize - 0x8000) / 0x2000;
+        let bank = if slot < 3 {
+            self.prg_banks[slot] as usize
+        } else {
+            self.prg_rom_size / 0x2000 - 1
+        };
+        (bank * 0x2000 + (addr as usize & 0x1FFF)) % self.prg_rom_size
+    }
+
+    fn bank_target(&self, bank: u8, use_ciram: bool, addr: u16) -> PpuTarget {
+        let offset = addr as usize & 0x3FF;
+        if use_ciram && bank >= CIRAM_BANK {
+            PpuTarget::Vram((bank as usize & 1) * 0x400 + offset)
+        } else {
+            PpuTarget::Chr((bank as usize * 0x400 + offset) % self.chr_size)
+        }
+    }
+
+    fn read_sound_ram(&mut self) -> u8 {
+        let data = self.sound_ram[self.sound_addr as usize];
+        self.step_sound_addr();
+        data
+    }
+
+    fn write_sound_ram(&mut self, data: u8) {
+        self.sound_ram[self.sound_addr as usize] = data;
+        self.step_sound_addr();
+    }
+
+    fn step_sound_addr(&mut self) {
+        if self.auto_increment {
+            self.sound_addr = (self.sound_addr + 1) & 0x7F;
+        }
+    }
+
+    fn enabled_channels(&self) -> u8 {
+        // 0x7F[6:4] holds the number of enabled channels minus one
+        ((self.sound_ram[0x7F] >> 4) & 0b111) + 1
+    }
+
+    fn step_channel(&mut self, channel: u8) {
+        // Channel registers, 8 bytes each:
+        //   +0 frequency[7:0]    +1 phase[7:0]
+        //   +2 frequency[15:8]   +3 phase[15:8]
+        //   +4 LLLLLL FF: wave length, frequency[17:16]
+        //   +5 phase[23:16]      +6 wave address in 4 bit samples
+        //   +7 volume[3:0]
+        let base = CHANNEL_REGS_START + 8 * channel as usize;
+        let regs = &self.sound_ram[base..base + 8];
+
+        let freq = regs[0] as u32
+            | (regs[2] as u32) << 8
+            | (regs[4] as u32 & 0b11) << 16;
+        let mut phase =
+            regs[1] as u32 | (regs[3] as u32) << 8 | (regs[5] as u32) << 16;
+        let length = 256 - (regs[4] as u32 & 0xFC);
+        let wave_addr = regs[6] as u32;
+        let volume = (regs[7] & 0xF) as i16;
+
+        phase = (phase + freq) % (length << 16);
+
+        let sample_addr = ((phase >> 16) + wave_addr) & 0xFF;
+        let byte = self.sound_ram[sample_addr as usize >> 1];
+        // samples are packed low nibble first
+        let sample = if sample_addr & 1 == 0 {
+            byte & 0xF
+        } else {
+            byte >> 4
+        };
+
+        self.sound_ram[base + 1] = phase as u8;
+        self.sound_ram[base + 3] = (phase >> 8) as u8;
+        self.sound_ram[base + 5] = (phase >> 16) as u8;
+        self.channel_out[channel as usize] = (sample as i16 - 8) * volume;
+    }
+}
+
+impl Mapper for Namco163 {
+    fn cpu_read(&mut self, addr: u16) -> CpuTarget {
+        match addr {
+            0x4800..=0x4FFF => CpuTarget::Data(self.read_sound_ram()),
+            _ => self.cpu_peek(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4FFF => self.write_sound_ram(data),
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | data as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter =
+                    (self.irq_counter & 0x00FF) | (data as u16 & 0x7F) << 8;
+                self.irq_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x8000..=0xBFFF => {
+                self.chr_banks[(addr as usize - 0x8000) / 0x800] = data;
+            }
+            0xC000..=0xDFFF => {
+                self.nametable_banks[(addr as usize - 0xC000) / 0x800] = data;
+            }
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = data & 0x3F;
+                self.sound_disabled = data & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => {
+                self.prg_banks[1] = data & 0x3F;
+                self.no_ciram_lo = data & 0x40 != 0;
+                self.no_ciram_hi = data & 0x80 != 0;
+            }
+            0xF000..=0xF7FF => self.prg_banks[2] = data & 0x3F,
+            0xF800..=0xFFFF => {
+                self.ram_protect = data;
+                self.sound_addr = data & 0x7F;
+                self.auto_increment = data & 0x80 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> CpuTarget {
+        match addr {
+            0x4800..=0x4FFF => {
+                CpuTarget::Data(self.sound_ram[self.sound_addr as usize])
+            }
+            0x5000..=0x57FF => CpuTarget::Data(self.irq_counter as u8),
+            0x5800..=0x5FFF => CpuTarget::Data(
+                ((self.irq_enabled as u8) << 7)
+                    | (self.irq_counter >> 8) as u8,
+            ),
+            0x6000..=0x7FFF => CpuTarget::PrgRam(addr as usize - 0x6000),
+            0x8000..=0xFFFF => CpuTarget::PrgRom(self.prg_offset(addr)),
+            _ => CpuTarget::Unmapped,
+        }
+    }
+
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        let window = (addr - 0x6000) / 0x800;
+        self.ram_protect & 0xF0 == 0x40
+            && self.ram_protect & (1 << window) == 0
+    }
+
+    fn battery_ram(&self) -> &[u8] {
+        &self.sound_ram
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        let len = usize::min(data.len(), SOUND_RAM_SIZE);
+        self.sound_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn ppu_map(&self, addr: u16) -> Option<PpuTarget> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let slot = addr as usize / 0x400;
+                let use_ciram = if slot < 4 {
+                    !self.no_ciram_lo
+                } else {
+                    !self.no_ciram_hi
+                };
+                Some(self.bank_target(self.chr_banks[slot], use_ciram, addr))
+            }
+            _ => {
+                // nametables are always banked, [0x3000, 0x3F00) mirrors down
+                let slot = ((addr as usize - 0x2000) & 0xFFF) / 0x400;
+                Some(self.bank_target(self.nametable_banks[slot], true, addr))
+            }
+        }
+    }
+
+    fn cpu_tick(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7FFF {
+                self.irq_pending = true;
+            }
+        }
+
+        if self.sound_disabled {
+            return;
+        }
+        self.sound_cycle += 1;
+        if self.sound_cycle == CYCLES_PER_CHANNEL {
+            self.sound_cycle = 0;
+            // channels are stepped from 8 downwards, only the enabled ones
+            self.step_channel(self.curr_channel);
+            let lowest = 8 - self.enabled_channels();
+            self.curr_channel = if self.curr_channel <= lowest {
+                7
+            } else {
+                self.curr_channel - 1
+            };
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn set_expansion_audio_multiplex(&mut self, enabled: bool) {
+        self.multiplex = enabled;
+    }
+
+    fn audio_output(&self) -> f32 {
+        if self.sound_disabled {
+            return 0.0;
+        }
+        let out = if self.multiplex {
+            // the channel that was just stepped is the one on the DAC
+            let last = if self.curr_channel == 7 {
+                8 - self.enabled_channels()
+            } else {
+                self.curr_channel + 1
+            };
+            self.channel_out[last as usize] as f32
+        } else {
+            // averaging makes each channel quieter the more are enabled,
+            // just like the time-multiplexed DAC does
+            let lowest = 8 - self.enabled_channels() as usize;
+            let sum: i16 = self.channel_out[lowest..].iter().sum();
+            sum as f32 / self.enabled_channels() as f32
+        };
+        out * MIX_LEVEL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CpuTarget, Mapper, PpuTarget};
+    use super::Namco163;
+
+    // 128 kB PRG, 128 kB CHR
+    fn mapper() -> Namco163 {
+        Namco163::new(0x20000, 0x20000)
+    }
+
+    fn prg(mapper: &Namco163, addr: u16) -> usize {
+        match mapper.cpu_peek(addr) {
+            CpuTarget::PrgRom(offset) => offset,
+            _ => panic!("{:04X} is not PRG ROM", addr),
+        }
+    }
+
+    fn ppu(mapper: &Namco163, addr: u16) -> (bool, usize) {
+        match mapper.ppu_map(addr) {
+            Some(PpuTarget::Chr(offset)) => (false, offset),
+            Some(PpuTarget::Vram(offset)) => (true, offset),
+            None => panic!("{:04X} is not mapped", addr),
+        }
+    }
+
+    fn data(mapper: &Namco163, addr: u16) -> u8 {
+        match mapper.cpu_peek(addr) {
+            CpuTarget::Data(data) => data,
+            _ => panic!("{:04X} is not a register", addr),
+        }
+    }
+
+    #[test]
+    fn irq_counts_up_to_7fff() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0x5000, 0xFD);
+        mapper.cpu_write(0x5800, 0x80 | 0x7F);
+        assert_eq!(data(&mapper, 0x5000), 0xFD);
+        assert_eq!(data(&mapper, 0x5800), 0xFF);
+
+        mapper.cpu_tick();
+        assert!(!mapper.irq_pending());
+        mapper.cpu_tick();
+        assert!(mapper.irq_pending());
+        // the counter stops at 0x7FFF
+        mapper.cpu_tick();
+        assert_eq!(data(&mapper, 0x5000), 0xFF);
+        assert_eq!(data(&mapper, 0x5800), 0xFF);
+
+        // writing either half acknowledges it
+        mapper.cpu_write(0x5000, 0);
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn irq_waits_for_enable() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0x5000, 0xFF);
+        mapper.cpu_write(0x5800, 0x7E);
+        mapper.cpu_tick();
+        assert_eq!(data(&mapper, 0x5000), 0xFF);
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0xE000, 0x41);
+        mapper.cpu_write(0xE800, 0x02);
+        mapper.cpu_write(0xF000, 0x03);
+        assert_eq!(prg(&mapper, 0x8000), 0x2000);
+        assert_eq!(prg(&mapper, 0xA123), 0x4123);
+        assert_eq!(prg(&mapper, 0xC000), 0x6000);
+        // the last 8 kB are fixed
+        assert_eq!(prg(&mapper, 0xFFFF), 0x1FFFF);
+    }
+
+    #[test]
+    fn chr_and_nametable_banks() {
+        let mut mapper = mapper();
+        mapper.cpu_write(0x8000, 0x05);
+        mapper.cpu_write(0x9800, 0xE1);
+        mapper.cpu_write(0xB800, 0xE0);
+        mapper.cpu_write(0xC000, 0xE0);
+        mapper.cpu_write(0xC800, 0xE1);
+        mapper.cpu_write(0xD000, 0x10);
+        assert_eq!(ppu(&mapper, 0x0010), (false, 0x1410));
+        // banks 0xE0 and up pick a CIRAM page...
+        assert_eq!(ppu(&mapper, 0x0C10), (true, 0x410));
+        assert_eq!(ppu(&mapper, 0x1C00), (true, 0x000));
+        // ...unless 0xE800 bits 6 and 7 turn that off for either half
+        mapper.cpu_write(0xE800, 0x40);
+        assert_eq!(ppu(&mapper, 0x0C10), (false, 0x18410));
+        assert_eq!(ppu(&mapper, 0x1C00), (true, 0x000));
+        mapper.cpu_write(0xE800, 0x80);
+        assert_eq!(ppu(&mapper, 0x0C10), (true, 0x410));
+        assert_eq!(ppu(&mapper, 0x1C00), (false, 0x18000));
+
+        // nametables always use CIRAM for 0xE0 and up
+        assert_eq!(ppu(&mapper, 0x2005), (true, 0x005));
+        assert_eq!(ppu(&mapper, 0x2405), (true, 0x405));
+        assert_eq!(ppu(&mapper, 0x2805), (false, 0x4005));
+        // and mirror down from 0x3000
+        assert_eq!(ppu(&mapper, 0x3405), (true, 0x405));
+    }
+
+    #[test]
+    fn prg_ram_write_protect() {
+        let mut mapper = mapper();
+        assert!(!mapper.prg_ram_writable(0x6000));
+        // 0b0100 unlocks, bit 1 keeps [0x6800, 0x7000) protected
+        mapper.cpu_write(0xF800, 0x42);
+        assert!(mapper.prg_ram_writable(0x6000));
+        assert!(!mapper.prg_ram_writable(0x6FFF));
+        assert!(mapper.prg_ram_writable(0x7FFF));
+    }
+
+    #[test]
+    fn sound_ram_port() {
+        let mut mapper = mapper();
+        // address 0x7E with auto increment
+        mapper.cpu_write(0xF800, 0x80 | 0x7E);
+        mapper.cpu_write(0x4800, 0x12);
+        mapper.cpu_write(0x4800, 0x34);
+        // wraps around to 0
+        mapper.cpu_write(0x4800, 0x56);
+        assert_eq!(&mapper.battery_ram()[0x7E..], [0x12, 0x34]);
+        assert_eq!(mapper.battery_ram()[0], 0x56);
+        mapper.cpu_write(0xF800, 0x7F);
+        assert!(matches!(mapper.cpu_read(0x4800), CpuTarget::Data(0x34)));
+        assert!(matches!(mapper.cpu_read(0x4800), CpuTarget::Data(0x34)));
+    }
+
+    // Channel 8 playing a 4 sample wave 1, 2, 3, 4 one sample per step, at
+    // volume 15, with `channels` channels enabled
+    fn synth(channels: u8) -> Namco163 {
+        let mut mapper = mapper();
+        mapper.cpu_write(0xF800, 0x80);
+        mapper.cpu_write(0x4800, 0x21);
+        mapper.cpu_write(0x4800, 0x43);
+        // frequency 0x10000, length 4, wave address 0
+        mapper.cpu_write(0xF800, 0x80 | 0x78);
+        for data in [0, 0, 0, 0, 0xFD, 0, 0] {
+            mapper.cpu_write(0x4800, data);
+        }
+        mapper.cpu_write(0x4800, (channels - 1) << 4 | 0xF);
+        mapper
+    }
+
+    // One channel step
+    fn step(mapper: &mut Namco163) {
+        for _ in 0..15 {
+            mapper.cpu_tick();
+        }
+    }
+
+    fn output(mapper: &Namco163) -> f32 {
+        mapper.audio_output() / super::MIX_LEVEL
+    }
+
+    #[test]
+    fn wavetable_steps_every_15_cycles() {
+        let mut mapper = synth(1);
+        for _ in 0..14 {
+            mapper.cpu_tick();
+        }
+        assert_eq!(output(&mapper), 0.0);
+        mapper.cpu_tick();
+        // phase 1 plays sample 2
+        assert_eq!(output(&mapper), (2.0 - 8.0) * 15.0);
+        assert_eq!(mapper.battery_ram()[0x7D], 1);
+        for sample in [3.0, 4.0, 1.0, 2.0] {
+            step(&mut mapper);
+            assert_eq!(output(&mapper), (sample - 8.0) * 15.0);
+        }
+        // the wave wrapped after 4 samples
+        assert_eq!(mapper.battery_ram()[0x7D], 1);
+    }
+
+    #[test]
+    fn more_channels_share_the_output() {
+        // channel 7 is silent, channel 8 at sample 2
+        let mut mapper = synth(2);
+        step(&mut mapper);
+        step(&mut mapper);
+        assert_eq!(output(&mapper), (2.0 - 8.0) * 15.0 / 2.0);
+        // both channels are stepped once per 2 steps now
+        step(&mut mapper);
+        step(&mut mapper);
+        assert_eq!(output(&mapper), (3.0 - 8.0) * 15.0 / 2.0);
+    }
+
+    #[test]
+    fn multiplexed_output_follows_the_stepped_channel() {
+        let mut mapper = synth(2);
+        mapper.set_expansion_audio_multiplex(true);
+        step(&mut mapper);
+        assert_eq!(output(&mapper), (2.0 - 8.0) * 15.0);
+        step(&mut mapper);
+        assert_eq!(output(&mapper), 0.0);
+        step(&mut mapper);
+        assert_eq!(output(&mapper), (3.0 - 8.0) * 15.0);
+    }
+
+    #[test]
+    fn sound_disable_stops_the_synth() {
+        let mut mapper = synth(1);
+        step(&mut mapper);
+        mapper.cpu_write(0xE000, 0x40);
+        assert_eq!(output(&mapper), 0.0);
+        step(&mut mapper);
+        assert_eq!(mapper.battery_ram()[0x7D], 1);
+        mapper.cpu_write(0xE000, 0);
+        step(&mut mapper);
+        assert_eq!(output(&mapper), (3.0 - 8.0) * 15.0);
+    }
+}
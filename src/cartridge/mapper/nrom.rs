@@ -0,0 +1,31 @@
+use super::{CpuTarget, Mapper};
+
+// Mapper 0: 16 or 32 kB of PRG ROM, no bank switching
+pub struct Nrom {
+    prg_rom_size: usize,
+}
+
+impl Nrom {
+    pub fn new(prg_rom_size: usize) -> Self {
+        Nrom { prg_rom_size }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&mut self, addr: u16) -> CpuTarget {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_write(&mut self, _addr: u16, _data: u8) {}
+
+    fn cpu_peek(&self, addr: u16) -> CpuTarget {
+        match addr {
+            0x6000..=0x7FFF => CpuTarget::PrgRam(addr as usize - 0x6000),
+            // 16 kB carts mirror [0x8000, 0xC000) into [0xC000, 0x10000)
+            0x8000..=0xFFFF => {
+                CpuTarget::PrgRom((addr as usize - 0x8000) % self.prg_rom_size)
+            }
+            _ => CpuTarget::Unmapped,
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::nnes::{DebugImage, NNES};
+use crate::palette::Palette;
+use sdl2::{
+    pixels::PixelFormatEnum, render::Canvas, video::Window, VideoSubsystem,
+};
+
+// Extra window showing one of the PPU debug views at a time
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum DebugView {
+    NAMETABLES,
+    PATTERNS,
+    OAM,
+    PALETTE,
+    EVENTS,
+}
+
+impl DebugView {
+    pub fn next(view: Option<Self>) -> Option<Self> {
+        match view {
+            None => Some(DebugView::NAMETABLES),
+            Some(DebugView::NAMETABLES) => Some(DebugView::PATTERNS),
+            Some(DebugView::PATTERNS) => Some(DebugView::OAM),
+            Some(DebugView::OAM) => Some(DebugView::PALETTE),
+            Some(DebugView::PALETTE) => Some(DebugView::EVENTS),
+            Some(DebugView::EVENTS) => None,
+        }
+    }
+
+    fn scale(self) -> u32 {
+        match self {
+            DebugView::NAMETABLES => 1,
+            DebugView::PATTERNS => 3,
+            DebugView::OAM => 4,
+            DebugView::PALETTE => 2,
+            DebugView::EVENTS => 2,
+        }
+    }
+}
+
+pub struct DebugWindow {
+    pub view: Option<DebugView>,
+    // palette group for the pattern tables, 4-7 are the sprite palettes
+    pub pattern_group: u8,
+    canvas: Option<Canvas<Window>>,
+}
+
+impl DebugWindow {
+    pub fn new() -> Self {
+        DebugWindow {
+            view: None,
+            pattern_group: 0,
+            canvas: None,
+        }
+    }
+
+    pub fn window_id(&self) -> Option<u32> {
+        self.canvas.as_ref().map(|canvas| canvas.window().id())
+    }
+
+    // Show the next view, opening the window on first use
+    pub fn cycle(&mut self, video: &VideoSubsystem) -> Result<(), String> {
+        self.view = DebugView::next(self.view);
+        let Some(view) = self.view else {
+            self.close();
+            return Ok(());
+        };
+
+        if self.canvas.is_none() {
+            let window = video
+                .window("nnes debug", 512, 480)
+                .build()
+                .map_err(|e| e.to_string())?;
+            let canvas = window
+                .into_canvas()
+                .software()
+                .build()
+                .map_err(|e| e.to_string())?;
+            self.canvas = Some(canvas);
+        }
+        let window = self.canvas.as_mut().unwrap().window_mut();
+        window
+            .set_title(&format!("nnes debug: {:?}", view))
+            .map_err(|e| e.to_string())?;
+        window.show();
+        Ok(())
+    }
+
+    // Show the event or sprite under the mouse in the title bar
+    pub fn hover(
+        &mut self,
+        nnes: &NNES,
+        palette: &Palette,
+        window_id: u32,
+        x: i32,
+        y: i32,
+    ) -> Result<(), String> {
+        let Some(view) = self.view else {
+            return Ok(());
+        };
+        if Some(window_id) != self.window_id() || x < 0 || y < 0 {
+            return Ok(());
+        }
+        let scale = view.scale() as i32;
+        let (x, y) = (x / scale, y / scale);
+        let ppu_ref = nnes.ppu.borrow();
+        let title = match view {
+            DebugView::EVENTS => {
+                let (scanline, cycle) = (y as u16, x as u16);
+                match ppu_ref.events.near(scanline, cycle) {
+                    Some(event) => event.to_string(),
+                    None => {
+                        format!("nnes debug: line {} dot {}", scanline, cycle)
+                    }
+                }
+            }
+            DebugView::OAM => {
+                match ppu_ref.oam_entry_at(palette, x as usize, y as usize) {
+                    Some(entry) => entry.to_string(),
+                    None => "nnes debug: OAM".to_string(),
+                }
+            }
+            _ => return Ok(()),
+        };
+        drop(ppu_ref);
+        let canvas = self.canvas.as_mut().unwrap();
+        canvas
+            .window_mut()
+            .set_title(&title)
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn close(&mut self) {
+        self.view = None;
+        if let Some(canvas) = self.canvas.as_mut() {
+            canvas.window_mut().hide();
+        }
+    }
+
+    pub fn draw(
+        &mut self,
+        nnes: &NNES,
+        palette: &Palette,
+    ) -> Result<(), String> {
+        // only log events while someone is looking at them
+        nnes.ppu.borrow_mut().events.enabled =
+            self.view == Some(DebugView::EVENTS);
+        let (Some(view), Some(canvas)) = (self.view, self.canvas.as_mut())
+        else {
+            return Ok(());
+        };
+
+        let ppu_ref = nnes.ppu.borrow();
+        let image: DebugImage = match view {
+            DebugView::NAMETABLES => ppu_ref.nametable_view(palette),
+            DebugView::PATTERNS => {
+                ppu_ref.pattern_view(palette, self.pattern_group)
+            }
+            DebugView::OAM => ppu_ref.oam_view(palette),
+            DebugView::PALETTE => ppu_ref.palette_view(palette),
+            DebugView::EVENTS => ppu_ref.event_view(),
+        };
+        drop(ppu_ref);
+
+        let (w, h) = (image.width as u32, image.height as u32);
+        let size = (w * view.scale(), h * view.scale());
+        if canvas.window().size() != size {
+            canvas
+                .window_mut()
+                .set_size(size.0, size.1)
+                .map_err(|e| e.to_string())?;
+        }
+        let texture_creator = canvas.texture_creator();
+        let mut texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, w, h)
+            .map_err(|e| e.to_string())?;
+        texture
+            .update(None, &image.data, image.width * 3)
+            .map_err(|e| e.to_string())?;
+        canvas.clear();
+        canvas.copy(&texture, None, None)?;
+        canvas.present();
+        Ok(())
+    }
+}
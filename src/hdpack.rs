@@ -0,0 +1,407 @@
+mod conditions;
+mod image;
+mod parse;
+
+use crate::nnes::{SourceFrame, TileKey, TileSource, NNES};
+use crate::palette::Palette;
+use conditions::Condition;
+use image::HdImage;
+use std::{collections::HashMap, fs, path::Path};
+
+// Mesen-style HD packs: a directory with a hires.txt and PNG sheets. Tiles
+// are matched by CHR tile and palette, using the pixel sources the PPU
+// records, and drawn from the sheets at `scale` times the NES resolution.
+
+// One <tile> replacement
+#[derive(Clone)]
+struct HdTile {
+    image: usize,
+    // top left of the tile's artwork in the image
+    x: usize,
+    y: usize,
+    brightness: f32,
+    // (condition, negated), all of them must hold
+    conditions: Vec<(usize, bool)>,
+}
+
+// An image drawn behind everything, where the backdrop would show
+struct HdBackground {
+    image: usize,
+    brightness: f32,
+    // how far it moves per pixel scrolled, 0 keeps it still
+    scroll_ratio: (f32, f32),
+    // offset into the image, in output pixels
+    left: i64,
+    top: i64,
+    conditions: Vec<(usize, bool)>,
+}
+
+pub struct HdPack {
+    scale: usize,
+    images: Vec<HdImage>,
+    conditions: Vec<Condition>,
+    tiles: HashMap<(TileKey, u32), Vec<HdTile>>,
+    // tiles marked as the default for any palette
+    default_tiles: HashMap<TileKey, Vec<HdTile>>,
+    backgrounds: Vec<HdBackground>,
+
+    // results of the conditions that hold for a whole frame, None for the
+    // ones checked per pixel
+    frame_results: Vec<Option<bool>>,
+    // RGB24, 256 * scale x 240 * scale
+    output: Vec<u8>,
+}
+
+impl HdPack {
+    fn new() -> Self {
+        HdPack {
+            scale: 1,
+            images: Vec::new(),
+            conditions: Vec::new(),
+            tiles: HashMap::new(),
+            default_tiles: HashMap::new(),
+            backgrounds: Vec::new(),
+            frame_results: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let path = dir.join("hires.txt");
+        let text = fs::read_to_string(&path).map_err(|e| {
+            format!("error: could not read {}: {}", path.display(), e)
+        })?;
+        parse::parse(&text, dir)
+    }
+
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    // The last finished frame with the pack's artwork swapped in
+    pub fn render(&mut self, nnes: &NNES, palette: &Palette) -> &[u8] {
+        let ppu_ref = nnes.ppu.borrow();
+        let sources = &ppu_ref.sources.frame;
+        let peek = |addr| nnes.peek(addr);
+        self.frame_results = self
+            .conditions
+            .iter()
+            .map(|condition| match condition.check_pixel(sources, 0, 0) {
+                Some(_) => None,
+                None => Some(condition.check_frame(
+                    sources,
+                    &peek,
+                    ppu_ref.total_frames,
+                )),
+            })
+            .collect();
+        let background = self
+            .backgrounds
+            .iter()
+            .find(|bg| self.passes(&bg.conditions, sources, 0, 0));
+
+        let s = self.scale;
+        let width = 256 * s;
+        let mut output = std::mem::take(&mut self.output);
+        output.resize(width * 240 * s * 3, 0);
+        for y in 0..240 {
+            for x in 0..256 {
+                let pixel = &sources.pixels[y * 256 + x];
+                let (px, py) = (x as i32, y as i32);
+                let find = |source: Option<TileSource>| {
+                    let source = source?;
+                    Some((source, self.find_tile(&source, sources, px, py)))
+                };
+                let background_layer = find(pixel.background);
+                let sprite_layer = find(pixel.sprite);
+                let layers = if pixel.sprite_behind {
+                    [sprite_layer, background_layer]
+                } else {
+                    [background_layer, sprite_layer]
+                };
+
+                for sy in 0..s {
+                    for sx in 0..s {
+                        let (ox, oy) = (x * s + sx, y * s + sy);
+                        let (r, g, b) = palette.rgb(pixel.backdrop);
+                        let mut rgb = [r as f32, g as f32, b as f32];
+                        if let Some(bg) = background {
+                            let scroll = sources.scroll;
+                            if let Some(color) =
+                                self.background_pixel(bg, scroll, ox, oy)
+                            {
+                                blend(&mut rgb, color, bg.brightness);
+                            }
+                        }
+                        for (source, tile) in layers.iter().flatten() {
+                            let color = match tile {
+                                Some(tile) => {
+                                    self.tile_pixel(source, tile, sx, sy)
+                                }
+                                None if source.pattern != 0 => {
+                                    let (r, g, b) = palette.rgb(source.color);
+                                    Some([r, g, b, 0xFF])
+                                }
+                                None => None,
+                            };
+                            let brightness =
+                                tile.map_or(1.0, |t| t.brightness);
+                            if let Some(color) = color {
+                                blend(&mut rgb, color, brightness);
+                            }
+                        }
+                        let i = (oy * width + ox) * 3;
+                        for c in 0..3 {
+                            output[i + c] = rgb[c].round() as u8;
+                        }
+                    }
+                }
+            }
+        }
+        self.output = output;
+        &self.output
+    }
+
+    // Helpers
+    fn passes(
+        &self,
+        requirements: &[(usize, bool)],
+        sources: &SourceFrame,
+        x: i32,
+        y: i32,
+    ) -> bool {
+        requirements.iter().all(|&(condition, negate)| {
+            let result = self.frame_results[condition].unwrap_or_else(|| {
+                self.conditions[condition]
+                    .check_pixel(sources, x, y)
+                    .unwrap_or(false)
+            });
+            result != negate
+        })
+    }
+
+    fn find_tile(
+        &self,
+        source: &TileSource,
+        sources: &SourceFrame,
+        x: i32,
+        y: i32,
+    ) -> Option<&HdTile> {
+        let exact = self.tiles.get(&(source.tile, source.palette));
+        let default = self.default_tiles.get(&source.tile);
+        exact
+            .into_iter()
+            .chain(default)
+            .flatten()
+            .find(|tile| self.passes(&tile.conditions, sources, x, y))
+    }
+
+    // Pixel (sx, sy) of the scale x scale block for one NES pixel
+    fn tile_pixel(
+        &self,
+        source: &TileSource,
+        tile: &HdTile,
+        sx: usize,
+        sy: usize,
+    ) -> Option<[u8; 4]> {
+        let s = self.scale;
+        let sx = if source.flip_h { s - 1 - sx } else { sx };
+        let sy = if source.flip_v { s - 1 - sy } else { sy };
+        self.images[tile.image].pixel(
+            tile.x + source.x as usize * s + sx,
+            tile.y + source.y as usize * s + sy,
+        )
+    }
+
+    fn background_pixel(
+        &self,
+        background: &HdBackground,
+        (scroll_x, scroll_y): (u16, u16),
+        ox: usize,
+        oy: usize,
+    ) -> Option<[u8; 4]> {
+        let s = self.scale as f32;
+        let (ratio_x, ratio_y) = background.scroll_ratio;
+        let x = background.left
+            + (scroll_x as f32 * ratio_x * s) as i64
+            + ox as i64;
+        let y = background.top
+            + (scroll_y as f32 * ratio_y * s) as i64
+            + oy as i64;
+        if x < 0 || y < 0 {
+            return None;
+        }
+        self.images[background.image].pixel(x as usize, y as usize)
+    }
+}
+
+// Alpha blend an RGBA color, scaled by brightness, over rgb
+fn blend(rgb: &mut [f32; 3], color: [u8; 4], brightness: f32) {
+    let alpha = color[3] as f32 / 255.0;
+    for c in 0..3 {
+        let value = f32::min(255.0, color[c] as f32 * brightness);
+        rgb[c] += (value - rgb[c]) * alpha;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, HdPack};
+    use crate::cartridge::Cartridge;
+    use crate::nnes::{SourceFrame, TileKey, TileSource, NNES};
+    use crate::palette::Palette;
+    use crate::region::Region;
+    use std::{env, fs, fs::File};
+
+    const PALETTE: u32 = 0x0F161626;
+
+    // Parses hires.txt next to a 32x16 tiles.png, where pixel (x, y) is
+    // (4x, 8y, 100) on the left half and (4x, 8y, 200) on the right
+    fn pack(name: &str, text: &str) -> HdPack {
+        let dir = env::temp_dir().join(format!(
+            "nnes-hdrender-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let file = File::create(dir.join("tiles.png")).unwrap();
+        let mut encoder = png::Encoder::new(file, 32, 16);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        let data: Vec<u8> = (0..16 * 32)
+            .flat_map(|i| {
+                let (x, y) = (i % 32, i / 32);
+                [
+                    x as u8 * 4,
+                    y as u8 * 8,
+                    if x < 16 { 100 } else { 200 },
+                    255,
+                ]
+            })
+            .collect();
+        writer.write_image_data(&data).unwrap();
+        writer.finish().unwrap();
+        let pack = parse::parse(text, &dir);
+        fs::remove_dir_all(dir).unwrap();
+        pack.unwrap()
+    }
+
+    fn source(tile: u32, palette: u32, x: u8, y: u8) -> TileSource {
+        TileSource {
+            tile: TileKey::ROM(tile),
+            palette,
+            x,
+            y,
+            flip_h: false,
+            flip_v: false,
+            pattern: 1,
+            color: 0x16,
+        }
+    }
+
+    // Renders a frame with the given background tile pixels
+    fn render(
+        pack: &mut HdPack,
+        pixels: &[((usize, usize), TileSource)],
+        total_frames: u64,
+    ) -> Vec<u8> {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1];
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        let nnes = NNES::new(Cartridge::new(rom).unwrap(), Region::NTSC);
+        {
+            let mut ppu_ref = nnes.ppu.borrow_mut();
+            let mut frame = SourceFrame {
+                pixels: vec![Default::default(); 256 * 240],
+                scroll: (0, 0),
+            };
+            for &((x, y), source) in pixels {
+                frame.pixels[y * 256 + x].background = Some(source);
+            }
+            ppu_ref.sources.frame = frame;
+            ppu_ref.total_frames = total_frames;
+        }
+        pack.render(&nnes, &Palette::builtin()).to_vec()
+    }
+
+    // Pixel of a scale 2 output
+    fn rgb(output: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let i = (y * 512 + x) * 3;
+        [output[i], output[i + 1], output[i + 2]]
+    }
+
+    fn nes_rgb(color: u16) -> [u8; 3] {
+        let (r, g, b) = Palette::builtin().rgb(color);
+        [r, g, b]
+    }
+
+    const HEADER: &str = "<ver>106\n<scale>2\n<img>tiles.png\n";
+
+    #[test]
+    fn tiles_replace_their_pixels() {
+        let text = format!("{}<tile>0,1A,0F161626,0,0,1,N", HEADER);
+        let mut pack = pack("replace", &text);
+        let output =
+            render(&mut pack, &[((10, 20), source(0x1A, PALETTE, 3, 5))], 0);
+        // a 2x2 block from pixel (3, 5) of the tile's art
+        assert_eq!(rgb(&output, 20, 40), [24, 80, 100]);
+        assert_eq!(rgb(&output, 21, 40), [28, 80, 100]);
+        assert_eq!(rgb(&output, 20, 41), [24, 88, 100]);
+        // the rest is backdrop
+        assert_eq!(rgb(&output, 0, 0), nes_rgb(0));
+
+        let mut flipped = source(0x1A, PALETTE, 3, 5);
+        flipped.flip_h = true;
+        flipped.flip_v = true;
+        let output = render(&mut pack, &[((10, 20), flipped)], 0);
+        assert_eq!(rgb(&output, 20, 40), [28, 88, 100]);
+        assert_eq!(rgb(&output, 21, 41), [24, 80, 100]);
+    }
+
+    #[test]
+    fn other_palettes_use_the_default_tile_or_the_nes_color() {
+        let text = format!(
+            "{}<tile>0,1A,0F161626,0,0,1,N\n<tile>0,2B,0F161626,16,0,0.5,Y",
+            HEADER
+        );
+        let mut pack = pack("default", &text);
+        let other = 0x0F121212;
+        let output = render(
+            &mut pack,
+            &[
+                ((0, 0), source(0x1A, other, 0, 0)),
+                ((1, 0), source(0x2B, other, 0, 0)),
+            ],
+            0,
+        );
+        assert_eq!(rgb(&output, 0, 0), nes_rgb(0x16));
+        // half brightness
+        assert_eq!(rgb(&output, 2, 0), [32, 0, 100]);
+    }
+
+    #[test]
+    fn conditional_tiles_come_first() {
+        // frameRange holds on odd frames, the tileNearby with no palette
+        // holds when any palette of tile 1A is 8 pixels below
+        let text = format!(
+            "{}<condition>odd,frameRange,2,1
+            <condition>below,tileNearby,0,8,1A
+            <tile>0,2B,0F161626,0,0,1,N
+            [odd]<tile>0,2B,0F161626,16,0,1,N
+            [below&!odd]<tile>0,2B,0F161626,0,8,1,N",
+            HEADER
+        );
+        let mut pack = pack("conditions", &text);
+        let pixels = [
+            ((0, 0), source(0x2B, PALETTE, 0, 0)),
+            ((8, 0), source(0x2B, PALETTE, 0, 0)),
+            ((8, 8), source(0x1A, 0x0F000000, 0, 0)),
+        ];
+        let output = render(&mut pack, &pixels, 0);
+        assert_eq!(rgb(&output, 0, 0), [0, 0, 100]);
+        assert_eq!(rgb(&output, 16, 0), [0, 64, 100]);
+        let output = render(&mut pack, &pixels, 1);
+        assert_eq!(rgb(&output, 0, 0), [64, 0, 200]);
+        assert_eq!(rgb(&output, 16, 0), [64, 0, 200]);
+    }
+}
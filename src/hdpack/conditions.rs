@@ -0,0 +1,317 @@
+use crate::nnes::{SourceFrame, TileKey};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Compare {
+    EQ,
+    NE,
+    GT,
+    LT,
+    GE,
+    LE,
+}
+
+impl Compare {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "==" => Some(Compare::EQ),
+            "!=" => Some(Compare::NE),
+            ">" => Some(Compare::GT),
+            "<" => Some(Compare::LT),
+            ">=" => Some(Compare::GE),
+            "<=" => Some(Compare::LE),
+            _ => None,
+        }
+    }
+
+    fn apply(self, lhs: u8, rhs: u8) -> bool {
+        match self {
+            Compare::EQ => lhs == rhs,
+            Compare::NE => lhs != rhs,
+            Compare::GT => lhs > rhs,
+            Compare::LT => lhs < rhs,
+            Compare::GE => lhs >= rhs,
+            Compare::LE => lhs <= rhs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Operand {
+    ADDRESS(u16),
+    CONSTANT(u8),
+}
+
+// What a <condition> line checks. Tile checks look at the frame's pixel
+// sources, memory checks at CPU memory once the frame is done.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum Condition {
+    // a background tile, or sprite, at a screen position, or relative to
+    // the pixel being drawn
+    TILE {
+        sprite: bool,
+        relative: bool,
+        x: i32,
+        y: i32,
+        tile: TileKey,
+        // None matches any palette
+        palette: Option<u32>,
+    },
+    MEMORY {
+        addr: u16,
+        compare: Compare,
+        operand: Operand,
+        mask: u8,
+    },
+    // frame % divisor >= compare
+    FRAME_RANGE {
+        divisor: u64,
+        compare: u64,
+    },
+}
+
+impl Condition {
+    // Result for one pixel, None when it is the same for the whole frame
+    pub fn check_pixel(
+        &self,
+        sources: &SourceFrame,
+        x: i32,
+        y: i32,
+    ) -> Option<bool> {
+        match *self {
+            Condition::TILE {
+                sprite,
+                relative: true,
+                x: dx,
+                y: dy,
+                tile,
+                palette,
+            } => Some(tile_at(sources, sprite, x + dx, y + dy, tile, palette)),
+            _ => None,
+        }
+    }
+
+    // Result for the whole frame, for all but the relative tile checks
+    pub fn check_frame(
+        &self,
+        sources: &SourceFrame,
+        peek: &dyn Fn(u16) -> u8,
+        frame: u64,
+    ) -> bool {
+        match *self {
+            Condition::TILE {
+                sprite,
+                x,
+                y,
+                tile,
+                palette,
+                ..
+            } => tile_at(sources, sprite, x, y, tile, palette),
+            Condition::MEMORY {
+                addr,
+                compare,
+                operand,
+                mask,
+            } => {
+                let rhs = match operand {
+                    Operand::ADDRESS(other) => peek(other) & mask,
+                    Operand::CONSTANT(value) => value,
+                };
+                compare.apply(peek(addr) & mask, rhs)
+            }
+            Condition::FRAME_RANGE { divisor, compare } => {
+                divisor != 0 && frame % divisor >= compare
+            }
+        }
+    }
+}
+
+// Helpers
+fn tile_at(
+    sources: &SourceFrame,
+    sprite: bool,
+    x: i32,
+    y: i32,
+    tile: TileKey,
+    palette: Option<u32>,
+) -> bool {
+    if !(0..256).contains(&x) || !(0..240).contains(&y) {
+        return false;
+    }
+    let pixel = &sources.pixels[(y * 256 + x) as usize];
+    let layer = if sprite {
+        pixel.sprite
+    } else {
+        pixel.background
+    };
+    layer.is_some_and(|source| {
+        source.tile == tile
+            && palette.is_none_or(|palette| source.palette == palette)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compare, Condition, Operand};
+    use crate::nnes::{SourceFrame, TileKey, TileSource};
+
+    const PALETTE: u32 = 0x0F161626;
+
+    fn source(tile: u32, palette: u32) -> TileSource {
+        TileSource {
+            tile: TileKey::ROM(tile),
+            palette,
+            x: 0,
+            y: 0,
+            flip_h: false,
+            flip_v: false,
+            pattern: 1,
+            color: 0x16,
+        }
+    }
+
+    // background tile 0x1A at (16, 32), sprite tile 0x2B at (20, 40)
+    fn frame() -> SourceFrame {
+        let mut frame = SourceFrame {
+            pixels: vec![Default::default(); 256 * 240],
+            scroll: (0, 0),
+        };
+        frame.pixels[32 * 256 + 16].background = Some(source(0x1A, PALETTE));
+        frame.pixels[40 * 256 + 20].sprite = Some(source(0x2B, PALETTE));
+        frame
+    }
+
+    fn tile(
+        sprite: bool,
+        relative: bool,
+        x: i32,
+        y: i32,
+        tile: u32,
+    ) -> Condition {
+        Condition::TILE {
+            sprite,
+            relative,
+            x,
+            y,
+            tile: TileKey::ROM(tile),
+            palette: Some(PALETTE),
+        }
+    }
+
+    fn with_palette(condition: Condition, palette: Option<u32>) -> Condition {
+        match condition {
+            Condition::TILE {
+                sprite,
+                relative,
+                x,
+                y,
+                tile,
+                ..
+            } => Condition::TILE {
+                sprite,
+                relative,
+                x,
+                y,
+                tile,
+                palette,
+            },
+            _ => condition,
+        }
+    }
+
+    #[test]
+    fn tile_at_a_screen_position() {
+        let frame = frame();
+        let check = |c: Condition| {
+            assert_eq!(c.check_pixel(&frame, 0, 0), None);
+            c.check_frame(&frame, &|_| 0, 0)
+        };
+        assert!(check(tile(false, false, 16, 32, 0x1A)));
+        assert!(check(tile(true, false, 20, 40, 0x2B)));
+        // wrong layer, tile or position
+        assert!(!check(tile(true, false, 16, 32, 0x1A)));
+        assert!(!check(tile(false, false, 16, 32, 0x1B)));
+        assert!(!check(tile(false, false, 17, 32, 0x1A)));
+        assert!(!check(tile(false, false, -1, 32, 0x1A)));
+        assert!(!check(tile(false, false, 16, 240, 0x1A)));
+    }
+
+    #[test]
+    fn tile_palette_none_matches_any() {
+        let frame = frame();
+        let check = |c: Condition| c.check_frame(&frame, &|_| 0, 0);
+        let condition = tile(false, false, 16, 32, 0x1A);
+        assert!(check(with_palette(condition, None)));
+        assert!(!check(with_palette(condition, Some(0x0F161627))));
+        // still needs a tile there
+        let empty = tile(false, false, 0, 0, 0x1A);
+        assert!(!check(with_palette(empty, None)));
+    }
+
+    #[test]
+    fn relative_tiles_are_checked_per_pixel() {
+        let frame = frame();
+        let above = tile(false, true, 0, -8, 0x1A);
+        assert_eq!(above.check_pixel(&frame, 16, 40), Some(true));
+        assert_eq!(above.check_pixel(&frame, 16, 32), Some(false));
+        // off the screen
+        assert_eq!(above.check_pixel(&frame, 16, 4), Some(false));
+        let any = with_palette(above, None);
+        assert_eq!(any.check_pixel(&frame, 16, 40), Some(true));
+    }
+
+    #[test]
+    fn memory_checks() {
+        let frame = frame();
+        let ram = |addr: u16| match addr {
+            0x0700 => 0x35,
+            0x0701 => 0x05,
+            _ => 0,
+        };
+        let check = |compare, operand, mask| {
+            let condition = Condition::MEMORY {
+                addr: 0x0700,
+                compare,
+                operand,
+                mask,
+            };
+            condition.check_frame(&frame, &ram, 0)
+        };
+        let constant = Operand::CONSTANT;
+        assert!(check(Compare::EQ, constant(0x35), 0xFF));
+        assert!(!check(Compare::NE, constant(0x35), 0xFF));
+        assert!(check(Compare::GT, constant(0x34), 0xFF));
+        assert!(!check(Compare::LT, constant(0x35), 0xFF));
+        assert!(check(Compare::GE, constant(0x35), 0xFF));
+        assert!(check(Compare::LE, constant(0x36), 0xFF));
+        // the mask applies to the address, not the constant
+        assert!(check(Compare::EQ, constant(0x05), 0x0F));
+        assert!(!check(Compare::EQ, constant(0x35), 0x0F));
+        // and to both sides of an address compare
+        assert!(!check(Compare::EQ, Operand::ADDRESS(0x0701), 0xFF));
+        assert!(check(Compare::EQ, Operand::ADDRESS(0x0701), 0x0F));
+    }
+
+    #[test]
+    fn frame_range() {
+        let frame = frame();
+        let condition = Condition::FRAME_RANGE {
+            divisor: 4,
+            compare: 2,
+        };
+        let results: Vec<bool> = (0..8)
+            .map(|n| condition.check_frame(&frame, &|_| 0, n))
+            .collect();
+        assert_eq!(
+            results,
+            [false, false, true, true, false, false, true, true]
+        );
+        let never = Condition::FRAME_RANGE {
+            divisor: 0,
+            compare: 0,
+        };
+        assert!(!never.check_frame(&frame, &|_| 0, 7));
+    }
+}
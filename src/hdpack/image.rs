@@ -0,0 +1,63 @@
+use std::{fs::File, path::Path};
+
+// A PNG sheet from the pack, as RGBA
+pub struct HdImage {
+    pub width: usize,
+    pub height: usize,
+    rgba: Vec<u8>,
+}
+
+impl HdImage {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let error = |e: &dyn std::fmt::Display| {
+            format!("could not read {}: {}", path.display(), e)
+        };
+        let file = File::open(path).map_err(|e| error(&e))?;
+        let mut decoder = png::Decoder::new(file);
+        // palette, 16 bit and low bit depth images all come out as 8 bits
+        // per channel, with tRNS turned into alpha
+        decoder
+            .set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(|e| error(&e))?;
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).map_err(|e| error(&e))?;
+        data.truncate(info.buffer_size());
+
+        let rgba = match info.color_type {
+            png::ColorType::Rgba => data,
+            png::ColorType::Rgb => data
+                .chunks_exact(3)
+                .flat_map(|p| [p[0], p[1], p[2], 0xFF])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => data
+                .chunks_exact(2)
+                .flat_map(|p| [p[0], p[0], p[0], p[1]])
+                .collect(),
+            png::ColorType::Grayscale => {
+                data.iter().flat_map(|&v| [v, v, v, 0xFF]).collect()
+            }
+            png::ColorType::Indexed => {
+                return Err(error(&"unexpected indexed output"))
+            }
+        };
+        Ok(HdImage {
+            width: info.width as usize,
+            height: info.height as usize,
+            rgba,
+        })
+    }
+
+    // None outside the image
+    pub fn pixel(&self, x: usize, y: usize) -> Option<[u8; 4]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let i = (y * self.width + x) * 4;
+        Some([
+            self.rgba[i],
+            self.rgba[i + 1],
+            self.rgba[i + 2],
+            self.rgba[i + 3],
+        ])
+    }
+}
@@ -0,0 +1,426 @@
+use super::conditions::{Compare, Condition, Operand};
+use super::image::HdImage;
+use super::{HdBackground, HdPack, HdTile};
+use crate::nnes::TileKey;
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+// hires.txt holds one tag per line:
+//   <ver>106
+//   <scale>2
+//   <img>tiles.png
+//   <condition>name,type,args...
+//   [cond1&!cond2]<tile>img,tile,palette,x,y,brightness,default
+//   [cond]<background>file.png,brightness,h ratio,v ratio,priority,left,top
+// Other tags (audio, patches, options) are skipped.
+
+struct Parser<'a> {
+    dir: &'a Path,
+    version: u32,
+    // condition names -> index in pack.conditions
+    names: HashMap<String, usize>,
+    // file names -> index in pack.images, and <img> index -> pack.images
+    files: HashMap<String, usize>,
+    img: Vec<usize>,
+    pack: HdPack,
+}
+
+pub fn parse(text: &str, dir: &Path) -> Result<HdPack, String> {
+    let mut parser = Parser {
+        dir,
+        version: 0,
+        names: HashMap::new(),
+        files: HashMap::new(),
+        img: Vec::new(),
+        pack: HdPack::new(),
+    };
+    for (i, line) in text.lines().enumerate() {
+        parser.line(line.trim()).map_err(|msg| {
+            let path = dir.join("hires.txt");
+            format!("error: {}:{}: {}", path.display(), i + 1, msg)
+        })?;
+    }
+
+    // replacements with conditions are tried before the plain ones
+    let pack = &mut parser.pack;
+    for tiles in pack
+        .tiles
+        .values_mut()
+        .chain(pack.default_tiles.values_mut())
+    {
+        tiles.sort_by_key(|tile| tile.conditions.is_empty());
+    }
+    Ok(parser.pack)
+}
+
+impl Parser<'_> {
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        let (conditions, line) = match line.strip_prefix('[') {
+            Some(rest) => {
+                let (list, rest) =
+                    rest.split_once(']').ok_or("missing ]".to_string())?;
+                (self.requirements(list)?, rest)
+            }
+            None => (Vec::new(), line),
+        };
+        // blank lines and anything else that is not a tag
+        let Some(rest) = line.strip_prefix('<') else {
+            return Ok(());
+        };
+        let (tag, value) = rest.split_once('>').ok_or("missing >")?;
+        let args: Vec<&str> = value.split(',').map(str::trim).collect();
+        match tag {
+            "ver" => self.version = decimal(value)?,
+            "scale" => {
+                self.pack.scale = decimal(value)?;
+                if !(1..=10).contains(&self.pack.scale) {
+                    return Err(format!("unsupported scale {}", value));
+                }
+            }
+            "img" => {
+                let image = self.image(value)?;
+                self.img.push(image);
+            }
+            "condition" => self.condition(&args)?,
+            "tile" => self.tile(&args, conditions)?,
+            "background" => self.background(&args, conditions)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // "a&!b": (condition, negated) pairs
+    fn requirements(&self, list: &str) -> Result<Vec<(usize, bool)>, String> {
+        list.split('&')
+            .map(|name| {
+                let name = name.trim();
+                let (name, negate) = match name.strip_prefix('!') {
+                    Some(name) => (name.trim(), true),
+                    None => (name, false),
+                };
+                let Some(&condition) = self.names.get(name) else {
+                    return Err(format!("unknown condition {}", name));
+                };
+                Ok((condition, negate))
+            })
+            .collect()
+    }
+
+    fn image(&mut self, file: &str) -> Result<usize, String> {
+        if let Some(&image) = self.files.get(file) {
+            return Ok(image);
+        }
+        let image = HdImage::load(&self.dir.join(file))?;
+        self.pack.images.push(image);
+        self.files
+            .insert(file.to_string(), self.pack.images.len() - 1);
+        Ok(self.pack.images.len() - 1)
+    }
+
+    fn condition(&mut self, args: &[&str]) -> Result<(), String> {
+        let name = arg(args, 0)?;
+        let kind = arg(args, 1)?;
+        let condition = match kind {
+            "tileAtPosition" | "spriteAtPosition" | "tileNearby"
+            | "spriteNearby" => Condition::TILE {
+                sprite: kind.starts_with("sprite"),
+                relative: kind.ends_with("Nearby"),
+                x: decimal(arg(args, 2)?)?,
+                y: decimal(arg(args, 3)?)?,
+                tile: self.tile_key(arg(args, 4)?)?,
+                palette: match args.get(5) {
+                    Some(palette) if !palette.is_empty() => {
+                        Some(hex(palette)?)
+                    }
+                    _ => None,
+                },
+            },
+            "memoryCheck" | "memoryCheckConstant" => {
+                let compare = arg(args, 3)?;
+                let operand = hex(arg(args, 4)?)?;
+                Condition::MEMORY {
+                    addr: hex(arg(args, 2)?)? as u16,
+                    compare: Compare::from_name(compare).ok_or_else(|| {
+                        format!("unknown operator {}", compare)
+                    })?,
+                    operand: if kind == "memoryCheck" {
+                        Operand::ADDRESS(operand as u16)
+                    } else {
+                        Operand::CONSTANT(operand as u8)
+                    },
+                    mask: match args.get(5) {
+                        Some(mask) => hex(mask)? as u8,
+                        None => 0xFF,
+                    },
+                }
+            }
+            "frameRange" => Condition::FRAME_RANGE {
+                divisor: decimal(arg(args, 2)?)?,
+                compare: decimal(arg(args, 3)?)?,
+            },
+            _ => return Err(format!("unknown condition type {}", kind)),
+        };
+        self.names
+            .insert(name.to_string(), self.pack.conditions.len());
+        self.pack.conditions.push(condition);
+        Ok(())
+    }
+
+    fn tile(
+        &mut self,
+        args: &[&str],
+        conditions: Vec<(usize, bool)>,
+    ) -> Result<(), String> {
+        let img: usize = decimal(arg(args, 0)?)?;
+        let Some(&image) = self.img.get(img) else {
+            return Err(format!("no <img> number {}", img));
+        };
+        let key = self.tile_key(arg(args, 1)?)?;
+        let palette = hex(arg(args, 2)?)?;
+        let tile = HdTile {
+            image,
+            x: decimal(arg(args, 3)?)?,
+            y: decimal(arg(args, 4)?)?,
+            brightness: optional(args, 5, 1.0)?,
+            conditions,
+        };
+        // default tiles also stand in for palettes with no art of their own
+        if args.get(6).is_some_and(|d| d.eq_ignore_ascii_case("y")) {
+            self.pack
+                .default_tiles
+                .entry(key)
+                .or_default()
+                .push(tile.clone());
+        }
+        self.pack
+            .tiles
+            .entry((key, palette))
+            .or_default()
+            .push(tile);
+        Ok(())
+    }
+
+    fn background(
+        &mut self,
+        args: &[&str],
+        conditions: Vec<(usize, bool)>,
+    ) -> Result<(), String> {
+        // the priority field is skipped, backgrounds always go behind
+        let background = HdBackground {
+            image: self.image(arg(args, 0)?)?,
+            brightness: optional(args, 1, 1.0)?,
+            scroll_ratio: (optional(args, 2, 0.0)?, optional(args, 3, 0.0)?),
+            left: optional(args, 5, 0)?,
+            top: optional(args, 6, 0)?,
+            conditions,
+        };
+        self.pack.backgrounds.push(background);
+        Ok(())
+    }
+
+    // 32 hex digits of CHR RAM data, or a CHR ROM tile index, in hex from
+    // version 100 on
+    fn tile_key(&self, value: &str) -> Result<TileKey, String> {
+        if value.len() == 32 && value.is_ascii() {
+            let mut data = [0; 16];
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = hex(&value[i * 2..i * 2 + 2])? as u8;
+            }
+            return Ok(TileKey::RAM(data));
+        }
+        let index = if self.version >= 100 {
+            hex(value)?
+        } else {
+            decimal(value)?
+        };
+        Ok(TileKey::ROM(index))
+    }
+}
+
+// Helpers
+fn arg<'a>(args: &[&'a str], i: usize) -> Result<&'a str, String> {
+    args.get(i)
+        .copied()
+        .ok_or_else(|| format!("missing field {}", i + 1))
+}
+
+fn optional<T: FromStr>(
+    args: &[&str],
+    i: usize,
+    default: T,
+) -> Result<T, String> {
+    match args.get(i) {
+        Some(value) if !value.is_empty() => decimal(value),
+        _ => Ok(default),
+    }
+}
+
+fn decimal<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number {}", value))
+}
+
+fn hex(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value.trim(), 16)
+        .map_err(|_| format!("invalid hex number {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::conditions::{Compare, Condition, Operand};
+    use super::super::HdPack;
+    use super::parse;
+    use crate::nnes::TileKey;
+    use std::{env, fs, fs::File, path::PathBuf};
+
+    // A pack directory holding a 16x16 tiles.png
+    fn pack_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "nnes-hdpack-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let file = File::create(dir.join("tiles.png")).unwrap();
+        let mut encoder = png::Encoder::new(file, 16, 16);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0xFF; 16 * 16 * 4]).unwrap();
+        dir
+    }
+
+    fn parse_in(name: &str, text: &str) -> Result<HdPack, String> {
+        let dir = pack_dir(name);
+        let pack = parse(text, &dir);
+        fs::remove_dir_all(dir).unwrap();
+        pack
+    }
+
+    #[test]
+    fn tiles_and_conditions() {
+        let pack = parse_in(
+            "tiles",
+            "<ver>106
+            <scale>2
+            <img>tiles.png
+            <condition>hurt,memoryCheckConstant,0700,==,1
+            <condition>near,tileNearby,0,-8,1A,0F161626
+            <tile>0,1A,0F161626,0,0,1,N
+            [hurt & !near]<tile>0,1A,0F161626,0,16,0.5,Y
+            <tile>0,00112233445566778899AABBCCDDEEFF,0F000000,16,0",
+        )
+        .unwrap();
+        assert_eq!(pack.scale, 2);
+        assert_eq!(pack.images.len(), 1);
+        assert_eq!(
+            pack.conditions,
+            [
+                Condition::MEMORY {
+                    addr: 0x700,
+                    compare: Compare::EQ,
+                    operand: Operand::CONSTANT(1),
+                    mask: 0xFF,
+                },
+                Condition::TILE {
+                    sprite: false,
+                    relative: true,
+                    x: 0,
+                    y: -8,
+                    tile: TileKey::ROM(0x1A),
+                    palette: Some(0x0F161626),
+                },
+            ]
+        );
+
+        // the conditional tile is tried first
+        let tiles = &pack.tiles[&(TileKey::ROM(0x1A), 0x0F161626)];
+        assert_eq!(tiles.len(), 2);
+        assert_eq!(tiles[0].conditions, [(0, false), (1, true)]);
+        assert_eq!((tiles[0].x, tiles[0].y), (0, 16));
+        assert_eq!(tiles[0].brightness, 0.5);
+        assert!(tiles[1].conditions.is_empty());
+        assert_eq!(tiles[1].brightness, 1.0);
+        assert_eq!(pack.default_tiles[&TileKey::ROM(0x1A)].len(), 1);
+
+        let ram = TileKey::RAM([
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA,
+            0xBB, 0xCC, 0xDD, 0xEE, 0xFF,
+        ]);
+        assert_eq!(pack.tiles[&(ram, 0x0F000000)][0].x, 16);
+    }
+
+    #[test]
+    fn tile_index_is_decimal_before_version_100() {
+        let key = |text: &str| {
+            let pack = parse_in("version", text).unwrap();
+            *pack.tiles.keys().next().map(|(key, _)| key).unwrap()
+        };
+        assert_eq!(key("<img>tiles.png\n<tile>0,26,0,0,0"), TileKey::ROM(26));
+        assert_eq!(
+            key("<ver>100\n<img>tiles.png\n<tile>0,26,0,0,0"),
+            TileKey::ROM(0x26)
+        );
+    }
+
+    #[test]
+    fn backgrounds_and_skipped_tags() {
+        let pack = parse_in(
+            "background",
+            "<ver>106
+            <condition>late,frameRange,60,30
+            <options>disableSpriteLimit
+            [late]<background>tiles.png,0.75,0.5,0,0,-4,8",
+        )
+        .unwrap();
+        assert_eq!(
+            pack.conditions,
+            [Condition::FRAME_RANGE {
+                divisor: 60,
+                compare: 30
+            }]
+        );
+        let background = &pack.backgrounds[0];
+        assert_eq!(background.brightness, 0.75);
+        assert_eq!(background.scroll_ratio, (0.5, 0.0));
+        assert_eq!((background.left, background.top), (-4, 8));
+        assert_eq!(background.conditions, [(0, false)]);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        for (text, line, message) in [
+            ("<ver>1x", 1, "invalid number 1x"),
+            ("<scale>0", 1, "unsupported scale 0"),
+            ("\n<scale>11", 2, "unsupported scale 11"),
+            ("<ver", 1, "missing >"),
+            ("[hurt<tile>0,1,0,0,0", 1, "missing ]"),
+            ("[hurt]<tile>0,1,0,0,0", 1, "unknown condition hurt"),
+            ("<tile>0,1,0,0,0", 1, "no <img> number 0"),
+            ("<img>tiles.png\n<tile>0,1,0F", 2, "missing field 4"),
+            (
+                "<img>tiles.png\n<tile>0,1,0G,0,0",
+                2,
+                "invalid hex number 0G",
+            ),
+            (
+                "<condition>a,spinning",
+                1,
+                "unknown condition type spinning",
+            ),
+            ("<condition>a,memoryCheck,0700,=,1", 1, "unknown operator ="),
+        ] {
+            let error = parse_in("errors", text).err().unwrap();
+            let expected = format!("hires.txt:{}: {}", line, message);
+            assert!(error.ends_with(&expected), "{}", error);
+            assert!(error.starts_with("error: "));
+        }
+    }
+
+    #[test]
+    fn missing_images_are_errors() {
+        let error = parse_in("image", "<img>missing.png").err().unwrap();
+        assert!(error.contains("hires.txt:1: could not read"), "{}", error);
+    }
+}
@@ -3,26 +3,45 @@ extern crate lazy_static;
 #[macro_use]
 extern crate bitflags;
 
+mod battery;
 mod cartridge;
 mod controller;
+mod debug;
+mod hdpack;
 mod nnes;
 mod palette;
+mod region;
 mod utils;
+mod video;
 
 use cartridge::{validate_rom, Cartridge};
-use nnes::NNES;
-pub use palette::NES_PALETTE;
+use debug::DebugWindow;
+use hdpack::HdPack;
+use nnes::{RamInit, NNES, SAMPLE_RATE};
+use palette::{NtscSettings, Palette};
+use region::Region;
 use sdl2::{
-    keyboard::Keycode, pixels::PixelFormatEnum, render::Canvas, video::Window,
+    audio::{AudioQueue, AudioSpecDesired},
+    event::WindowEvent,
+    keyboard::Keycode,
+    pixels::PixelFormatEnum,
+    rect::Rect,
+    render::{Canvas, Texture},
+    video::Window,
     Sdl,
 };
 use std::{
     env,
     fs::read,
+    path::{Path, PathBuf},
     process,
     thread::sleep,
     time::{Duration, Instant},
 };
+use video::{
+    BlendMode, CrtMask, CrtSettings, FrameBlend, NtscPreset, Overscan,
+    PixelAspect, ScaleMode, Upscaler, Video,
+};
 
 macro_rules! die {
     ($msg:expr) => {
@@ -31,30 +50,286 @@ macro_rules! die {
     };
 }
 
-fn init_sdl() -> Result<(Sdl, Canvas<Window>), String> {
+fn init_sdl(vsync: bool) -> Result<(Sdl, Canvas<Window>), String> {
     let sdl = sdl2::init()?;
     let video = sdl.video()?;
     let window = video
         .window("nnes", 256 * 2, 240 * 2)
         .position_centered()
+        .resizable()
         .opengl()
         .build()
         .map_err(|e| e.to_string())?;
-    let canvas = window
-        .into_canvas()
-        .software()
-        .present_vsync()
-        .build()
-        .map_err(|e| e.to_string())?;
+    let mut canvas = window.into_canvas().software();
+    if vsync {
+        canvas = canvas.present_vsync();
+    }
+    let canvas = canvas.build().map_err(|e| e.to_string())?;
     Ok((sdl, canvas))
 }
 
-fn init_emu() -> NNES {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        die!("usage: cargo run -- <path to rom>");
+// Mono output at the mixer's rate. No audio device is not an error, the game
+// just runs silently.
+fn init_audio(sdl: &Sdl) -> Option<AudioQueue<f32>> {
+    let spec = AudioSpecDesired {
+        freq: Some(SAMPLE_RATE as i32),
+        channels: Some(1),
+        samples: None,
+    };
+    let queue = sdl
+        .audio()
+        .and_then(|audio| audio.open_queue::<f32, _>(None, &spec));
+    match queue {
+        Ok(queue) => {
+            queue.resume();
+            Some(queue)
+        }
+        Err(e) => {
+            eprintln!("warning: no audio: {}", e);
+            None
+        }
     }
-    let rom = match read(args[1].clone()) {
+}
+
+const USAGE: &str = "usage: cargo run -- <path to rom> [options]
+  --palette <path to .pal>    load a 64 or 512 color palette, repeatable
+  --ntsc                      generate a palette from the NTSC signal
+  --hue <degrees>             NTSC hue shift, default 0
+  --saturation <n>            NTSC saturation, default 1
+  --contrast <n>              NTSC contrast, default 1
+  --brightness <n>            NTSC brightness, default 0
+  --gamma <n>                 NTSC display gamma, default 2.2
+  --filter <name>             NTSC filter: composite, svideo, rgb or mono,
+                              uses the NTSC settings above
+  --crt                       CRT effects, rendered at the window size
+  --scanlines <0-1>           CRT scanline darkening, default 0.5
+  --mask <name>               CRT mask: none, grille or shadow
+  --mask-strength <0-1>       CRT mask darkening, default 0.3
+  --curvature <n>             CRT barrel curvature, default 0.04
+  --bloom <n>                 CRT phosphor bloom, default 0.15
+  --vignette <0-1>            CRT corner darkening, default 0.25
+  --scale <name>              fit: stretch, aspect or integer
+  --par <name>                pixel aspect: square, ntsc (8:7) or pal,
+                              follows the region by default
+  --overscan <t,b,l,r>        NES pixels to crop from each edge
+  --upscaler <name>           nearest, scale2x, scale3x, hq2x, hq3x, hq4x
+                              or xbr, unused with --crt
+  --blend <name>              frame blending: off, mix or lcd
+  --blend-weight <0-1>        share of the previous frame, default 0.5
+  --no-sprite-limit           draw sprites past the 8 per line limit
+  --fast-ppu                  render whole scanlines at once when nothing
+                              changes mid-line
+  --hd-pack <dir>             folder with a Mesen-style hires.txt and PNG
+                              tile sheets
+  --audio-multiplex           play the N163's channels time-multiplexed like
+                              the chip, with its high pitched whine
+  --region <name>             console timing: ntsc, pal or dendy, detected
+                              from the rom header by default
+  --oam-init <name>           power-on OAM: zero, ff, random or console
+  --palette-init <name>       power-on palette RAM: console, zero, ff or
+                              random
+  --export-palette <path>     write the starting palette as a 512 color
+                              .pal, then exit if no rom was given";
+
+// any generator setting implies --ntsc
+const NTSC_FLAGS: [&str; 6] = [
+    "--ntsc",
+    "--hue",
+    "--saturation",
+    "--contrast",
+    "--brightness",
+    "--gamma",
+];
+
+// any CRT setting implies --crt
+const CRT_FLAGS: [&str; 7] = [
+    "--crt",
+    "--scanlines",
+    "--mask",
+    "--mask-strength",
+    "--curvature",
+    "--bloom",
+    "--vignette",
+];
+
+struct Args {
+    rom_path: Option<String>,
+    palette_paths: Vec<String>,
+    ntsc: Option<NtscSettings>,
+    export_path: Option<String>,
+    filter: Option<NtscPreset>,
+    crt: Option<CrtSettings>,
+    scale_mode: ScaleMode,
+    aspect: Option<PixelAspect>,
+    overscan: Overscan,
+    upscaler: Upscaler,
+    blend: BlendMode,
+    blend_weight: f32,
+    unlimited_sprites: bool,
+    fast_ppu: bool,
+    hd_pack: Option<String>,
+    audio_multiplex: bool,
+    region: Option<Region>,
+    oam_init: RamInit,
+    palette_init: RamInit,
+}
+
+fn parse_args() -> Args {
+    let mut rom_path = None;
+    let mut palette_paths = Vec::new();
+    let mut ntsc = NtscSettings::default();
+    let mut generate = false;
+    let mut export_path = None;
+    let mut filter = None;
+    let mut crt = CrtSettings::default();
+    let mut use_crt = false;
+    let mut scale_mode = ScaleMode::ASPECT;
+    let mut aspect = None;
+    let mut overscan = Overscan::default();
+    let mut upscaler = Upscaler::NEAREST;
+    let mut blend = BlendMode::OFF;
+    let mut blend_weight = 0.5;
+    let mut unlimited_sprites = false;
+    let mut fast_ppu = false;
+    let mut hd_pack = None;
+    let mut audio_multiplex = false;
+    let mut region = None;
+    let mut oam_init = RamInit::ZERO;
+    let mut palette_init = RamInit::CONSOLE;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        generate |= NTSC_FLAGS.contains(&arg.as_str());
+        use_crt |= CRT_FLAGS.contains(&arg.as_str());
+        match arg.as_str() {
+            "--palette" => palette_paths.push(next_value(&mut args)),
+            "--ntsc" => {}
+            "--hue" => ntsc.hue = next_number(&mut args),
+            "--saturation" => ntsc.saturation = next_number(&mut args),
+            "--contrast" => ntsc.contrast = next_number(&mut args),
+            "--brightness" => ntsc.brightness = next_number(&mut args),
+            "--gamma" => ntsc.gamma = next_number(&mut args),
+            "--export-palette" => export_path = Some(next_value(&mut args)),
+            "--filter" => {
+                filter = Some(parse_name(&mut args, NtscPreset::from_name))
+            }
+            "--crt" => {}
+            "--scanlines" => crt.scanlines = next_number(&mut args),
+            "--mask" => crt.mask = parse_name(&mut args, CrtMask::from_name),
+            "--mask-strength" => crt.mask_strength = next_number(&mut args),
+            "--curvature" => crt.curvature = next_number(&mut args),
+            "--bloom" => crt.bloom = next_number(&mut args),
+            "--vignette" => crt.vignette = next_number(&mut args),
+            "--scale" => {
+                scale_mode = parse_name(&mut args, ScaleMode::from_name)
+            }
+            "--par" => {
+                aspect = Some(parse_name(&mut args, PixelAspect::from_name))
+            }
+            "--overscan" => overscan = parse_name(&mut args, Overscan::parse),
+            "--upscaler" => {
+                upscaler = parse_name(&mut args, Upscaler::from_name)
+            }
+            "--blend" => blend = parse_name(&mut args, BlendMode::from_name),
+            "--blend-weight" => blend_weight = next_number(&mut args),
+            "--no-sprite-limit" => unlimited_sprites = true,
+            "--fast-ppu" => fast_ppu = true,
+            "--hd-pack" => hd_pack = Some(next_value(&mut args)),
+            "--audio-multiplex" => audio_multiplex = true,
+            "--region" => {
+                region = Some(parse_name(&mut args, Region::from_name))
+            }
+            "--oam-init" => {
+                oam_init = parse_name(&mut args, RamInit::from_name)
+            }
+            "--palette-init" => {
+                palette_init = parse_name(&mut args, RamInit::from_name)
+            }
+            _ if rom_path.is_none() && !arg.starts_with("--") => {
+                rom_path = Some(arg)
+            }
+            _ => {
+                die!(USAGE);
+            }
+        }
+    }
+    if rom_path.is_none() && export_path.is_none() {
+        die!(USAGE);
+    }
+    Args {
+        rom_path,
+        palette_paths,
+        ntsc: generate.then_some(ntsc),
+        export_path,
+        filter,
+        crt: use_crt.then_some(crt),
+        scale_mode,
+        aspect,
+        overscan,
+        upscaler,
+        blend,
+        blend_weight,
+        unlimited_sprites,
+        fast_ppu,
+        hd_pack,
+        audio_multiplex,
+        region,
+        oam_init,
+        palette_init,
+    }
+}
+
+fn parse_name<T>(
+    args: &mut impl Iterator<Item = String>,
+    from_name: fn(&str) -> Option<T>,
+) -> T {
+    match from_name(&next_value(args)) {
+        Some(value) => value,
+        None => {
+            die!(USAGE);
+        }
+    }
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>) -> String {
+    match args.next() {
+        Some(value) => value,
+        None => {
+            die!(USAGE);
+        }
+    }
+}
+
+fn next_number(args: &mut impl Iterator<Item = String>) -> f32 {
+    match next_value(args).parse() {
+        Ok(number) => number,
+        Err(_) => {
+            die!(USAGE);
+        }
+    }
+}
+
+fn init_palettes(args: &Args) -> Vec<Palette> {
+    // the last palette given is the one shown first, P cycles through the
+    // rest and the built-in one
+    let mut palettes = vec![Palette::builtin()];
+    for path in &args.palette_paths {
+        match Palette::load(Path::new(path)) {
+            Ok(palette) => palettes.push(palette),
+            Err(msg) => {
+                die!(msg.as_str());
+            }
+        }
+    }
+    if let Some(settings) = &args.ntsc {
+        palettes.push(Palette::generate(settings));
+    }
+    palettes
+}
+
+fn init_emu(args: &Args) -> (NNES, PathBuf) {
+    let rom_path = args.rom_path.as_deref().unwrap();
+    let rom = match read(rom_path) {
         Ok(rom) => rom,
         Err(_) => {
             die!("error: invalid path to rom");
@@ -66,25 +341,106 @@ fn init_emu() -> NNES {
             die!(msg.as_str());
         }
     };
-    let cartridge = Cartridge::new(&rom);
-    let mut nnes = NNES::new(cartridge);
-    nnes.reset();
-    nnes
+    let cartridge = match Cartridge::new(rom) {
+        Ok(cartridge) => cartridge,
+        Err(msg) => {
+            die!(msg.as_str());
+        }
+    };
+    let region = args.region.unwrap_or(cartridge.region);
+    let mut nnes = NNES::new(cartridge, region);
+    nnes.power_on(args.oam_init, args.palette_init);
+
+    let sav_path = battery::save_path(rom_path);
+    if nnes.has_battery() {
+        if let Some(data) = battery::load(&sav_path) {
+            nnes.load_battery_ram(&data);
+        }
+    }
+    (nnes, sav_path)
+}
+
+fn save_battery(nnes: &mut NNES, sav_path: &Path, last_saved: &mut Vec<u8>) {
+    // only touch the disk when the game actually wrote to its save
+    let Some(data) = nnes.battery_ram() else {
+        return;
+    };
+    if data == *last_saved {
+        return;
+    }
+    match battery::save(sav_path, &data) {
+        Ok(_) => *last_saved = data,
+        Err(e) => eprintln!("warning: could not save battery ram: {}", e),
+    }
 }
 
 fn main() -> Result<(), String> {
-    let (sdl, mut canvas) = init_sdl()?;
+    let args = parse_args();
+    let palettes = init_palettes(&args);
+    let mut palette_idx = palettes.len() - 1;
+    if let Some(path) = &args.export_path {
+        if let Err(e) = palettes[palette_idx].export(Path::new(path)) {
+            die!(format!("error: could not write {}: {}", path, e));
+        }
+        if args.rom_path.is_none() {
+            return Ok(());
+        }
+    }
+
+    let mut video = Video::new(
+        args.ntsc.unwrap_or_default(),
+        args.crt.unwrap_or_default(),
+    );
+    video.filter = args.filter;
+    video.crt_enabled = args.crt.is_some();
+    video.scale_mode = args.scale_mode;
+    video.overscan = args.overscan;
+    video.upscaler = args.upscaler;
+    video.blend = FrameBlend::new(args.blend, args.blend_weight);
+
+    let (mut nnes, sav_path) = init_emu(&args);
+    video.aspect =
+        args.aspect.unwrap_or(PixelAspect::from_region(nnes.region));
+    nnes.ppu.borrow_mut().unlimited_sprites = args.unlimited_sprites;
+    nnes.ppu.borrow_mut().fast_lines = args.fast_ppu;
+    nnes.cartridge
+        .borrow_mut()
+        .set_expansion_audio_multiplex(args.audio_multiplex);
+
+    // Displays mostly refresh at 60 Hz, vsync would hold 50 Hz regions to it
+    let (sdl, mut canvas) = init_sdl(nnes.region == Region::NTSC)?;
+    let audio = init_audio(&sdl);
+    // queue at most a few frames ahead, so the sound does not drift behind
+    // the picture when the frame pacing runs slightly fast
+    let max_queued = SAMPLE_RATE / 10 * std::mem::size_of::<f32>() as u32;
     let texture_creator = canvas.texture_creator();
-    let mut texture = texture_creator
-        .create_texture_streaming(PixelFormatEnum::RGB24, 256, 240)
-        .map_err(|e| e.to_string())?;
+    // the picture's size depends on the video settings, so the texture
+    // follows it
+    let mut texture: Option<Texture> = None;
 
-    let mut nnes = init_emu();
+    let mut hd_pack =
+        args.hd_pack
+            .as_ref()
+            .map(|dir| match HdPack::load(Path::new(dir)) {
+                Ok(pack) => pack,
+                Err(msg) => {
+                    die!(msg.as_str());
+                }
+            });
+    // the PPU only records pixel sources while the pack is drawn
+    nnes.ppu.borrow_mut().sources.enabled = hd_pack.is_some();
+    let mut last_saved = nnes.battery_ram().unwrap_or_default();
 
-    // NES CPU runs ~1.7898 MHz, frame rate ~60.1 Hz: ~29780 CPU ticks/frame.
-    // Tick CPU once per 12 master cycles: 29780 * 12 = ~357360 master cycles per frame.
-    let master_cycles_per_frame = 357360;
-    let target_frame_duration = Duration::from_millis(1000 / 60);
+    // NTSC: 341 * 262 - 0.5 dots of 4 master cycles, ~60.1 Hz.
+    // PAL and Dendy: 341 * 312 dots of 5 master cycles, ~50 Hz.
+    let master_cycles_per_frame = nnes.region.master_cycles_per_frame();
+    let target_frame_duration =
+        Duration::from_secs_f64(1.0 / nnes.region.frame_rate());
+    // flush battery saves every ~10 seconds in case we don't exit cleanly
+    let frames_per_save = nnes.region.frame_rate().round() as u64 * 10;
+    let mut frames = 0u64;
+
+    let mut debug = DebugWindow::new();
 
     let mut event_pump = sdl.event_pump()?;
     'running: loop {
@@ -103,41 +459,271 @@ fn main() -> Result<(), String> {
             nnes.tick();
         }
 
-        // 2) Map ppu.front (u8 indices) -> raw RGB bytes
-        texture.with_lock(None, |buffer: &mut [u8], _pitch: usize| {
-            for (i, &palette_idx) in
-                nnes.ppu.borrow_mut().front.iter().enumerate()
-            {
-                let (r, g, b) = NES_PALETTE[palette_idx as usize];
-                let base = i * 3;
-                buffer[base + 0] = r;
-                buffer[base + 1] = g;
-                buffer[base + 2] = b;
+        // Queue this frame's audio
+        if let Some(queue) = &audio {
+            if queue.size() < max_queued {
+                queue.queue_audio(&nnes.mixer.samples)?;
+            }
+        }
+        nnes.mixer.samples.clear();
+
+        // 2) Map ppu.front (9 bit colors) -> RGB through the video settings,
+        // or draw it with the HD pack's artwork
+        let hd_enabled = nnes.ppu.borrow().sources.enabled;
+        let picture = match hd_pack.as_mut() {
+            Some(pack) if hd_enabled => {
+                let scale = pack.scale();
+                let rgb = pack.render(&nnes, &palettes[palette_idx]);
+                video.draw_hd(rgb, scale, canvas.output_size()?)
+            }
+            _ => {
+                let ppu_ref = nnes.ppu.borrow();
+                video.draw(
+                    &ppu_ref.front,
+                    ppu_ref.front_phase,
+                    &palettes[palette_idx],
+                    canvas.output_size()?,
+                )
             }
-        })?;
+        };
 
-        // 3) Blit and present
+        // 3) Upload
+        let (w, h) = (picture.width as u32, picture.height as u32);
+        let resized = match texture.take() {
+            Some(t) if t.query().width == w && t.query().height == h => t,
+            _ => texture_creator
+                .create_texture_streaming(PixelFormatEnum::RGB24, w, h)
+                .map_err(|e| e.to_string())?,
+        };
+        let output = texture.insert(resized);
+        output
+            .update(None, picture.data, picture.width * 3)
+            .map_err(|e| e.to_string())?;
+
+        // 4) Blit and present
+        let (x, y, w, h) = picture.rect;
         canvas.clear();
-        canvas.copy(&texture, None, None)?;
+        canvas.copy(output, None, Rect::new(x, y, w, h))?;
         canvas.present();
+        debug.draw(&nnes, &palettes[palette_idx])?;
 
-        // 4) Handle input
+        // 5) Handle input, hotkeys show their new setting in the title
+        let mut status = None;
         for event in event_pump.poll_iter() {
-            if let sdl2::event::Event::KeyDown {
-                keycode: Some(Keycode::Escape),
-                ..
-            } = event
-            {
-                break 'running;
+            match event {
+                sdl2::event::Event::Quit { .. }
+                | sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => break 'running,
+                sdl2::event::Event::Window {
+                    window_id,
+                    win_event: WindowEvent::Close,
+                    ..
+                } => {
+                    // with the debug window open, closing the main window
+                    // no longer quits on its own
+                    if Some(window_id) == debug.window_id() {
+                        debug.close();
+                    } else {
+                        break 'running;
+                    }
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    repeat: false,
+                    ..
+                } => {
+                    palette_idx = (palette_idx + 1) % palettes.len();
+                    status = Some(format!(
+                        "palette: {}",
+                        palettes[palette_idx].name
+                    ));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::F),
+                    repeat: false,
+                    ..
+                } => {
+                    video.filter = NtscPreset::next(video.filter);
+                    status = Some(format!("filter: {:?}", video.filter));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::C),
+                    repeat: false,
+                    ..
+                } => {
+                    video.crt_enabled = !video.crt_enabled;
+                    status = Some(format!("crt: {}", video.crt_enabled));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::X),
+                    repeat: false,
+                    ..
+                } => {
+                    video.upscaler = video.upscaler.next();
+                    status = Some(format!("upscaler: {:?}", video.upscaler));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::M),
+                    repeat: false,
+                    ..
+                } => {
+                    video.scale_mode = video.scale_mode.next();
+                    status = Some(format!("scale: {:?}", video.scale_mode));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::B),
+                    repeat: false,
+                    ..
+                } => {
+                    video.blend.mode = video.blend.mode.next();
+                    status = Some(format!("blend: {:?}", video.blend.mode));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::L),
+                    repeat: false,
+                    ..
+                } => {
+                    let mut ppu_ref = nnes.ppu.borrow_mut();
+                    ppu_ref.unlimited_sprites = !ppu_ref.unlimited_sprites;
+                    status = Some(format!(
+                        "sprite limit: {}",
+                        !ppu_ref.unlimited_sprites
+                    ));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::H),
+                    repeat: false,
+                    ..
+                } if hd_pack.is_some() => {
+                    let sources = &mut nnes.ppu.borrow_mut().sources;
+                    sources.enabled = !sources.enabled;
+                    status = Some(format!("hd pack: {}", sources.enabled));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::R),
+                    repeat: false,
+                    ..
+                } => {
+                    nnes.reset();
+                    status = Some("reset".to_string());
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::V),
+                    repeat: false,
+                    ..
+                } => {
+                    debug.cycle(&sdl.video()?)?;
+                    status = Some(format!("debug view: {:?}", debug.view));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    repeat: false,
+                    ..
+                } => {
+                    let overrides = &mut nnes.ppu.borrow_mut().overrides;
+                    overrides.hide_background = !overrides.hide_background;
+                    status = Some(format!(
+                        "hide background: {}",
+                        overrides.hide_background
+                    ));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    repeat: false,
+                    ..
+                } => {
+                    let overrides = &mut nnes.ppu.borrow_mut().overrides;
+                    overrides.hide_sprites = !overrides.hide_sprites;
+                    status = Some(format!(
+                        "hide sprites: {}",
+                        overrides.hide_sprites
+                    ));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    repeat: false,
+                    ..
+                } => {
+                    let overrides = &mut nnes.ppu.borrow_mut().overrides;
+                    overrides.no_clipping = !overrides.no_clipping;
+                    status = Some(format!(
+                        "no clipping: {}",
+                        overrides.no_clipping
+                    ));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    repeat: false,
+                    ..
+                } => {
+                    let overrides = &mut nnes.ppu.borrow_mut().overrides;
+                    overrides.force_palette = match overrides.force_palette {
+                        None => Some(0),
+                        Some(7) => None,
+                        Some(group) => Some(group + 1),
+                    };
+                    status = Some(format!(
+                        "force palette: {:?}",
+                        overrides.force_palette
+                    ));
+                }
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => {
+                    let overrides = &mut nnes.ppu.borrow_mut().overrides;
+                    overrides.highlight_sprite_zero =
+                        !overrides.highlight_sprite_zero;
+                    status = Some(format!(
+                        "highlight sprite 0: {}",
+                        overrides.highlight_sprite_zero
+                    ));
+                }
+                sdl2::event::Event::MouseMotion {
+                    window_id, x, y, ..
+                } => debug.hover(
+                    &nnes,
+                    &palettes[palette_idx],
+                    window_id,
+                    x,
+                    y,
+                )?,
+                sdl2::event::Event::KeyDown {
+                    keycode: Some(Keycode::N),
+                    repeat: false,
+                    ..
+                } => {
+                    debug.pattern_group = (debug.pattern_group + 1) % 8;
+                    status = Some(format!(
+                        "pattern palette: {}",
+                        debug.pattern_group
+                    ));
+                }
+                _ => {}
             }
         }
+        if let Some(status) = status {
+            canvas
+                .window_mut()
+                .set_title(&format!("nnes - {}", status))
+                .map_err(|e| e.to_string())?;
+        }
+
+        frames += 1;
+        if frames.is_multiple_of(frames_per_save) {
+            save_battery(&mut nnes, &sav_path, &mut last_saved);
+        }
 
-        // 5) Clamp to 60fps
+        // 6) Pace frames to the region's frame rate
         let frame_time = frame_start.elapsed();
         if frame_time < target_frame_duration {
             sleep(target_frame_duration - frame_time);
         }
     }
 
+    save_battery(&mut nnes, &sav_path, &mut last_saved);
     Ok(())
 }
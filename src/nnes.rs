@@ -1,23 +1,32 @@
 mod cpu;
+mod mixer;
 mod ppu;
 
 use std::{cell::RefCell, rc::Rc};
 
 use super::Cartridge;
-use cpu::{bus::Bus, CPU};
-use ppu::PPU;
+use crate::region::Region;
+use cpu::{bus::Bus, IrqSource, CPU};
+use mixer::Mixer;
+pub use mixer::SAMPLE_RATE;
+pub use ppu::{DebugImage, RamInit, SourceFrame, TileKey, TileSource};
+use ppu::{EventKind, PPU};
 
 pub struct NNES {
     pub master_clock: u64,
     pub cpu: Rc<RefCell<CPU>>,
     pub ppu: Rc<RefCell<PPU>>,
+    pub cartridge: Rc<RefCell<Cartridge>>,
+    pub region: Region,
+    pub mixer: Mixer,
     // pub apu: Rc<RefCell<APU>>,
 }
 
 impl NNES {
-    pub fn new(cartridge: Cartridge) -> Self {
-        let ppu = Rc::new(RefCell::new(PPU::new(&cartridge)));
-        let bus = Bus::new(ppu.clone(), &cartridge);
+    pub fn new(cartridge: Cartridge, region: Region) -> Self {
+        let cartridge = Rc::new(RefCell::new(cartridge));
+        let ppu = Rc::new(RefCell::new(PPU::new(cartridge.clone(), region)));
+        let bus = Bus::new(ppu.clone(), cartridge.clone());
         let cpu = Rc::new(RefCell::new(CPU::new(bus)));
         // let apu = APU::new();
 
@@ -31,33 +40,80 @@ impl NNES {
             master_clock: 0,
             cpu,
             ppu,
+            cartridge,
+            region,
+            mixer: Mixer::new(region),
             // apu,
         }
     }
 
+    pub fn power_on(&mut self, oam: RamInit, palette: RamInit) {
+        self.ppu.borrow_mut().power_on(oam, palette);
+        self.cpu.borrow_mut().reset();
+    }
+
     pub fn reset(&mut self) {
         self.ppu.borrow_mut().reset();
         self.cpu.borrow_mut().reset();
     }
 
+    pub fn has_battery(&self) -> bool {
+        self.cartridge.borrow().has_battery
+    }
+
+    pub fn battery_ram(&self) -> Option<Vec<u8>> {
+        let cartridge_ref = self.cartridge.borrow();
+        cartridge_ref
+            .has_battery
+            .then(|| cartridge_ref.battery_ram())
+    }
+
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        self.cartridge.borrow_mut().load_battery_ram(data);
+    }
+
+    // CPU memory without side effects, for the frontend
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.cpu.borrow().bus.peek(addr)
+    }
+
     pub fn tick(&mut self) {
-        // CPU runs at master/12
-        if self.master_clock % 12 == 0 {
+        // CPU runs at master/12 (PAL master/16, Dendy master/15)
+        if self.master_clock % self.region.cpu_divider() == 0 {
             // if self.cpu.borrow_mut().ins.is_none() {
             //     self.cpu.borrow_mut().trace();
             // }
             let mut cpu_ref = self.cpu.borrow_mut();
             cpu_ref.tick();
             if cpu_ref.store.oam_dma_data < 0x100 {
-                self.ppu.borrow_mut().oam
+                let mut ppu_ref = self.ppu.borrow_mut();
+                // sprite evaluation may be reading OAM on this line
+                ppu_ref.catch_up();
+                ppu_ref.oam
                     [(cpu_ref.store.oam_dma_index.wrapping_sub(1)) as usize] =
                     cpu_ref.store.oam_dma_data as u8;
                 cpu_ref.store.oam_dma_data = 0x200;
             }
+
+            // Mapper IRQ counters and expansion audio run off the CPU clock
+            let mut cartridge_ref = self.cartridge.borrow_mut();
+            cartridge_ref.cpu_tick();
+            let irq_prev = cpu_ref.irq_pending;
+            cpu_ref.set_irq(IrqSource::MAPPER, cartridge_ref.irq_pending());
+            self.mixer.push(cartridge_ref.audio_output());
+
+            // keep the event log's pc current, and log mapper IRQs
+            let mut ppu_ref = self.ppu.borrow_mut();
+            if ppu_ref.events.enabled {
+                ppu_ref.events.pc = cpu_ref.ins_addr;
+                if cpu_ref.irq_pending && !irq_prev {
+                    ppu_ref.log_event(EventKind::IRQ, 0, 0);
+                }
+            }
         }
 
-        // PPU runs at master/4
-        if self.master_clock % 4 == 0 {
+        // PPU runs at master/4 (PAL and Dendy master/5)
+        if self.master_clock % self.region.ppu_divider() == 0 {
             self.ppu.borrow_mut().tick();
         }
 
@@ -69,3 +125,54 @@ impl NNES {
         self.master_clock = self.master_clock.wrapping_add(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::NNES;
+    use crate::{cartridge::Cartridge, region::Region};
+
+    // Starts a 5B square on channel A at full volume, then spins
+    const PROGRAM: [u8; 31] = [
+        0xA9, 0x00, 0x8D, 0x00, 0xC0, // LDA #$00, STA $C000
+        0xA9, 0x40, 0x8D, 0x00, 0xE0, // LDA #$40, STA $E000: period 0x40
+        0xA9, 0x07, 0x8D, 0x00, 0xC0, // LDA #$07, STA $C000
+        0xA9, 0x3E, 0x8D, 0x00, 0xE0, // LDA #$3E, STA $E000: tone A only
+        0xA9, 0x08, 0x8D, 0x00, 0xC0, // LDA #$08, STA $C000
+        0xA9, 0x0F, 0x8D, 0x00, 0xE0, // LDA #$0F, STA $E000: volume 15
+        0x4C, // JMP to itself, operand below
+    ];
+
+    // Mapper 69 with PROGRAM in the fixed bank at $E000
+    fn fme7_rom() -> Vec<u8> {
+        let mut prg = vec![0; 0x4000];
+        prg[0x2000..0x2000 + PROGRAM.len()].copy_from_slice(&PROGRAM);
+        let jmp = 0xE000 + PROGRAM.len() as u16 - 1;
+        prg[0x2000 + PROGRAM.len()..0x2000 + PROGRAM.len() + 2]
+            .copy_from_slice(&jmp.to_le_bytes());
+        // NMI, reset and IRQ vectors
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0xE0, 0x00, 0xE0, 0x00, 0xE0]);
+
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x50, 0x40];
+        rom.resize(16, 0);
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+        rom
+    }
+
+    #[test]
+    fn expansion_audio_reaches_the_mixer() {
+        let cartridge = Cartridge::new(fme7_rom()).unwrap();
+        let mut nnes = NNES::new(cartridge, Region::NTSC);
+        for _ in 0..nnes.region.master_cycles_per_frame() {
+            nnes.tick();
+        }
+        // a frame's worth of samples, swinging between silence and one
+        // full-volume 5B channel (1.6 2A03 pulses)
+        let samples = &nnes.mixer.samples;
+        assert!((samples.len() as i64 - 734).abs() <= 1);
+        let max = samples.iter().cloned().fold(0.0, f32::max);
+        let min = samples.iter().cloned().fold(1.0, f32::min);
+        assert!((max - 0.149 * 1.6).abs() < 1e-3, "{}", max);
+        assert_eq!(min, 0.0);
+    }
+}
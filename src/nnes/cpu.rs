@@ -18,6 +18,14 @@ bitflags! {
     }
 }
 
+bitflags! {
+    // Devices holding the shared IRQ line low. The APU frame counter and
+    // DMC get their own bits once the APU exists.
+    pub struct IrqSource: u8 {
+        const MAPPER = 0b0000_0001;
+    }
+}
+
 // pc is fetched from 0xFFFC/0xFFFD on startup
 const RESET_VECTOR: u16 = 0xFFFC;
 // on reset, three pushes occur changing this from 0 to 0xfd
@@ -59,12 +67,15 @@ pub struct CPU {
     // FSM metadata
     state: CPUState,
     pub ins: Option<&'static OpCode>,
+    // address of the current instruction, for debugging
+    pub ins_addr: u16,
     curr_ins_ticks: i8,
     required_ins_ticks: u8,
     pub store: CPUStore,
     software_interrupt: bool,
     pub nmi_pending: bool,
-    irq_pending: bool,
+    pub irq_pending: bool,
+    irq_sources: IrqSource,
     servicing_interrupt: bool,
     hijacked: bool,
     page_crossed: bool,
@@ -95,6 +106,7 @@ impl CPU {
             bus,
             state: CPUState::Fetch,
             ins: None,
+            ins_addr: 0,
             curr_ins_ticks: 0,
             required_ins_ticks: 0,
             store: CPUStore {
@@ -111,6 +123,7 @@ impl CPU {
             software_interrupt: false,
             nmi_pending: false,
             irq_pending: false,
+            irq_sources: IrqSource::empty(),
             servicing_interrupt: true,
             hijacked: false,
             page_crossed: false,
@@ -143,6 +156,7 @@ impl CPU {
     }
 
     fn fetch(&mut self) {
+        self.ins_addr = self.pc;
         self.store.data = self.bus.mem_read(self.pc);
         self.pc = self.pc.wrapping_add(1);
 
@@ -244,6 +258,12 @@ impl CPU {
         self.total_ticks += 1;
     }
 
+    // The IRQ line is asserted while any source holds it
+    pub fn set_irq(&mut self, source: IrqSource, active: bool) {
+        self.irq_sources.set(source, active);
+        self.irq_pending = !self.irq_sources.is_empty();
+    }
+
     // FSM Helpers
     fn set_next_state(&mut self, finished_subcycles: bool) {
         // Interrupts being serviced do not poll for other interrupts
@@ -1,6 +1,6 @@
 mod devices;
 
-use super::super::{Cartridge, PPU};
+use super::super::{Cartridge, EventKind, PPU};
 use crate::controller::Joypad;
 use devices::memory_map;
 use std::{cell::RefCell, rc::Rc};
@@ -32,15 +32,21 @@ pub trait BusDevice {
 pub struct Bus {
     pub memory_handlers: Vec<Box<dyn BusDevice>>,
     open_bus: u8,
+    // register writes go to the PPU's event log
+    ppu: Rc<RefCell<PPU>>,
 }
 
 impl Bus {
-    pub fn new(ppu: Rc<RefCell<PPU>>, cartridge: &Cartridge) -> Self {
+    pub fn new(
+        ppu: Rc<RefCell<PPU>>,
+        cartridge: Rc<RefCell<Cartridge>>,
+    ) -> Self {
         let mut memory_handlers: Vec<Box<dyn BusDevice>> = Vec::new();
-        memory_map(ppu, cartridge, &mut memory_handlers);
+        memory_map(ppu.clone(), cartridge, &mut memory_handlers);
         Bus {
             memory_handlers,
             open_bus: 0,
+            ppu,
         }
     }
 
@@ -56,6 +62,14 @@ impl Bus {
     }
 
     pub fn mem_write(&mut self, addr: u16, data: u8) {
+        if let Some(kind) = EventKind::from_write(addr) {
+            let mut ppu_ref = self.ppu.borrow_mut();
+            ppu_ref.log_event(kind, addr, data);
+            // mapper writes can switch CHR banks or mirroring mid-line
+            if kind == EventKind::MAPPER_WRITE {
+                ppu_ref.catch_up();
+            }
+        }
         for handler in &mut self.memory_handlers {
             if handler.contains(addr) {
                 handler.mem_write(addr, data);
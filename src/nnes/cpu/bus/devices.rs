@@ -138,76 +138,27 @@ impl BusDevice for Joypad {
     }
 }
 
-pub struct Expansion_ROM {
-    expansion_rom: [u8; 0x1FE0],
+// Everything the cartridge responds to in [0x4020, 0x10000): mapper
+// registers, PRG RAM and PRG ROM. Unmapped addresses are left to open bus.
+pub struct Cartridge_Slot {
+    cartridge: Rc<RefCell<Cartridge>>,
 }
 
-impl BusDevice for Expansion_ROM {
+impl BusDevice for Cartridge_Slot {
     fn contains(&self, addr: u16) -> bool {
-        (0x4020..0x6000).contains(&addr)
+        addr >= 0x4020 && self.cartridge.borrow().cpu_peek(addr).is_some()
     }
 
     fn mem_read(&mut self, addr: u16) -> u8 {
-        unimplemented!()
+        self.cartridge.borrow_mut().cpu_read(addr).unwrap()
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
-        unimplemented!()
+        self.cartridge.borrow_mut().cpu_write(addr, data);
     }
 
     fn peek(&self, addr: u16) -> u8 {
-        unimplemented!()
-    }
-}
-
-pub struct SRAM {
-    sram: Vec<u8>,
-}
-
-impl BusDevice for SRAM {
-    fn contains(&self, addr: u16) -> bool {
-        (0x6000..0x8000).contains(&addr)
-    }
-
-    fn mem_read(&mut self, addr: u16) -> u8 {
-        unimplemented!()
-    }
-
-    fn mem_write(&mut self, addr: u16, data: u8) {
-        unimplemented!()
-    }
-
-    fn peek(&self, addr: u16) -> u8 {
-        unimplemented!()
-    }
-}
-
-pub struct PRG_ROM {
-    num_banks: u8,
-    prg_rom: Vec<u8>,
-}
-
-impl BusDevice for PRG_ROM {
-    fn contains(&self, addr: u16) -> bool {
-        (0x8000..=0xFFFF).contains(&addr)
-    }
-
-    fn mem_read(&mut self, addr: u16) -> u8 {
-        match self.num_banks {
-            1 => self.prg_rom[(addr as usize - 0x8000) & 0x3FFF],
-            2 => self.prg_rom[addr as usize - 0x8000],
-            _ => unimplemented!(),
-        }
-    }
-
-    fn mem_write(&mut self, addr: u16, data: u8) {}
-
-    fn peek(&self, addr: u16) -> u8 {
-        match self.num_banks {
-            1 => self.prg_rom[(addr as usize - 0x8000) & 0x3FFF],
-            2 => self.prg_rom[addr as usize - 0x8000],
-            _ => unimplemented!(),
-        }
+        self.cartridge.borrow().cpu_peek(addr).unwrap()
     }
 }
 
@@ -215,13 +166,13 @@ impl BusDevice for PRG_ROM {
  * Map various memory objects into the CPU's address space based on the
  * inserted cartridge.
  * @param   ppu                 pointer to a shared PPU object
- * @param   cartridge           reference to inserted cartridge
+ * @param   cartridge           pointer to the shared inserted cartridge
  * @param   memory_handlers     reference to vector of pointers to available
  *                              memory objects
  */
 pub fn memory_map(
     ppu: Rc<RefCell<PPU>>,
-    cartridge: &Cartridge,
+    cartridge: Rc<RefCell<Cartridge>>,
     memory_handlers: &mut Vec<Box<dyn BusDevice>>,
 ) {
     memory_handlers.push(Box::new(RAM {
@@ -236,12 +187,5 @@ pub fn memory_map(
         active: 0,
         state: 0,
     }));
-    if cartridge.has_trainer || cartridge.has_sram {
-        memory_handlers.push(Box::new(SRAM {
-            sram: cartridge.sram.clone(),
-        }));
-    }
-    let prg_rom = cartridge.prg_rom.clone();
-    let num_banks = (prg_rom.len() / 0x4000) as u8;
-    memory_handlers.push(Box::new(PRG_ROM { prg_rom, num_banks }));
+    memory_handlers.push(Box::new(Cartridge_Slot { cartridge }));
 }
@@ -0,0 +1,74 @@
+use crate::region::Region;
+
+// Rate the frontend's audio device plays samples back at
+pub const SAMPLE_RATE: u32 = 44100;
+
+// Collects the console's audio, one level per CPU cycle on the 2A03 mixer's
+// 0-1 scale, and averages it down to SAMPLE_RATE. Only cartridge expansion
+// audio comes in until there is an APU.
+pub struct Mixer {
+    cycles_per_sample: f64,
+    cycles: f64,
+    sum: f32,
+    count: u32,
+    pub samples: Vec<f32>,
+}
+
+impl Mixer {
+    pub fn new(region: Region) -> Self {
+        let cpu_hz = region.master_clock_hz() / region.cpu_divider() as f64;
+        Mixer {
+            cycles_per_sample: cpu_hz / SAMPLE_RATE as f64,
+            cycles: 0.0,
+            sum: 0.0,
+            count: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, level: f32) {
+        self.sum += level;
+        self.count += 1;
+        self.cycles += 1.0;
+        if self.cycles >= self.cycles_per_sample {
+            self.cycles -= self.cycles_per_sample;
+            self.samples.push(self.sum / self.count as f32);
+            self.sum = 0.0;
+            self.count = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mixer, SAMPLE_RATE};
+    use crate::region::Region;
+
+    #[test]
+    fn one_second_of_cpu_cycles_is_one_second_of_samples() {
+        for region in [Region::NTSC, Region::PAL, Region::DENDY] {
+            let mut mixer = Mixer::new(region);
+            let cpu_hz =
+                region.master_clock_hz() / region.cpu_divider() as f64;
+            for _ in 0..cpu_hz.round() as u32 {
+                mixer.push(0.25);
+            }
+            let count = mixer.samples.len() as i64;
+            assert!((count - SAMPLE_RATE as i64).abs() <= 1, "{:?}", region);
+            assert!(mixer.samples.iter().all(|&s| s == 0.25));
+        }
+    }
+
+    #[test]
+    fn samples_average_the_cycles_they_cover() {
+        let mut mixer = Mixer::new(Region::NTSC);
+        // a square wave far above the sample rate averages out to its middle
+        while mixer.samples.len() < 10 {
+            let level = if mixer.count % 2 == 0 { 1.0 } else { 0.0 };
+            mixer.push(level);
+        }
+        for &sample in &mixer.samples {
+            assert!((sample - 0.5).abs() < 0.05, "{}", sample);
+        }
+    }
+}
@@ -1,21 +1,39 @@
 mod core;
+mod debug;
+mod events;
+mod fast;
 mod io;
+mod power;
+mod sources;
 
-use crate::cartridge::{Cartridge, Mirroring};
+use crate::cartridge::Cartridge;
+use crate::region::Region;
+use std::{cell::RefCell, rc::Rc};
+
+pub use debug::DebugImage;
+pub use events::{EventKind, EventLog};
+pub use power::RamInit;
+use sources::SourceLog;
+pub use sources::{SourceFrame, TileKey, TileSource};
 
 const PATTERN_TABLE_START: u16 = 0x0000;
-const PATTERN_TABLE_END: u16 = 0x1FFF;
 const NAMETABLE_START: u16 = 0x2000;
 const NAMETABLE_END: u16 = 0x3EFF;
 const PALETTE_START: u16 = 0x3F00;
 const PALETTE_END: u16 = 0x3FFF;
 
-const PRE_RENDER_LINE: u16 = 261;
 const VISIBLE_LINES: std::ops::RangeInclusive<u16> = 0..=239;
 
 const PRE_FETCH_CYCLES: std::ops::RangeInclusive<u16> = 321..=336;
 const VISIBLE_CYCLES: std::ops::RangeInclusive<u16> = 1..=256;
 
+// OAM holds 64 sprites, 8 of them fit in the slots
+const MAX_EXTRA_SPRITES: usize = 56;
+
+// dots between the NMI output rising and the CPU seeing it, a PPUSTATUS
+// read in between suppresses the NMI
+const NMI_DELAY: u8 = 2;
+
 bitflags! {
     pub struct PPUCTRL: u8 {
         const HORZ_NAMETABLE = 0b0000_0001;
@@ -77,6 +95,8 @@ pub struct PPUStore {
     tile_number: u8,
     attributes: u8,
     x_coordinate: u8,
+    sprite_zero_next: bool,
+    sprite_addr: u16,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -87,6 +107,20 @@ pub struct Sprite {
     x_coordinate: u8,
 }
 
+// Debug switches for what gets drawn. Games still see the real PPU: sprite
+// 0 hit and register reads ignore these.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RenderOverrides {
+    pub hide_background: bool,
+    pub hide_sprites: bool,
+    // draw the left 8 pixels even when PPUMASK clips them
+    pub no_clipping: bool,
+    // draw every tile and sprite with palette 0-7 (4-7 are the sprite
+    // palettes)
+    pub force_palette: Option<u8>,
+    pub highlight_sprite_zero: bool,
+}
+
 pub struct PPU {
     // Architectural state
     v: u16, // 15 bits: yyy NN YYYYY XXXXX (fine y, nametable select, coarse y, coarse x)
@@ -94,7 +128,9 @@ pub struct PPU {
     x: u8,  // 3 bits
     w: u8,  // 1 bit
     f: u8,  // 1 bit
-    chr_rom: Vec<u8>,
+    cartridge: Rc<RefCell<Cartridge>>,
+    // frame length and vblank timing
+    region: Region,
     vram: [u8; 0x800],
     palette: [u8; 0x20],
     // Sprites are 4 bytes each:
@@ -114,12 +150,47 @@ pub struct PPU {
     // Fetched sprites
     sprites: [Sprite; 8],
 
-    // Open bus
+    // Sprite shift registers and x counters, one per fetched sprite
+    sprite_pattern_lo: [u8; 8],
+    sprite_pattern_hi: [u8; 8],
+    sprite_x_counter: [u8; 8],
+    // whether sprites[0] is OAM sprite 0 on the current scanline
+    sprite_zero_line: bool,
+
+    // Enhancement: draw the sprites past the 8 per scanline limit too
+    pub unlimited_sprites: bool,
+    pub overrides: RenderOverrides,
+    // scroll at the first dot of the frame, in the 512x480 nametable space
+    start_scroll: (u16, u16),
+    // HD packs: the tile behind every pixel
+    pub sources: SourceLog,
+
+    // Speed mode: render visible lines a whole line at a time when nothing
+    // changes mid-line
+    pub fast_lines: bool,
+    // the current line is left for its last dot
+    fast_line: bool,
+    // the mapper needs every fetch at its dot, so lines never go fast
+    watches_fetches: bool,
+    // the sprites past the limit, allocated once and refilled every line
+    extra_sprites: Vec<Sprite>,
+    extra_pattern_lo: Vec<u8>,
+    extra_pattern_hi: Vec<u8>,
+    extra_x_counter: Vec<u8>,
+
+    // Open bus, with the dot each bit was last driven: undriven bits decay
+    // to 0 after ~600 ms
     open_bus: u8,
+    open_bus_refresh: [u64; 8],
 
-    // Image buffers
-    pub front: [u8; 256 * 240],
-    pub back: [u8; 256 * 240],
+    // Image buffers, 9 bit colors: PPUMASK[7:5] emphasis, then the 6 bit
+    // palette index
+    pub front: [u16; 256 * 240],
+    pub back: [u16; 256 * 240],
+    // Dot each buffer's frame started on, mod 3. Together with the color
+    // cycle (8 of 12 half-clocks per dot) it sets the NTSC artifact phase.
+    pub front_phase: u8,
+    back_phase: u8,
 
     // I/O operations
     ppu_ctrl: PPUCTRL,
@@ -130,27 +201,34 @@ pub struct PPU {
     pub on_nmi: Box<dyn FnMut()>,
 
     // PPU metadata
-    mirroring: Mirroring,
     pub cycle: u16,
     pub scanline: u16,
     store: PPUStore,
     nmi_prev: bool,
+    nmi_delay: u8,
+    // PPUSTATUS was read the dot before vblank starts
+    suppress_vblank: bool,
+    // register writes are ignored until the first pre-render line
+    warming_up: bool,
 
     // Debugging tools
+    pub events: EventLog,
     total_cycles: u64,
     total_scanlines: u64,
     pub total_frames: u64,
 }
 
 impl PPU {
-    pub fn new(cartridge: &Cartridge) -> Self {
+    pub fn new(cartridge: Rc<RefCell<Cartridge>>, region: Region) -> Self {
+        let watches_fetches = cartridge.borrow().watches_ppu_fetches();
         PPU {
             v: 0,
             t: 0,
             x: 0,
             w: 0,
             f: 0,
-            chr_rom: cartridge.chr_rom.clone(),
+            cartridge,
+            region,
             vram: [0; 0x800],
             palette: [0; 0x20],
             oam: [0; 64 * 4],
@@ -159,23 +237,39 @@ impl PPU {
             pattern_hi: 0,
             attribute_lo: 0,
             attribute_hi: 0,
-            sprites: [
-                Sprite { 
-                    y_coordinate: 0, 
-                    tile_number: 0, 
-                    attributes: 0, 
-                    x_coordinate: 0 
-                }; 8],
+            sprites: [Sprite {
+                y_coordinate: 0,
+                tile_number: 0,
+                attributes: 0,
+                x_coordinate: 0,
+            }; 8],
+            sprite_pattern_lo: [0; 8],
+            sprite_pattern_hi: [0; 8],
+            sprite_x_counter: [0; 8],
+            unlimited_sprites: false,
+            overrides: RenderOverrides::default(),
+            start_scroll: (0, 0),
+            sources: SourceLog::new(),
+            fast_lines: false,
+            fast_line: false,
+            watches_fetches,
+            extra_sprites: Vec::with_capacity(MAX_EXTRA_SPRITES),
+            extra_pattern_lo: Vec::with_capacity(MAX_EXTRA_SPRITES),
+            extra_pattern_hi: Vec::with_capacity(MAX_EXTRA_SPRITES),
+            extra_x_counter: Vec::with_capacity(MAX_EXTRA_SPRITES),
+            sprite_zero_line: false,
             open_bus: 0,
+            open_bus_refresh: [0; 8],
             front: [0; 256 * 240],
             back: [0; 256 * 240],
+            front_phase: 0,
+            back_phase: 0,
             ppu_ctrl: PPUCTRL::empty(),
             ppu_mask: PPUMASK::empty(),
             ppu_status: PPUSTATUS::empty(),
             oam_addr: 0,
             read_buffer: 0,
             on_nmi: Box::new(|| {}),
-            mirroring: cartridge.mirroring,
             cycle: 0,
             scanline: 0,
             store: PPUStore {
@@ -196,29 +290,37 @@ impl PPU {
                 tile_number: 0,
                 attributes: 0,
                 x_coordinate: 0,
+                sprite_zero_next: false,
+                sprite_addr: 0,
             },
             nmi_prev: false,
+            nmi_delay: 0,
+            suppress_vblank: false,
+            warming_up: false,
+            events: EventLog::new(),
             total_cycles: 0,
             total_scanlines: 0,
             total_frames: 0,
         }
     }
 
-    pub fn reset(&mut self) {
-        self.cycle = 0;
-        self.scanline = 261;
+    // last line of the frame: 261 on NTSC, 311 on PAL and Dendy
+    fn pre_render_line(&self) -> u16 {
+        self.region.scanlines() - 1
+    }
+
+    // either layer on in PPUMASK, which is what gates fetches, v updates
+    // and sprite evaluation
+    fn rendering_enabled(&self) -> bool {
+        self.ppu_mask
+            .intersects(PPUMASK::SHOW_BACKGROUND | PPUMASK::SHOW_SPRITES)
     }
 
     fn mem_read(&self, mut addr: u16) -> u8 {
         addr &= 0x3FFF;
         match addr {
-            PATTERN_TABLE_START..=PATTERN_TABLE_END => {
-                self.chr_rom[addr as usize]
-            }
-            NAMETABLE_START..=NAMETABLE_END => {
-                addr = self.get_vram_addr(addr);
-                assert!(addr < 0x800);
-                self.vram[addr as usize]
+            PATTERN_TABLE_START..=NAMETABLE_END => {
+                self.cartridge.borrow().ppu_read(addr, &self.vram)
             }
             PALETTE_START..=PALETTE_END => {
                 addr = self.get_palette_addr(addr);
@@ -232,14 +334,10 @@ impl PPU {
     fn mem_write(&mut self, mut addr: u16, data: u8) {
         addr &= 0x3FFF;
         match addr {
-            PATTERN_TABLE_START..=PATTERN_TABLE_END => {
-                self.chr_rom[addr as usize] = data
-            }
-            NAMETABLE_START..=NAMETABLE_END => {
-                addr = self.get_vram_addr(addr);
-                assert!(addr < 0x800);
-                self.vram[addr as usize] = data;
-            }
+            PATTERN_TABLE_START..=NAMETABLE_END => self
+                .cartridge
+                .borrow_mut()
+                .ppu_write(addr, data, &mut self.vram),
             PALETTE_START..=PALETTE_END => {
                 addr = self.get_palette_addr(addr);
                 assert!(addr < 0x20);
@@ -257,17 +355,33 @@ impl PPU {
         //———————————————————————————————————————————————————————————————————
         //  Pre-render -> {Render AND Evaluate} -> VBlank -> NMI
         //———————————————————————————————————————————————————————————————————
-        if self.scanline == PRE_RENDER_LINE {
+        if self.scanline == self.pre_render_line() {
             self.handle_pre_render_line();
         }
 
+        // mid-frame scroll writes move v, not where the frame started
+        if self.scanline == 0 && self.cycle == 0 {
+            self.start_scroll = self.scroll_from_v();
+        }
+
         if VISIBLE_LINES.contains(&self.scanline) {
-            // current scanline rendering
-            self.handle_render_lines();
-            // next scanline evaluation
-            self.handle_evaluation_lines();
+            if self.cycle == 0 {
+                self.fast_line = self.can_render_fast();
+            }
+            if !self.fast_line {
+                // current scanline rendering
+                self.handle_render_lines();
+                // next scanline evaluation
+                self.handle_evaluation_lines();
+            } else if self.cycle == 340 {
+                self.render_line_fast();
+                self.fast_line = false;
+            }
         }
 
+        // OAMADDR is held at 0 while sprites are fetched
+        self.handle_oam_addr_reset();
+
         // vblank entrance/exit and NMI detection
         self.handle_vblank_lines();
 
@@ -281,20 +395,6 @@ impl PPU {
     }
 
     // Helpers
-    fn get_vram_addr(&self, mut addr: u16) -> u16 {
-        addr &= 0xFFF;
-        let table = addr / 0x400;
-        let offset = addr & 0x3FF;
-
-        let mirrored = match self.mirroring {
-            Mirroring::VERTICAL => table & 1, // 0,2 -> 0 (NT1), 1,3 -> 1 (NT2)
-            Mirroring::HORIZONTAL => table >> 1, // 0,1 -> 0 (NT1), 2,3 -> 1 (NT2)
-            Mirroring::ALTERNATIVE => unimplemented!(),
-        };
-
-        (mirrored << 10) + offset
-    }
-
     fn get_palette_addr(&self, mut addr: u16) -> u16 {
         addr &= 0x1F;
         // Mirror $3F10/$3F14/$3F18/$3F1C to $3F00/$3F04/$3F08/$3F0C
@@ -305,26 +405,35 @@ impl PPU {
     }
 
     fn handle_pre_render_line(&mut self) {
-        if self
-            .ppu_mask
-            .intersects(PPUMASK::SHOW_BACKGROUND | PPUMASK::SHOW_SPRITES)
-        {
+        if self.rendering_enabled() {
             if (280..=304).contains(&self.cycle) {
                 self.copy_y();
             }
             self.handle_fetch_cycles();
+
+            // nothing was evaluated for line 0, so all 8 fetches are empty
+            if self.cycle == 257 {
+                self.store.accepted_sprite = 0;
+                self.sprite_zero_line = false;
+                self.extra_sprites.clear();
+                self.extra_pattern_lo.clear();
+                self.extra_pattern_hi.clear();
+                self.extra_x_counter.clear();
+            }
+            if (257..=320).contains(&self.cycle) {
+                self.handle_sprite_fetches();
+            }
         }
     }
 
     fn handle_render_lines(&mut self) {
-        if self
-            .ppu_mask
-            .intersects(PPUMASK::SHOW_BACKGROUND | PPUMASK::SHOW_SPRITES)
-        {
+        if self.rendering_enabled() {
             if VISIBLE_CYCLES.contains(&self.cycle) {
                 self.draw_pixel();
             }
             self.handle_fetch_cycles();
+        } else if VISIBLE_CYCLES.contains(&self.cycle) {
+            self.draw_backdrop();
         }
     }
 
@@ -357,9 +466,28 @@ impl PPU {
         }
     }
 
+    fn handle_sprite_fetches(&mut self) {
+        // 8 cycles per sprite: garbage nametable and attribute fetches,
+        // then the pattern lo and hi bytes
+        let slot = ((self.cycle - 257) / 8) as usize;
+        match self.cycle % 8 {
+            5 => self.fetch_sprite_lo(slot),
+            7 => self.fetch_sprite_hi(slot),
+            _ => {}
+        }
+    }
+
     fn handle_evaluation_lines(&mut self) {
-        self.store.sprite_height = 
-            if self.ppu_ctrl.contains(PPUCTRL::SPRITE_SIZE) { 16 } else { 8 };
+        // sprite evaluation only runs while rendering
+        if !self.rendering_enabled() {
+            return;
+        }
+        self.store.sprite_height =
+            if self.ppu_ctrl.contains(PPUCTRL::SPRITE_SIZE) {
+                16
+            } else {
+                8
+            };
         match self.cycle {
             0 => {}
             1..=64 => {
@@ -375,6 +503,15 @@ impl PPU {
                 }
             }
             65..=256 => {
+                if self.cycle == 65 {
+                    // start over from OAM sprite 0 for this scanline
+                    self.store.sprite_eval_state =
+                        SpriteEvalState::YCoordinate;
+                    self.store.curr_sprite = 0;
+                    self.store.curr_sprite_byte = 0;
+                    self.store.accepted_sprite = 0;
+                    self.store.sprite_zero_next = false;
+                }
                 // note: oam[n][m] = oam[4*n + m]
                 // 1. starting at n = 0,
                 //    read oam[n][0] (y coordinate) and copy to secondary oam if not full
@@ -413,8 +550,9 @@ impl PPU {
                         }
                     }
                 } else {
-                    // if secondary oam is not full, write to it. if it is full, read from it.
-                    if self.store.accepted_sprite < 8 {
+                    // if secondary oam is not full, write to it. if it is full, or all 64
+                    // sprites were evaluated, read from it.
+                    if self.store.accepted_sprite < 8 && self.store.sprite_eval_state != SpriteEvalState::Done {
                         self.secondary_oam[secondary_oam_idx as usize] = match self.store.sprite_eval_state {
                             SpriteEvalState::YCoordinate => {
                                 self.store.y_coordinate
@@ -437,8 +575,12 @@ impl PPU {
                     if self.store.sprite_eval_state != SpriteEvalState::Done {
                         // if y coordinate is in range
                         if (self.scanline >= self.store.y_coordinate as u16)
-                            && (self.scanline < (self.store.y_coordinate + self.store.sprite_height) as u16)
+                            && (self.scanline < self.store.y_coordinate as u16 + self.store.sprite_height as u16)
                         {
+                            // sprite 0 is in range, and always lands in the first slot
+                            if self.store.curr_sprite == 0 && self.store.curr_sprite_byte == 0 {
+                                self.store.sprite_zero_next = true;
+                            }
                             self.store.curr_sprite_byte = match self.store.curr_sprite_byte {
                                 0 => {
                                     self.store.sprite_eval_state = SpriteEvalState::TileNumber;
@@ -461,7 +603,7 @@ impl PPU {
 
                             // if m 3 -> 0, n += 1
                             if self.store.curr_sprite_byte == 0 {
-                                self.store.curr_sprite = self.store.curr_sprite.wrapping_add(1);
+                                self.store.curr_sprite = (self.store.curr_sprite + 1) % 64;
                                 // if done reading from oam
                                 if self.store.curr_sprite == 0 {
                                     self.store.sprite_eval_state = SpriteEvalState::Done;
@@ -478,7 +620,7 @@ impl PPU {
                             }
                         } else {
                             // check next sprite
-                            self.store.curr_sprite = self.store.curr_sprite.wrapping_add(1);
+                            self.store.curr_sprite = (self.store.curr_sprite + 1) % 64;
                             // if done reading from oam
                             if self.store.curr_sprite == 0 {
                                 self.store.sprite_eval_state = SpriteEvalState::Done;
@@ -498,6 +640,13 @@ impl PPU {
                 }
             }
             257..=320 => {
+                if self.cycle == 257 {
+                    self.store.read_sprite = 0;
+                    self.store.read_sprite_byte = 0;
+                    self.store.found_empty = false;
+                    self.sprite_zero_line = self.store.sprite_zero_next;
+                    self.fetch_extra_sprites();
+                }
                 // cycles 1-4: read the y coordinate, tile number, attributes, and x coordinate
                 //   from secondary OAM
                 // cycles 5-8: dummy reads of the x coordinate from secondary OAM 4 times
@@ -540,6 +689,7 @@ impl PPU {
                         _ => unreachable!()
                     }
                 }
+                self.handle_sprite_fetches();
             }
             320..=340 => {
                 // TODO: initialize background render pipeline
@@ -552,17 +702,23 @@ impl PPU {
     }
 
     fn handle_vblank_lines(&mut self) {
-        if self.scanline == 241 && self.cycle == 1 {
-            // enter VBlank
-            self.ppu_status.insert(PPUSTATUS::IS_VBLANK);
+        if self.scanline == self.region.vblank_line() && self.cycle == 1 {
+            // enter VBlank, unless a PPUSTATUS read just raced it
+            if !self.suppress_vblank {
+                self.ppu_status.insert(PPUSTATUS::IS_VBLANK);
+            }
+            self.suppress_vblank = false;
             // present completed frame
             std::mem::swap(&mut self.front, &mut self.back);
+            self.front_phase = self.back_phase;
+            self.sources.end_frame();
             // self.back.fill(0); // MAYBE BUG: reset buffer or not?
         }
 
-        if self.scanline == PRE_RENDER_LINE && self.cycle == 1 {
-            // exit VBlank
+        if self.scanline == self.pre_render_line() && self.cycle == 1 {
+            // exit VBlank, and the power-on/reset warm-up with it
             self.ppu_status.remove(PPUSTATUS::IS_VBLANK);
+            self.warming_up = false;
             self.nmi_prev = false;
             self.ppu_status.remove(PPUSTATUS::SPRITE0_HIT);
             self.ppu_status.remove(PPUSTATUS::SPRITE_OVERFLOW);
@@ -572,8 +728,17 @@ impl PPU {
     fn handle_nmi_polling(&mut self) {
         let nmi_now = self.ppu_ctrl.contains(PPUCTRL::NMI_ON_VBLANK)
             && self.ppu_status.contains(PPUSTATUS::IS_VBLANK);
-        if nmi_now && !self.nmi_prev {
-            (self.on_nmi.as_mut())();
+        if !nmi_now {
+            // the output dropped before the CPU saw it
+            self.nmi_delay = 0;
+        } else if !self.nmi_prev {
+            self.nmi_delay = NMI_DELAY;
+        } else if self.nmi_delay > 0 {
+            self.nmi_delay -= 1;
+            if self.nmi_delay == 0 {
+                (self.on_nmi.as_mut())();
+                self.log_event(EventKind::NMI, 0, 0);
+            }
         }
         self.nmi_prev = nmi_now;
     }
@@ -582,20 +747,709 @@ impl PPU {
         self.cycle += 1;
         self.total_cycles += 1;
 
-        // skip the last cycle of the pre-render line on odd frames
-        if (self.f == 1 && self.scanline == 261 && self.cycle == 340)
-            || self.cycle == 341
-        {
+        // skip the last cycle of the pre-render line on odd NTSC frames,
+        // only while rendering
+        let skip = self.region.skips_odd_frame_dot()
+            && self.rendering_enabled()
+            && self.f == 1
+            && self.scanline == self.pre_render_line()
+            && self.cycle == 340;
+        if skip || self.cycle == 341 {
             self.cycle = 0;
             self.scanline += 1;
             self.total_scanlines += 1;
         }
 
         // finalize frame
-        if self.scanline == 262 {
+        if self.scanline == self.region.scanlines() {
             self.scanline = 0;
             self.f ^= 1;
             self.total_frames += 1;
+            self.events.end_frame();
+            // skipped dots on odd frames shift this by one
+            self.back_phase = (self.total_cycles % 3) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RamInit, Sprite, TileKey, PPU, PPUCTRL, PPUSTATUS};
+    use crate::cartridge::Cartridge;
+    use crate::region::Region;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    // NTSC PPU on an NROM cartridge with the given CHR ROM, zero filled to
+    // 8 kB. The nametables are horizontally mirrored.
+    fn ppu_with_chr(chr: &[u8]) -> PPU {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1];
+        rom.resize(16 + 0x4000, 0);
+        rom.extend_from_slice(chr);
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        let cartridge = Cartridge::new(rom).unwrap();
+        PPU::new(Rc::new(RefCell::new(cartridge)), Region::NTSC)
+    }
+
+    // NTSC PPU on a blank NROM cartridge, with NMIs counted
+    fn ppu() -> (PPU, Rc<Cell<u32>>) {
+        let mut ppu = ppu_with_chr(&[]);
+        let nmis = Rc::new(Cell::new(0));
+        let count = nmis.clone();
+        ppu.on_nmi = Box::new(move || count.set(count.get() + 1));
+        (ppu, nmis)
+    }
+
+    // ticks until (scanline, cycle) is the next dot to run
+    fn run_to(ppu: &mut PPU, scanline: u16, cycle: u16) {
+        while (ppu.scanline, ppu.cycle) != (scanline, cycle) {
+            ppu.tick();
+        }
+    }
+
+    #[test]
+    fn nmi_fires_two_dots_after_vblank() {
+        let (mut ppu, nmis) = ppu();
+        ppu.reg_write(0, 0x80);
+        run_to(&mut ppu, 241, 1);
+        ppu.tick();
+        assert!(ppu.ppu_status.contains(PPUSTATUS::IS_VBLANK));
+        ppu.tick();
+        assert_eq!(nmis.get(), 0);
+        ppu.tick();
+        assert_eq!(nmis.get(), 1);
+
+        // enabling NMIs during vblank fires one the same 2 dots later
+        run_to(&mut ppu, 250, 0);
+        ppu.reg_write(0, 0x00);
+        ppu.tick();
+        ppu.reg_write(0, 0x80);
+        ppu.tick();
+        ppu.tick();
+        assert_eq!(nmis.get(), 1);
+        ppu.tick();
+        assert_eq!(nmis.get(), 2);
+    }
+
+    #[test]
+    fn ppustatus_read_races_vblank() {
+        // the dot the read lands before, whether it sees the flag, and
+        // whether the NMI still fires. A read just before the flag is set
+        // keeps it clear all frame; one on the same dot or the next sees
+        // it and cancels the NMI.
+        for (cycle, flag, nmi) in [
+            (0, false, true),
+            (1, false, false),
+            (2, true, false),
+            (3, true, false),
+            (4, true, true),
+        ] {
+            let (mut ppu, nmis) = ppu();
+            ppu.reg_write(0, 0x80);
+            run_to(&mut ppu, 241, cycle);
+            let status = ppu.reg_read(2);
+            assert_eq!(status & 0x80 != 0, flag, "read at dot {}", cycle);
+            run_to(&mut ppu, 242, 0);
+            assert_eq!(nmis.get() == 1, nmi, "read at dot {}", cycle);
+            assert_eq!(
+                ppu.ppu_status.contains(PPUSTATUS::IS_VBLANK),
+                cycle == 0,
+                "read at dot {}",
+                cycle
+            );
+        }
+    }
+
+    // Solid tiles in both pattern tables: tile n is filled with color n
+    // for n in 1..=3, tile 0xFF with color 3
+    fn solid_chr() -> Vec<u8> {
+        let mut chr = vec![0; 0x2000];
+        for table in [0, 0x1000] {
+            for tile in [1, 2, 3, 0xFF] {
+                let color = usize::min(tile, 3);
+                let addr = table + tile * 16;
+                chr[addr..addr + 8].fill(if color & 1 != 0 { 0xFF } else { 0 });
+                chr[addr + 8..addr + 16]
+                    .fill(if color & 2 != 0 { 0xFF } else { 0 });
+            }
+        }
+        chr
+    }
+
+    // Both layers on, no clipping, sprites hidden, and colors that tell the
+    // layers apart: backdrop 0x0F, background color 1 0x11, sprite palette
+    // 0 color 2 0x22 and sprite palette 1 color 3 0x27
+    fn scene() -> PPU {
+        let mut ppu = ppu_with_chr(&solid_chr());
+        ppu.reg_write(1, 0x1E);
+        ppu.oam.fill(0xFF);
+        ppu.palette[0x00] = 0x0F;
+        ppu.palette[0x01] = 0x11;
+        ppu.palette[0x12] = 0x22;
+        ppu.palette[0x17] = 0x27;
+        ppu
+    }
+
+    fn set_sprite(ppu: &mut PPU, n: usize, y: u8, tile: u8, attr: u8, x: u8) {
+        ppu.oam[4 * n..4 * n + 4].copy_from_slice(&[y, tile, attr, x]);
+    }
+
+    // A whole frame from the pre-render line, left in ppu.back
+    fn render_frame(ppu: &mut PPU) {
+        run_to(ppu, 261, 0);
+        run_to(ppu, 240, 0);
+    }
+
+    fn pixel(ppu: &PPU, x: usize, y: usize) -> u16 {
+        ppu.back[y * 256 + x]
+    }
+
+    #[test]
+    fn first_opaque_sprite_wins_even_behind_the_background() {
+        let mut ppu = scene();
+        // background tile at x 80..88, lines 40..48
+        ppu.vram[5 * 32 + 10] = 1;
+        // sprite 0 behind the background over sprite 1 in front, both at
+        // x 84..92, lines 40..48
+        set_sprite(&mut ppu, 0, 39, 2, 0x20, 84);
+        set_sprite(&mut ppu, 1, 39, 3, 0x01, 84);
+        render_frame(&mut ppu);
+
+        // sprite 0 hides sprite 1 and is hidden by the background itself
+        assert_eq!(pixel(&ppu, 85, 44), 0x11);
+        // where the background is transparent sprite 0 shows, not sprite 1
+        assert_eq!(pixel(&ppu, 90, 44), 0x22);
+        // and sprite 1 on its own is in front
+        set_sprite(&mut ppu, 0, 0xFF, 0, 0, 0);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 85, 44), 0x27);
+    }
+
+    #[test]
+    fn sprites_past_the_limit() {
+        let mut ppu = scene();
+        ppu.unlimited_sprites = true;
+        // 10 sprites on lines 40..48: 8 at x 0, 16, .. 112, the 9th over
+        // the right half of the 8th and the 10th on its own
+        for n in 0..8 {
+            set_sprite(&mut ppu, n, 39, 2, 0x00, 16 * n as u8);
+        }
+        set_sprite(&mut ppu, 8, 39, 3, 0x01, 116);
+        set_sprite(&mut ppu, 9, 39, 3, 0x01, 200);
+        render_frame(&mut ppu);
+
+        for n in 0..8 {
+            assert_eq!(pixel(&ppu, 16 * n + 2, 44), 0x22);
+        }
+        // the first 8 keep their priority
+        assert_eq!(pixel(&ppu, 118, 44), 0x22);
+        assert_eq!(pixel(&ppu, 122, 44), 0x27);
+        assert_eq!(pixel(&ppu, 204, 44), 0x27);
+        // and the hardware still overflows
+        assert!(ppu.ppu_status.contains(PPUSTATUS::SPRITE_OVERFLOW));
+
+        // with the limit on the 9th and 10th are gone
+        ppu.unlimited_sprites = false;
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 122, 44), 0x0F);
+        assert_eq!(pixel(&ppu, 204, 44), 0x0F);
+    }
+
+    #[test]
+    fn sprite_zero_hit() {
+        let mut ppu = scene();
+        ppu.vram[5 * 32 + 10] = 1;
+        set_sprite(&mut ppu, 0, 39, 2, 0x00, 84);
+        // sprite 1 over the background does not count
+        set_sprite(&mut ppu, 1, 99, 2, 0x00, 84);
+        ppu.vram[12 * 32 + 10] = 1;
+        render_frame(&mut ppu);
+        assert!(ppu.ppu_status.contains(PPUSTATUS::SPRITE0_HIT));
+
+        // only sprite 1 over the background
+        set_sprite(&mut ppu, 0, 0xFF, 0, 0, 0);
+        render_frame(&mut ppu);
+        assert!(!ppu.ppu_status.contains(PPUSTATUS::SPRITE0_HIT));
+
+        // a transparent sprite 0 pixel over the background does not count
+        set_sprite(&mut ppu, 0, 39, 0, 0x00, 84);
+        render_frame(&mut ppu);
+        assert!(!ppu.ppu_status.contains(PPUSTATUS::SPRITE0_HIT));
+    }
+
+    #[test]
+    fn no_sprite_zero_hit_at_x_255() {
+        let mut ppu = scene();
+        // background at x 248..256
+        ppu.vram[5 * 32 + 31] = 1;
+        // only x = 255 overlaps
+        set_sprite(&mut ppu, 0, 39, 2, 0x00, 255);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 255, 44), 0x22);
+        assert!(!ppu.ppu_status.contains(PPUSTATUS::SPRITE0_HIT));
+
+        // x = 254 is the last dot that hits
+        set_sprite(&mut ppu, 0, 39, 2, 0x00, 254);
+        render_frame(&mut ppu);
+        assert!(ppu.ppu_status.contains(PPUSTATUS::SPRITE0_HIT));
+    }
+
+    #[test]
+    fn no_sprite_zero_hit_in_the_clipped_left_column() {
+        // background at x 0..8, sprite 0 over all of it
+        for (mask, hit) in [
+            (0x1E, true),
+            // background clipped
+            (0x1C, false),
+            // sprites clipped
+            (0x1A, false),
+        ] {
+            let mut ppu = scene();
+            ppu.reg_write(1, mask);
+            ppu.vram[5 * 32] = 1;
+            set_sprite(&mut ppu, 0, 39, 2, 0x00, 0);
+            render_frame(&mut ppu);
+            assert_eq!(
+                ppu.ppu_status.contains(PPUSTATUS::SPRITE0_HIT),
+                hit,
+                "mask {:02X}",
+                mask
+            );
         }
     }
+
+    #[test]
+    fn sprite_row_addresses() {
+        let (mut ppu, _) = ppu();
+        let sprite = |attributes| Sprite {
+            y_coordinate: 10,
+            tile_number: 0,
+            attributes,
+            x_coordinate: 0,
+        };
+        let row_addr = |ppu: &mut PPU, row: u16, attributes: u8, tile: u8| {
+            ppu.scanline = 10 + row;
+            ppu.sprite_row_addr(sprite(attributes), tile)
+        };
+
+        // 8x8: PPUCTRL picks the table, the tile number is used as is
+        assert_eq!(row_addr(&mut ppu, 3, 0x00, 0x05), 0x0053);
+        assert_eq!(row_addr(&mut ppu, 3, 0x80, 0x05), 0x0054);
+        ppu.ppu_ctrl.insert(PPUCTRL::SPRITE_PATTERN_TABLE);
+        assert_eq!(row_addr(&mut ppu, 3, 0x00, 0x05), 0x1053);
+
+        // 8x16: tile[0] picks the table and PPUCTRL is ignored, rows 8-15
+        // come from the next tile
+        ppu.ppu_ctrl.insert(PPUCTRL::SPRITE_SIZE);
+        assert_eq!(row_addr(&mut ppu, 0, 0x00, 0x05), 0x1040);
+        assert_eq!(row_addr(&mut ppu, 9, 0x00, 0x05), 0x1051);
+        ppu.ppu_ctrl.remove(PPUCTRL::SPRITE_PATTERN_TABLE);
+        assert_eq!(row_addr(&mut ppu, 0, 0x00, 0x04), 0x0040);
+        assert_eq!(row_addr(&mut ppu, 15, 0x00, 0x04), 0x0057);
+        // the vertical flip swaps the two tiles too
+        assert_eq!(row_addr(&mut ppu, 0, 0x80, 0x04), 0x0057);
+        assert_eq!(row_addr(&mut ppu, 9, 0x80, 0x04), 0x0046);
+    }
+
+    #[test]
+    fn tall_sprites_draw_both_tiles() {
+        let mut ppu = scene();
+        ppu.palette[0x13] = 0x23;
+        ppu.reg_write(0, PPUCTRL::SPRITE_SIZE.bits());
+        // tile 3 is odd: table 0x1000, color 2 tile on top of the color 3
+        // tile, on lines 40..56
+        set_sprite(&mut ppu, 0, 39, 0x03, 0x00, 84);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 85, 40), 0x22);
+        assert_eq!(pixel(&ppu, 85, 47), 0x22);
+        assert_eq!(pixel(&ppu, 85, 48), 0x23);
+        assert_eq!(pixel(&ppu, 85, 55), 0x23);
+        assert_eq!(pixel(&ppu, 85, 56), 0x0F);
+
+        set_sprite(&mut ppu, 0, 39, 0x03, 0x80, 84);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 85, 40), 0x23);
+        assert_eq!(pixel(&ppu, 85, 55), 0x22);
+    }
+
+    #[test]
+    fn empty_sprite_slots_fetch_tile_ff() {
+        for (ctrl, table) in [(0x00, 0x0000), (0x20, 0x1000)] {
+            let mut ppu = scene();
+            ppu.reg_write(0, ctrl);
+            run_to(&mut ppu, 261, 0);
+            // slot 0's pattern fetch on line 10, no sprite is in range
+            run_to(&mut ppu, 10, 262);
+            let addr = ppu.store.sprite_addr;
+            assert_eq!(addr & 0xFFE0, table + 0xFE0, "ctrl {:02X}", ctrl);
+            // tile 0xFF is opaque, but empty slots load nothing
+            assert_eq!(ppu.sprite_pattern_lo[0], 0);
+            assert_eq!(ppu.sprite_pattern_hi[0], 0);
+        }
+    }
+
+    #[test]
+    fn open_bus_bits_decay_on_their_own() {
+        let (mut ppu, _) = ppu();
+        // about 600 ms of NTSC dots
+        const DECAY: u64 = 3_300_000;
+        ppu.reg_write(3, 0xFF);
+        ppu.total_cycles += DECAY / 2;
+        assert_eq!(ppu.reg_read(0), 0xFF);
+
+        // a PPUSTATUS read drives bits 7-5 again, bits 4-0 keep aging
+        ppu.ppu_status.insert(
+            PPUSTATUS::IS_VBLANK
+                | PPUSTATUS::SPRITE0_HIT
+                | PPUSTATUS::SPRITE_OVERFLOW,
+        );
+        assert_eq!(ppu.reg_read(2), 0xFF);
+        ppu.total_cycles += DECAY / 2 + 1000;
+        assert_eq!(ppu.reg_read(0), 0xE0);
+        ppu.total_cycles += DECAY / 2;
+        assert_eq!(ppu.reg_read(0), 0x00);
+    }
+
+    #[test]
+    fn oam_data_reads_ff_while_secondary_oam_clears() {
+        let (mut ppu, _) = ppu();
+        ppu.oam.fill(0x12);
+        ppu.reg_write(1, 0x18);
+        run_to(&mut ppu, 10, 1);
+        for cycle in 1..=64 {
+            run_to(&mut ppu, 10, cycle);
+            assert_eq!(ppu.reg_read(4), 0xFF, "dot {}", cycle);
+        }
+        // evaluation reads primary OAM
+        run_to(&mut ppu, 10, 70);
+        assert_eq!(ppu.reg_read(4), 0x12);
+
+        // with rendering off, OAMADDR picks the byte
+        ppu.reg_write(1, 0x00);
+        run_to(&mut ppu, 11, 10);
+        assert_eq!(ppu.reg_read(4), 0x12);
+    }
+
+    #[test]
+    fn palette_reads_keep_open_bus_in_bits_7_6() {
+        let (mut ppu, _) = ppu();
+        ppu.palette[1] = 0x2A;
+        ppu.reg_write(6, 0x3F);
+        ppu.reg_write(6, 0x01);
+        ppu.reg_write(3, 0xC0);
+        assert_eq!(ppu.reg_read(7), 0xEA);
+        // the read drives bits 5-0 only
+        assert_eq!(ppu.reg_read(0), 0xEA);
+
+        // grayscale masks the palette bits, not open bus
+        ppu.reg_write(1, 0x01);
+        ppu.reg_write(6, 0x3F);
+        ppu.reg_write(6, 0x01);
+        ppu.reg_write(3, 0x40);
+        assert_eq!(ppu.reg_read(7), 0x60);
+    }
+
+    #[test]
+    fn ppu_data_access_while_rendering_bumps_x_and_y() {
+        let (mut ppu, _) = ppu();
+        ppu.reg_write(1, 0x18);
+        // dot 2 neither fetches nor moves v
+        run_to(&mut ppu, 10, 2);
+        ppu.v = 0;
+        ppu.reg_read(7);
+        // coarse x and fine y both step
+        assert_eq!(ppu.v, 0x1001);
+
+        ppu.reg_write(1, 0x00);
+        ppu.v = 0;
+        ppu.reg_read(7);
+        assert_eq!(ppu.v, 0x0001);
+    }
+
+    #[test]
+    fn power_on_ram_patterns() {
+        let (mut ppu, _) = ppu();
+        ppu.power_on(RamInit::ZERO, RamInit::FF);
+        assert!(ppu.oam.iter().all(|&b| b == 0));
+        // palette RAM is 6 bits wide
+        assert!(ppu.palette.iter().all(|&b| b == 0x3F));
+
+        ppu.power_on(RamInit::FF, RamInit::CONSOLE);
+        assert!(ppu.oam.iter().all(|&b| b == 0xFF));
+        assert_eq!(ppu.palette[..4], [0x09, 0x01, 0x00, 0x01]);
+        assert_eq!(ppu.palette[0x1F], 0x08);
+
+        ppu.power_on(RamInit::RANDOM, RamInit::RANDOM);
+        assert!(ppu.oam.iter().any(|&b| b != ppu.oam[0]));
+        assert!(ppu.palette.iter().all(|&b| b <= 0x3F));
+    }
+
+    #[test]
+    fn reset_clears_only_some_registers() {
+        let (mut ppu, _) = ppu();
+        run_to(&mut ppu, 245, 5);
+        ppu.reg_write(0, 0x80);
+        ppu.reg_write(1, 0x1E);
+        ppu.reg_write(3, 0x10);
+        ppu.reg_write(4, 0x55);
+        ppu.reg_write(6, 0x21);
+        ppu.reg_write(6, 0x23);
+        ppu.reg_write(7, 0x66);
+        ppu.reg_write(5, 0x7D);
+        ppu.read_buffer = 0x77;
+        ppu.ppu_status.insert(PPUSTATUS::SPRITE0_HIT);
+        let v = ppu.v;
+
+        ppu.reset();
+        assert!(ppu.ppu_ctrl.is_empty());
+        assert!(ppu.ppu_mask.is_empty());
+        assert_eq!((ppu.w, ppu.t, ppu.x, ppu.read_buffer), (0, 0, 0, 0));
+        assert_eq!((ppu.scanline, ppu.cycle), (0, 0));
+        // OAM, VRAM, OAMADDR, PPUSTATUS and v stay
+        assert_eq!(ppu.oam[0x10], 0x55);
+        assert_eq!(ppu.vram[0x123], 0x66);
+        assert_eq!(ppu.oam_addr, 0x11);
+        assert!(ppu.ppu_status.contains(PPUSTATUS::SPRITE0_HIT));
+        assert_eq!(ppu.v, v);
+    }
+
+    #[test]
+    fn writes_wait_for_the_first_pre_render_line() {
+        let (mut ppu, _) = ppu();
+        ppu.power_on(RamInit::ZERO, RamInit::ZERO);
+        ppu.reg_write(0, 0x80);
+        ppu.reg_write(1, 0x1E);
+        ppu.reg_write(5, 0xFF);
+        ppu.reg_write(6, 0x3F);
+        assert!(ppu.ppu_ctrl.is_empty());
+        assert!(ppu.ppu_mask.is_empty());
+        assert_eq!((ppu.t, ppu.x, ppu.w), (0, 0, 0));
+        // OAMADDR, OAMDATA and PPUDATA work from the start
+        ppu.reg_write(3, 0x20);
+        ppu.reg_write(4, 0x99);
+        ppu.reg_write(7, 0x44);
+        assert_eq!(ppu.oam[0x20], 0x99);
+        assert_eq!(ppu.v, 1);
+
+        // still ignored on the last dot before the pre-render line
+        run_to(&mut ppu, 260, 340);
+        ppu.reg_write(1, 0x1E);
+        assert!(ppu.ppu_mask.is_empty());
+        run_to(&mut ppu, 261, 2);
+        ppu.reg_write(0, 0x80);
+        ppu.reg_write(1, 0x1E);
+        ppu.reg_write(5, 0xFF);
+        assert!(ppu.ppu_ctrl.contains(PPUCTRL::NMI_ON_VBLANK));
+        assert_eq!(ppu.ppu_mask.bits(), 0x1E);
+        assert_eq!((ppu.x, ppu.w), (7, 1));
+    }
+
+    #[test]
+    fn rendering_off_shows_the_backdrop_or_the_palette_entry_at_v() {
+        let mut ppu = scene();
+        ppu.reg_write(1, 0x00);
+        ppu.palette[0x05] = 0x16;
+        ppu.vram[5 * 32 + 10] = 1;
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 0x0F);
+        assert_eq!(pixel(&ppu, 84, 44), 0x0F);
+
+        ppu.reg_write(6, 0x3F);
+        ppu.reg_write(6, 0x05);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 0x16);
+        assert_eq!(pixel(&ppu, 255, 239), 0x16);
+
+        // $3F10 mirrors the backdrop
+        ppu.reg_write(6, 0x3F);
+        ppu.reg_write(6, 0x10);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), 0x0F);
+    }
+
+    // background tile 1 at x 80..88 and sprite 0 (tile 2) at x 84..92, on
+    // lines 40..48, with sprite 1 (tile 3, palette 1) at x 120..128
+    fn overlap_scene() -> PPU {
+        let mut ppu = scene();
+        ppu.vram[5 * 32 + 10] = 1;
+        set_sprite(&mut ppu, 0, 39, 2, 0x00, 84);
+        set_sprite(&mut ppu, 1, 39, 3, 0x01, 120);
+        ppu
+    }
+
+    #[test]
+    fn hidden_layers_still_hit_sprite_zero() {
+        let mut ppu = overlap_scene();
+        ppu.overrides.hide_background = true;
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 82, 44), 0x0F);
+        assert_eq!(pixel(&ppu, 86, 44), 0x22);
+        assert!(ppu.ppu_status.contains(PPUSTATUS::SPRITE0_HIT));
+
+        let mut ppu = overlap_scene();
+        ppu.overrides.hide_sprites = true;
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 86, 44), 0x11);
+        assert_eq!(pixel(&ppu, 124, 44), 0x0F);
+        assert!(ppu.ppu_status.contains(PPUSTATUS::SPRITE0_HIT));
+    }
+
+    #[test]
+    fn no_clipping_draws_the_left_column_without_hitting_in_it() {
+        let mut ppu = scene();
+        // clip both layers in the left 8 pixels
+        ppu.reg_write(1, 0x18);
+        ppu.vram[5 * 32] = 1;
+        set_sprite(&mut ppu, 0, 39, 2, 0x00, 0);
+        set_sprite(&mut ppu, 1, 79, 2, 0x00, 0);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 2, 44), 0x0F);
+        assert_eq!(pixel(&ppu, 2, 84), 0x0F);
+        // sprite 0 and the background only overlap in the clipped column
+        assert!(!ppu.ppu_status.contains(PPUSTATUS::SPRITE0_HIT));
+
+        ppu.overrides.no_clipping = true;
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 2, 44), 0x22);
+        assert_eq!(pixel(&ppu, 2, 84), 0x22);
+        assert_eq!(pixel(&ppu, 10, 44), 0x0F);
+        assert!(!ppu.ppu_status.contains(PPUSTATUS::SPRITE0_HIT));
+    }
+
+    #[test]
+    fn force_palette_and_sprite_zero_highlight() {
+        let mut ppu = overlap_scene();
+        ppu.palette[0x15] = 0x30;
+        ppu.palette[0x16] = 0x36;
+        ppu.palette[0x17] = 0x37;
+        ppu.overrides.force_palette = Some(5);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 82, 44), 0x30);
+        assert_eq!(pixel(&ppu, 86, 44), 0x36);
+        assert_eq!(pixel(&ppu, 124, 44), 0x37);
+        // the backdrop keeps its color
+        assert_eq!(pixel(&ppu, 40, 44), 0x0F);
+
+        let mut ppu = overlap_scene();
+        ppu.overrides.highlight_sprite_zero = true;
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 86, 44), 0x2A);
+        assert_eq!(pixel(&ppu, 124, 44), 0x27);
+        assert_eq!(pixel(&ppu, 82, 44), 0x11);
+    }
+
+    #[test]
+    fn output_color_grayscale_and_emphasis() {
+        let (mut ppu, _) = ppu();
+        assert_eq!(ppu.output_color(0x2D), 0x2D);
+        ppu.reg_write(1, 0x01);
+        assert_eq!(ppu.output_color(0x2D), 0x20);
+        assert_eq!(ppu.output_color(0x0F), 0x00);
+        // red, green and blue emphasis land in bits 6, 7 and 8
+        ppu.reg_write(1, 0x20);
+        assert_eq!(ppu.output_color(0x2D), 0x06D);
+        ppu.reg_write(1, 0x40);
+        assert_eq!(ppu.output_color(0x2D), 0x0AD);
+        ppu.reg_write(1, 0x80);
+        assert_eq!(ppu.output_color(0x2D), 0x12D);
+        ppu.reg_write(1, 0xE1);
+        assert_eq!(ppu.output_color(0x2D), 0x1E0);
+
+        // PAL and Dendy PPUs have the red and green bits swapped
+        ppu.region = Region::PAL;
+        ppu.reg_write(1, 0x20);
+        assert_eq!(ppu.output_color(0x2D), 0x0AD);
+        ppu.reg_write(1, 0x40);
+        assert_eq!(ppu.output_color(0x2D), 0x06D);
+        ppu.reg_write(1, 0x80);
+        assert_eq!(ppu.output_color(0x2D), 0x12D);
+        ppu.region = Region::DENDY;
+        ppu.reg_write(1, 0xA0);
+        assert_eq!(ppu.output_color(0x2D), 0x1AD);
+
+        // both rendered and backdrop pixels go through it
+        let mut ppu = overlap_scene();
+        ppu.reg_write(1, 0x5F);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 82, 44), 0x090);
+        assert_eq!(pixel(&ppu, 86, 44), 0x0A0);
+        assert_eq!(pixel(&ppu, 40, 44), 0x080);
+        ppu.reg_write(1, 0x41);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 40, 44), 0x080);
+    }
+
+    // renders a frame with sources on, through to the vblank that ends it
+    fn recorded_frame(ppu: &mut PPU) {
+        ppu.sources.enabled = true;
+        render_frame(ppu);
+        run_to(ppu, 241, 2);
+    }
+
+    #[test]
+    fn sources_name_the_tile_pixel_and_palette() {
+        let mut ppu = overlap_scene();
+        // sprite 0 flipped both ways, behind the background
+        set_sprite(&mut ppu, 0, 39, 2, 0xE0, 84);
+        recorded_frame(&mut ppu);
+        let frame = &ppu.sources.frame;
+
+        let pixel = frame.pixels[44 * 256 + 82];
+        assert_eq!(pixel.backdrop, 0x0F);
+        let background = pixel.background.unwrap();
+        assert_eq!(background.tile, TileKey::ROM(1));
+        assert_eq!(background.palette, 0x0F110000);
+        assert_eq!((background.x, background.y), (2, 4));
+        assert_eq!((background.pattern, background.color), (1, 0x11));
+        assert!(pixel.sprite.is_none());
+
+        let pixel = frame.pixels[44 * 256 + 86];
+        let sprite = pixel.sprite.unwrap();
+        assert!(pixel.sprite_behind);
+        assert_eq!(sprite.tile, TileKey::ROM(2));
+        assert_eq!(sprite.palette, 0x0F002200);
+        // column 2 and row 4 of the flipped sprite
+        assert_eq!((sprite.x, sprite.y), (5, 3));
+        assert!(sprite.flip_h && sprite.flip_v);
+        assert_eq!((sprite.pattern, sprite.color), (2, 0x22));
+
+        let pixel = frame.pixels[44 * 256 + 124];
+        assert_eq!(pixel.sprite.unwrap().palette, 0x0F000027);
+        assert_eq!(pixel.background.unwrap().pattern, 0);
+        assert!(!pixel.sprite_behind);
+    }
+
+    #[test]
+    fn sources_follow_the_drawn_layers() {
+        let mut ppu = overlap_scene();
+        ppu.overrides.hide_sprites = true;
+        recorded_frame(&mut ppu);
+        let pixel = ppu.sources.frame.pixels[44 * 256 + 86];
+        assert!(pixel.background.is_some() && pixel.sprite.is_none());
+
+        // with rendering off only the backdrop is left
+        ppu.reg_write(1, 0x00);
+        recorded_frame(&mut ppu);
+        let pixel = ppu.sources.frame.pixels[44 * 256 + 86];
+        assert!(pixel.background.is_none() && pixel.sprite.is_none());
+        assert_eq!(pixel.backdrop, 0x0F);
+        assert_eq!(ppu.sources.frame.scroll, (0, 0));
+    }
+
+    #[test]
+    fn sources_record_the_frame_scroll() {
+        let mut ppu = overlap_scene();
+        run_to(&mut ppu, 245, 0);
+        // second nametable, 13 pixels across and 7 down
+        ppu.reg_write(0, 0x01);
+        ppu.reg_write(5, 13);
+        ppu.reg_write(5, 7);
+        recorded_frame(&mut ppu);
+        assert_eq!(ppu.sources.frame.scroll, (256 + 13, 7));
+        // the tile at (80, 40) now shows 13 pixels left and 7 up
+        let background = ppu.sources.frame.pixels[37 * 256 + 69].background;
+        let background = background.unwrap();
+        assert_eq!(background.tile, TileKey::ROM(1));
+        assert_eq!((background.x, background.y), (2, 4));
+    }
 }
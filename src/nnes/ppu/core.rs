@@ -1,38 +1,349 @@
-use super::{NAMETABLE_START, PPU, PPUCTRL, PPUMASK, PPUSTATUS};
+use super::{
+    EventKind, Sprite, NAMETABLE_START, PALETTE_START, PPU, PPUCTRL, PPUMASK,
+    PPUSTATUS,
+};
+use crate::region::Region;
+use crate::utils::bit_7;
+
+// Color sprite 0 is drawn in with highlight_sprite_zero: bright green
+const SPRITE_ZERO_HIGHLIGHT: u8 = 0x2A;
+
+// The sprite side of one pixel
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpritePixel {
+    // Sprite palettes live at [0x3F10, 0x3F20): 0x10 | palette << 2 |
+    // pattern, 0 when transparent
+    pub color: u8,
+    // slot drawn from, 8+ for the sprites past the limit
+    pub slot: u8,
+    pub behind: bool,
+    // sprite 0, on a line where it is in slot 0
+    pub zero: bool,
+}
+
+impl SpritePixel {
+    pub fn new(pattern: u8, attributes: u8, slot: usize) -> Self {
+        SpritePixel {
+            color: 0x10 | ((attributes & 0b11) << 2) | pattern,
+            slot: slot as u8,
+            behind: attributes & 0b0010_0000 != 0,
+            zero: false,
+        }
+    }
+}
 
 impl PPU {
     pub fn draw_pixel(&mut self) {
         let x = self.cycle - 1; // this is called during cycles [1,256]
-        let y = self.scanline;
-        let idx = (y * 256 + x) as usize;
 
         let mut background = 0;
         if self.ppu_mask.contains(PPUMASK::SHOW_BACKGROUND) {
-            if x >= 8 || self.ppu_mask.contains(PPUMASK::NO_CLIP_BACKGROUND) {
-                // Select pixel from pattern table shift registers using fine x scroll
-                let bit_mux = 0x8000 >> self.x;
-                let p1 = ((self.pattern_lo & bit_mux) > 0) as u8;
-                let p2 = ((self.pattern_hi & bit_mux) > 0) as u8;
+            // Select pixel from pattern table shift registers using fine x scroll
+            let bit_mux = 0x8000 >> self.x;
+            let p1 = ((self.pattern_lo & bit_mux) > 0) as u8;
+            let p2 = ((self.pattern_hi & bit_mux) > 0) as u8;
 
-                // Select palette from attribute table shift registers
-                let a1 = ((self.attribute_lo & bit_mux) > 0) as u8;
-                let a2 = ((self.attribute_hi & bit_mux) > 0) as u8;
+            // Select palette from attribute table shift registers
+            let a1 = ((self.attribute_lo & bit_mux) > 0) as u8;
+            let a2 = ((self.attribute_hi & bit_mux) > 0) as u8;
 
-                let pattern = (p2 << 1) | p1;
-                let attribute = (a2 << 1) | a1;
+            let pattern = (p2 << 1) | p1;
+            let attribute = (a2 << 1) | a1;
 
-                // A pattern of 0 means the background color is used
-                if pattern != 0 {
-                    background = (attribute << 2) | pattern;
+            // A pattern of 0 means the background color is used
+            if pattern != 0 {
+                background = (attribute << 2) | pattern;
+            }
+        }
+
+        let mut sprite = SpritePixel::default();
+        if self.ppu_mask.contains(PPUMASK::SHOW_SPRITES) {
+            // The first opaque sprite wins, even if it is behind the
+            // background and a later sprite would have been in front
+            for i in 0..8 {
+                if self.sprite_x_counter[i] != 0 {
+                    continue;
+                }
+                let p1 = bit_7(self.sprite_pattern_lo[i]);
+                let p2 = bit_7(self.sprite_pattern_hi[i]);
+                let pattern = (p2 << 1) | p1;
+                if pattern == 0 {
+                    continue;
+                }
+                let attributes = self.sprites[i].attributes;
+                sprite = SpritePixel::new(pattern, attributes, i);
+                sprite.zero = i == 0 && self.sprite_zero_line;
+                break;
+            }
+            // sprites past the limit come after all 8, in OAM order
+            if sprite.color == 0 {
+                for i in 0..self.extra_sprites.len() {
+                    if self.extra_x_counter[i] != 0 {
+                        continue;
+                    }
+                    let p1 = bit_7(self.extra_pattern_lo[i]);
+                    let p2 = bit_7(self.extra_pattern_hi[i]);
+                    let pattern = (p2 << 1) | p1;
+                    if pattern == 0 {
+                        continue;
+                    }
+                    let attributes = self.extra_sprites[i].attributes;
+                    sprite = SpritePixel::new(pattern, attributes, 8 + i);
+                    break;
                 }
             }
         }
+        self.shift_sprites();
+
+        self.output_pixel(x, background, sprite);
+    }
+
+    // Everything after the layers are picked: sprite 0 hit, priority, the
+    // debug overrides and the palette lookup. background is the 4 bit
+    // palette index, 0 when transparent. Shared by both renderers.
+    pub fn output_pixel(
+        &mut self,
+        x: u16,
+        mut background: u8,
+        sprite: SpritePixel,
+    ) {
+        let idx = (self.scanline * 256 + x) as usize;
+        let SpritePixel {
+            color: mut sprite_color,
+            slot: sprite_slot,
+            behind: behind_background,
+            zero: is_sprite_zero,
+        } = sprite;
 
-        // TODO: Sprite rendering and multiplexing
-        let color = background;
+        // Pixels are picked regardless of left column clipping, which is
+        // applied here so the debug overrides can skip it
+        let clip_background =
+            x < 8 && !self.ppu_mask.contains(PPUMASK::NO_CLIP_BACKGROUND);
+        let clip_sprites =
+            x < 8 && !self.ppu_mask.contains(PPUMASK::NO_CLIP_SPRITES);
+
+        // Sprite 0 hit needs both pixels opaque after left column clipping,
+        // and never happens at x = 255
+        let hit_background = background != 0 && !clip_background;
+        let hit_sprite = is_sprite_zero && !clip_sprites;
+        if hit_sprite && hit_background && x != 255 {
+            if !self.ppu_status.contains(PPUSTATUS::SPRITE0_HIT) {
+                self.log_event(EventKind::SPRITE0_HIT, 0, 0);
+            }
+            self.ppu_status.insert(PPUSTATUS::SPRITE0_HIT);
+        }
+
+        // From here on only the drawn pixel changes
+        let overrides = self.overrides;
+        let show_background = self.ppu_mask.contains(PPUMASK::SHOW_BACKGROUND)
+            && !overrides.hide_background
+            && (overrides.no_clipping || !clip_background);
+        if !show_background {
+            background = 0;
+        }
+        if overrides.hide_sprites || clip_sprites && !overrides.no_clipping {
+            sprite_color = 0;
+        }
+
+        let mut color = match (background != 0, sprite_color != 0) {
+            (false, false) => 0,
+            (false, true) => sprite_color,
+            (true, false) => background,
+            (true, true) if behind_background => background,
+            (true, true) => sprite_color,
+        };
+        if let Some(group) = overrides.force_palette {
+            if color != 0 {
+                color = ((group & 0b111) << 2) | (color & 0b11);
+            }
+        }
 
         let palette_addr = self.get_palette_addr(color as u16);
-        self.back[idx] = self.palette[palette_addr as usize] % 64;
+        let mut palette_idx = self.palette[palette_addr as usize] & 0x3F;
+        if overrides.highlight_sprite_zero
+            && is_sprite_zero
+            && sprite_color != 0
+        {
+            palette_idx = SPRITE_ZERO_HIGHLIGHT;
+        }
+        self.back[idx] = self.output_color(palette_idx);
+
+        if self.sources.enabled {
+            let sprite = (sprite_color != 0)
+                .then_some((sprite_slot as usize, sprite_color & 0b11));
+            self.record_source(x, show_background, sprite, behind_background);
+        }
+    }
+
+    pub fn draw_backdrop(&mut self) {
+        let x = self.cycle - 1; // this is called during cycles [1,256]
+        let y = self.scanline;
+        let idx = (y * 256 + x) as usize;
+
+        // with rendering off the PPU outputs the backdrop color, unless v
+        // points into palette RAM, then it outputs that entry instead
+        let color = if self.v & 0x3FFF >= PALETTE_START {
+            self.v
+        } else {
+            0
+        };
+        let palette_addr = self.get_palette_addr(color);
+        let palette_idx = self.palette[palette_addr as usize] & 0x3F;
+        self.back[idx] = self.output_color(palette_idx);
+        if self.sources.enabled {
+            self.record_backdrop_source(x, self.back[idx]);
+        }
+    }
+
+    pub fn output_color(&self, mut palette_idx: u8) -> u16 {
+        // grayscale keeps only the brightness column of the palette
+        if self.ppu_mask.contains(PPUMASK::GRAYSCALE) {
+            palette_idx &= 0x30;
+        }
+        // PPUMASK[7:5] (BGR emphasis) become color[8:6]
+        let mut emphasis = (self.ppu_mask.bits() >> 5) as u16;
+        // the 2C07 and Dendy PPUs swap the red and green bits
+        if self.region != Region::NTSC {
+            emphasis = emphasis & 0b100
+                | (emphasis & 0b001) << 1
+                | (emphasis & 0b010) >> 1;
+        }
+        emphasis << 6 | palette_idx as u16
+    }
+
+    pub fn shift_sprites(&mut self) {
+        // Count down to each sprite's x, then shift its pattern out
+        for i in 0..8 {
+            if self.sprite_x_counter[i] > 0 {
+                self.sprite_x_counter[i] -= 1;
+            } else {
+                self.sprite_pattern_lo[i] <<= 1;
+                self.sprite_pattern_hi[i] <<= 1;
+            }
+        }
+        for i in 0..self.extra_sprites.len() {
+            if self.extra_x_counter[i] > 0 {
+                self.extra_x_counter[i] -= 1;
+            } else {
+                self.extra_pattern_lo[i] <<= 1;
+                self.extra_pattern_hi[i] <<= 1;
+            }
+        }
+    }
+
+    pub fn fetch_sprite_lo(&mut self, slot: usize) {
+        let sprite = self.sprites[slot];
+        // empty slots still fetch tile 0xFF, which mappers watching A12
+        // (MMC3) count on in 8x16 mode
+        let tile = if slot >= self.store.accepted_sprite as usize {
+            0xFF
+        } else {
+            sprite.tile_number
+        };
+        self.store.sprite_addr = self.sprite_row_addr(sprite, tile);
+        self.fetch_sprite_source(slot, self.store.sprite_addr);
+        let data = self.fetch(self.store.sprite_addr);
+        self.sprite_pattern_lo[slot] = self.sprite_pattern(slot, data);
+    }
+
+    pub fn fetch_sprite_hi(&mut self, slot: usize) {
+        // must always be called after self.fetch_sprite_lo()
+        let data = self.fetch(self.store.sprite_addr + 8);
+        self.sprite_pattern_hi[slot] = self.sprite_pattern(slot, data);
+        self.sprite_x_counter[slot] = self.sprites[slot].x_coordinate;
+    }
+
+    // With the sprite limit off, find the in-range sprites past the first 8
+    // and fetch them as well. Evaluation and the overflow flag are left
+    // alone, so games see the hardware behavior.
+    pub fn fetch_extra_sprites(&mut self) {
+        self.extra_sprites.clear();
+        self.extra_pattern_lo.clear();
+        self.extra_pattern_hi.clear();
+        self.extra_x_counter.clear();
+        if !self.unlimited_sprites {
+            return;
+        }
+
+        let height = self.store.sprite_height as u16;
+        let mut in_range = 0;
+        for n in 0..64 {
+            let y = self.oam[4 * n] as u16;
+            if self.scanline < y || self.scanline >= y + height {
+                continue;
+            }
+            in_range += 1;
+            if in_range <= 8 {
+                continue;
+            }
+            let sprite = Sprite {
+                y_coordinate: self.oam[4 * n],
+                tile_number: self.oam[4 * n + 1],
+                attributes: self.oam[4 * n + 2],
+                x_coordinate: self.oam[4 * n + 3],
+            };
+            let addr = self.sprite_row_addr(sprite, sprite.tile_number);
+            self.fetch_sprite_source(self.extra_sprites.len() + 8, addr);
+            let mut lo = self.mem_read(addr);
+            let mut hi = self.mem_read(addr + 8);
+            if sprite.attributes & 0b0100_0000 != 0 {
+                lo = lo.reverse_bits();
+                hi = hi.reverse_bits();
+            }
+            self.extra_sprites.push(sprite);
+            self.extra_pattern_lo.push(lo);
+            self.extra_pattern_hi.push(hi);
+            self.extra_x_counter.push(sprite.x_coordinate);
+        }
+    }
+
+    pub fn sprite_row_addr(&self, sprite: Sprite, tile: u8) -> u16 {
+        let tall = self.ppu_ctrl.contains(PPUCTRL::SPRITE_SIZE);
+        let height = if tall { 16 } else { 8 };
+        // row of the sprite on the next scanline, flipped vertically if
+        // attributes[7] is set. In 8x16 mode the flip spans both tiles.
+        let mut row = self.scanline.wrapping_sub(sprite.y_coordinate as u16)
+            & (height - 1);
+        if sprite.attributes & 0b1000_0000 != 0 {
+            row = height - 1 - row;
+        }
+        if tall {
+            // tile[0] picks the pattern table, tile[7:1] the top tile, and
+            // rows 8-15 come from the bottom tile right after it
+            let table = 0x1000 * (tile & 1) as u16;
+            let tile = (tile & 0xFE) as u16 + (row >> 3);
+            table + tile * 16 + (row & 7)
+        } else {
+            // get base addr from PPUCTRL::SPRITE_PATTERN_TABLE: 0: 0x0000,
+            // 1: 0x1000
+            let table =
+                if self.ppu_ctrl.contains(PPUCTRL::SPRITE_PATTERN_TABLE) {
+                    0x1000
+                } else {
+                    0
+                };
+            table + tile as u16 * 16 + row
+        }
+    }
+
+    fn sprite_pattern(&self, slot: usize, data: u8) -> u8 {
+        if slot >= self.store.accepted_sprite as usize {
+            // empty slots still fetch, but load a transparent pattern
+            0
+        } else if self.sprites[slot].attributes & 0b0100_0000 != 0 {
+            // flip horizontally
+            data.reverse_bits()
+        } else {
+            data
+        }
+    }
+
+    // A rendering fetch, which mappers watching them (MMC3's A12) get to see
+    fn fetch(&mut self, addr: u16) -> u8 {
+        if self.watches_fetches {
+            self.cartridge.borrow_mut().ppu_fetch(addr);
+        }
+        self.mem_read(addr)
     }
 
     pub fn fetch_nametable(&mut self) {
@@ -40,7 +351,7 @@ impl PPU {
         let offset = self.v & 0b11_11111_11111;
         // final address: .10 NN YYYYY XXXXX
         let addr = NAMETABLE_START | offset;
-        self.store.nametable_byte = self.mem_read(addr);
+        self.store.nametable_byte = self.fetch(addr);
     }
 
     pub fn fetch_attribute(&mut self) {
@@ -54,7 +365,7 @@ impl PPU {
         let coarse_x = (self.v >> 2) & 0b111;
         // final address: .10 NN 1111 YYY XXX
         let addr = NAMETABLE_START | nametable | offset | coarse_y | coarse_x;
-        let attr_byte = self.mem_read(addr);
+        let attr_byte = self.fetch(addr);
 
         // Each attribute packs four 2-bit tiles:
         //     attr_byte[1:0]: top left
@@ -85,13 +396,14 @@ impl PPU {
         // final address: pattern_table_base + start of current tile data + fine_y for lo byte
         self.store.tile_addr =
             background_pattern_table_start + tile_offset + fine_y;
-        self.store.tile_lo_byte = self.mem_read(self.store.tile_addr);
+        self.store.tile_lo_byte = self.fetch(self.store.tile_addr);
     }
 
     pub fn fetch_tile_hi(&mut self) {
         // must always be called after self.fetch_tile_lo()
         // final address: prev lo address + 8 for hi byte
-        self.store.tile_hi_byte = self.mem_read(self.store.tile_addr + 8);
+        self.store.tile_hi_byte = self.fetch(self.store.tile_addr + 8);
+        self.fetch_tile_source();
     }
 
     pub fn copy_y(&mut self) {
@@ -144,6 +456,7 @@ impl PPU {
     }
 
     pub fn store_tiles(&mut self) {
+        self.sources.store_tile();
         self.pattern_lo =
             (self.pattern_lo & 0xFF00) | self.store.tile_lo_byte as u16;
         self.pattern_hi =
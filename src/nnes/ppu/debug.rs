@@ -0,0 +1,446 @@
+use super::{NAMETABLE_START, PALETTE_START, PPU, PPUCTRL};
+use crate::palette::Palette;
+use std::fmt;
+
+// Views of live PPU state for debugging, rendered as RGB24 images so they
+// work without a window
+
+pub struct DebugImage {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl DebugImage {
+    pub fn new(width: usize, height: usize) -> Self {
+        DebugImage {
+            width,
+            height,
+            data: vec![0; width * height * 3],
+        }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, (r, g, b): (u8, u8, u8)) {
+        let i = (y * self.width + x) * 3;
+        self.data[i..i + 3].copy_from_slice(&[r, g, b]);
+    }
+}
+
+// One decoded OAM entry
+pub struct OamEntry {
+    pub index: usize,
+    pub x: u8,
+    pub y: u8,
+    pub tile: u8,
+    pub palette: u8,
+    pub behind_background: bool,
+    pub flip_h: bool,
+    pub flip_v: bool,
+    // the sprite's pixels, 8x8 or 8x16
+    pub preview: DebugImage,
+}
+
+impl fmt::Display for OamEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:2}: x {:3} y {:3} tile {:02X} palette {}{}{}{}",
+            self.index,
+            self.x,
+            self.y,
+            self.tile,
+            self.palette,
+            if self.behind_background {
+                " behind"
+            } else {
+                ""
+            },
+            if self.flip_h { " flip-h" } else { "" },
+            if self.flip_v { " flip-v" } else { "" },
+        )
+    }
+}
+
+// Scroll rectangle outline in the nametable view
+const SCROLL_COLOR: (u8, u8, u8) = (255, 0, 255);
+// Gap between sprites in the OAM view
+const OAM_CELL: usize = 10;
+
+impl PPU {
+    // The 4 logical nametables as a 512x480 image, after mirroring, with
+    // the 256x240 screen the last frame started at outlined
+    pub fn nametable_view(&self, palette: &Palette) -> DebugImage {
+        let mut image = DebugImage::new(512, 480);
+        let table = self.background_table();
+        for nametable in 0..4u16 {
+            let base = NAMETABLE_START + nametable * 0x400;
+            let (left, top) = (nametable as usize % 2, nametable as usize / 2);
+            for tile_y in 0..30u16 {
+                for tile_x in 0..32u16 {
+                    let tile = self.peek(base + tile_y * 32 + tile_x);
+                    let attribute = self
+                        .peek(base + 0x3C0 + (tile_y / 4) * 8 + tile_x / 4);
+                    let shift = ((tile_y & 2) << 1) | (tile_x & 2);
+                    let group = (attribute >> shift) & 0b11;
+                    self.draw_tile(
+                        &mut image,
+                        palette,
+                        table + tile as u16 * 16,
+                        group,
+                        left * 256 + tile_x as usize * 8,
+                        top * 240 + tile_y as usize * 8,
+                    );
+                }
+            }
+        }
+
+        let (scroll_x, scroll_y) = self.start_scroll;
+        let (scroll_x, scroll_y) = (scroll_x as usize, scroll_y as usize);
+        for i in 0..256 {
+            let x = (scroll_x + i) % 512;
+            image.set(x, scroll_y % 480, SCROLL_COLOR);
+            image.set(x, (scroll_y + 239) % 480, SCROLL_COLOR);
+        }
+        for i in 0..240 {
+            let y = (scroll_y + i) % 480;
+            image.set(scroll_x % 512, y, SCROLL_COLOR);
+            image.set((scroll_x + 255) % 512, y, SCROLL_COLOR);
+        }
+        image
+    }
+
+    // Both pattern tables side by side as a 256x128 image, colored with
+    // palette group 0-7 (4-7 are the sprite palettes)
+    pub fn pattern_view(&self, palette: &Palette, group: u8) -> DebugImage {
+        let mut image = DebugImage::new(256, 128);
+        for table in 0..2u16 {
+            for tile in 0..256u16 {
+                self.draw_tile(
+                    &mut image,
+                    palette,
+                    table * 0x1000 + tile * 16,
+                    group & 0b111,
+                    table as usize * 128 + (tile % 16) as usize * 8,
+                    (tile / 16) as usize * 8,
+                );
+            }
+        }
+        image
+    }
+
+    // All 64 sprites, decoded
+    pub fn oam_entries(&self, palette: &Palette) -> Vec<OamEntry> {
+        (0..64)
+            .map(|index| self.oam_entry(palette, index))
+            .collect()
+    }
+
+    // One sprite, decoded
+    pub fn oam_entry(&self, palette: &Palette, index: usize) -> OamEntry {
+        let tall = self.ppu_ctrl.contains(PPUCTRL::SPRITE_SIZE);
+        let table = if self.ppu_ctrl.contains(PPUCTRL::SPRITE_PATTERN_TABLE) {
+            0x1000
+        } else {
+            0x0000
+        };
+        let sprite = &self.oam[index * 4..index * 4 + 4];
+        let (tile, attributes) = (sprite[1], sprite[2]);
+        let mut entry = OamEntry {
+            index,
+            x: sprite[3],
+            y: sprite[0],
+            tile,
+            palette: attributes & 0b11,
+            behind_background: attributes & 0b0010_0000 != 0,
+            flip_h: attributes & 0b0100_0000 != 0,
+            flip_v: attributes & 0b1000_0000 != 0,
+            preview: DebugImage::new(8, if tall { 16 } else { 8 }),
+        };
+
+        // 8x16 sprites take their table from bit 0 of the tile
+        let (addr, halves) = if tall {
+            ((tile as u16 & 1) * 0x1000 + (tile as u16 & 0xFE) * 16, 2)
+        } else {
+            (table + tile as u16 * 16, 1)
+        };
+        for half in 0..halves {
+            self.draw_tile(
+                &mut entry.preview,
+                palette,
+                addr + half * 16,
+                4 + entry.palette,
+                0,
+                half as usize * 8,
+            );
+        }
+        flip(&mut entry.preview, entry.flip_h, entry.flip_v);
+        entry
+    }
+
+    // The OAM entries' previews in an 8x8 grid, in OAM order
+    pub fn oam_view(&self, palette: &Palette) -> DebugImage {
+        let mut image = DebugImage::new(8 * OAM_CELL, 8 * OAM_CELL * 2);
+        for entry in self.oam_entries(palette) {
+            let left = (entry.index % 8) * OAM_CELL + 1;
+            let top = (entry.index / 8) * OAM_CELL * 2 + 1;
+            let preview = &entry.preview;
+            for y in 0..preview.height {
+                for x in 0..preview.width {
+                    let i = (y * preview.width + x) * 3;
+                    let rgb = (
+                        preview.data[i],
+                        preview.data[i + 1],
+                        preview.data[i + 2],
+                    );
+                    image.set(left + x, top + y, rgb);
+                }
+            }
+        }
+        image
+    }
+
+    // The sprite whose cell covers (x, y) of the OAM view
+    pub fn oam_entry_at(
+        &self,
+        palette: &Palette,
+        x: usize,
+        y: usize,
+    ) -> Option<OamEntry> {
+        let (column, row) = (x / OAM_CELL, y / (OAM_CELL * 2));
+        if column >= 8 || row >= 8 {
+            return None;
+        }
+        Some(self.oam_entry(palette, row * 8 + column))
+    }
+
+    // Palette RAM as a 16x2 grid of 16x16 swatches, background on top
+    pub fn palette_view(&self, palette: &Palette) -> DebugImage {
+        let mut image = DebugImage::new(256, 32);
+        for entry in 0..0x20u16 {
+            let rgb = self.palette_rgb(palette, PALETTE_START + entry);
+            let (left, top) = ((entry % 16) as usize, (entry / 16) as usize);
+            for y in 0..16 {
+                for x in 0..16 {
+                    image.set(left * 16 + x, top * 16 + y, rgb);
+                }
+            }
+        }
+        image
+    }
+
+    // Helpers
+    // v at the first dot of a frame as a point in the nametable view, less
+    // the 2 tiles fetched ahead
+    pub(super) fn scroll_from_v(&self) -> (u16, u16) {
+        // v: yyy NN YYYYY XXXXX, plus fine x
+        let coarse_x = self.v & 0b11111;
+        let coarse_y = (self.v >> 5) & 0b11111;
+        let fine_y = (self.v >> 12) & 0b111;
+        let scroll_x = ((self.v >> 10) & 1) * 256 + coarse_x * 8;
+        let scroll_y = ((self.v >> 11) & 1) * 240 + coarse_y * 8 + fine_y;
+        let scroll_x = (scroll_x + 512 - 16 + self.x as u16) % 512;
+        (scroll_x, scroll_y % 480)
+    }
+
+    fn background_table(&self) -> u16 {
+        if self.ppu_ctrl.contains(PPUCTRL::BACKGROUND_PATTERN_TABLE) {
+            0x1000
+        } else {
+            0x0000
+        }
+    }
+
+    fn palette_rgb(&self, palette: &Palette, addr: u16) -> (u8, u8, u8) {
+        let palette_addr = self.get_palette_addr(addr);
+        palette.rgb((self.palette[palette_addr as usize] & 0x3F) as u16)
+    }
+
+    fn draw_tile(
+        &self,
+        image: &mut DebugImage,
+        palette: &Palette,
+        addr: u16,
+        group: u8,
+        left: usize,
+        top: usize,
+    ) {
+        for row in 0..8 {
+            let lo = self.peek(addr + row);
+            let hi = self.peek(addr + row + 8);
+            for col in 0..8 {
+                let pattern =
+                    ((hi >> (7 - col)) & 1) << 1 | (lo >> (7 - col)) & 1;
+                // pattern 0 is transparent, and shows the backdrop
+                let color = if pattern == 0 {
+                    0
+                } else {
+                    (group as u16) << 2 | pattern as u16
+                };
+                let rgb = self.palette_rgb(palette, PALETTE_START + color);
+                image.set(left + col, top + row as usize, rgb);
+            }
+        }
+    }
+}
+
+fn flip(image: &mut DebugImage, flip_h: bool, flip_v: bool) {
+    let source = image.data.clone();
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let sx = if flip_h { image.width - 1 - x } else { x };
+            let sy = if flip_v { image.height - 1 - y } else { y };
+            let i = (sy * image.width + sx) * 3;
+            image.set(x, y, (source[i], source[i + 1], source[i + 2]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DebugImage, PPU, SCROLL_COLOR};
+    use crate::cartridge::Cartridge;
+    use crate::palette::Palette;
+    use crate::region::Region;
+    use std::{cell::RefCell, rc::Rc};
+
+    // Tile 1 is solid color 1 and tile 2 has one color 3 pixel at its top
+    // left, in the first pattern table. Tile 3 of the second is solid
+    // color 2. Horizontal mirroring.
+    fn ppu() -> PPU {
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1];
+        rom.resize(16 + 0x4000, 0);
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].fill(0xFF);
+        chr[0x20] = 0x80;
+        chr[0x28] = 0x80;
+        chr[0x1038..0x1040].fill(0xFF);
+        rom.extend(chr);
+        let cartridge = Cartridge::new(rom).unwrap();
+        let mut ppu = PPU::new(Rc::new(RefCell::new(cartridge)), Region::NTSC);
+        ppu.palette[0x00] = 0x0F;
+        ppu.palette[0x01] = 0x11;
+        ppu.palette[0x05] = 0x21;
+        ppu.palette[0x16] = 0x26;
+        ppu.palette[0x17] = 0x27;
+        ppu
+    }
+
+    fn get(image: &DebugImage, x: usize, y: usize) -> (u8, u8, u8) {
+        let i = (y * image.width + x) * 3;
+        (image.data[i], image.data[i + 1], image.data[i + 2])
+    }
+
+    #[test]
+    fn nametable_view() {
+        let palette = Palette::builtin();
+        let mut ppu = ppu();
+        ppu.vram[0] = 1;
+        // tile 2 of the first row, with the attribute's top right group 1
+        ppu.vram[2] = 1;
+        ppu.vram[0x3C0] = 0b0100;
+        let view = ppu.nametable_view(&palette);
+        assert_eq!((view.width, view.height), (512, 480));
+        assert_eq!(get(&view, 4, 4), palette.rgb(0x11));
+        assert_eq!(get(&view, 12, 4), palette.rgb(0x0F));
+        assert_eq!(get(&view, 20, 4), palette.rgb(0x21));
+        // the right nametable mirrors the left, the bottom ones are empty
+        assert_eq!(get(&view, 260, 4), palette.rgb(0x11));
+        assert_eq!(get(&view, 4, 244), palette.rgb(0x0F));
+    }
+
+    #[test]
+    fn nametable_view_outlines_the_frame_scroll() {
+        let palette = Palette::builtin();
+        let mut ppu = ppu();
+        ppu.reg_write(5, 20);
+        ppu.reg_write(5, 12);
+        ppu.reg_write(1, 0x08);
+        while ppu.scanline != 261 {
+            ppu.tick();
+        }
+        while ppu.scanline != 10 {
+            ppu.tick();
+        }
+        // a scroll write mid-frame only moves t
+        ppu.reg_write(5, 100);
+        ppu.reg_write(5, 100);
+        let view = ppu.nametable_view(&palette);
+        assert_eq!(get(&view, 20, 12), SCROLL_COLOR);
+        assert_eq!(get(&view, 20 + 255, 12 + 239), SCROLL_COLOR);
+        assert_ne!(get(&view, 100, 100), SCROLL_COLOR);
+    }
+
+    #[test]
+    fn pattern_view() {
+        let palette = Palette::builtin();
+        let ppu = ppu();
+        let view = ppu.pattern_view(&palette, 0);
+        assert_eq!((view.width, view.height), (256, 128));
+        assert_eq!(get(&view, 12, 4), palette.rgb(0x11));
+        assert_eq!(get(&view, 16, 0), palette.rgb(0x00));
+        // the second table starts at x 128
+        assert_eq!(get(&view, 128 + 12, 4), palette.rgb(0x0F));
+        assert_eq!(get(&view, 128 + 28, 4), palette.rgb(0x00));
+        let view = ppu.pattern_view(&palette, 1);
+        assert_eq!(get(&view, 12, 4), palette.rgb(0x21));
+    }
+
+    #[test]
+    fn oam_entries() {
+        let palette = Palette::builtin();
+        let mut ppu = ppu();
+        // tile 2 with palette 1, flipped both ways
+        ppu.oam[12..16].copy_from_slice(&[20, 2, 0b1100_0001, 30]);
+        let entries = ppu.oam_entries(&palette);
+        assert_eq!(entries.len(), 64);
+        let entry = &entries[3];
+        assert_eq!(
+            entry.to_string(),
+            " 3: x  30 y  20 tile 02 palette 1 flip-h flip-v"
+        );
+        let preview = &entry.preview;
+        assert_eq!((preview.width, preview.height), (8, 8));
+        assert_eq!(get(preview, 7, 7), palette.rgb(0x27));
+        assert_eq!(get(preview, 0, 0), palette.rgb(0x0F));
+
+        // 8x16 tile 3 is tiles 2 and 3 of the second table
+        ppu.reg_write(0, 0x20);
+        ppu.oam[12..16].copy_from_slice(&[20, 3, 0b0010_0001, 30]);
+        let entry = ppu.oam_entry(&palette, 3);
+        assert!(entry.behind_background);
+        assert_eq!((entry.preview.width, entry.preview.height), (8, 16));
+        assert_eq!(get(&entry.preview, 4, 4), palette.rgb(0x0F));
+        assert_eq!(get(&entry.preview, 4, 12), palette.rgb(0x26));
+    }
+
+    #[test]
+    fn oam_entry_at() {
+        let palette = Palette::builtin();
+        let mut ppu = ppu();
+        for (n, byte) in ppu.oam.iter_mut().enumerate() {
+            *byte = (n / 4) as u8;
+        }
+        // 10 pixel wide cells, 20 high
+        let index = |x, y| ppu.oam_entry_at(&palette, x, y).map(|e| e.index);
+        assert_eq!(index(35, 5), Some(3));
+        assert_eq!(index(5, 45), Some(16));
+        assert_eq!(index(79, 159), Some(63));
+        assert_eq!(index(80, 5), None);
+        assert_eq!(index(5, 160), None);
+        assert_eq!(ppu.oam_entry_at(&palette, 35, 5).unwrap().tile, 3);
+    }
+
+    #[test]
+    fn palette_view() {
+        let palette = Palette::builtin();
+        let mut ppu = ppu();
+        ppu.palette[0x11] = 0x2A;
+        let view = ppu.palette_view(&palette);
+        assert_eq!((view.width, view.height), (256, 32));
+        assert_eq!(get(&view, 8, 8), palette.rgb(0x0F));
+        assert_eq!(get(&view, 24, 8), palette.rgb(0x11));
+        assert_eq!(get(&view, 24, 24), palette.rgb(0x2A));
+        // 0x3F10 mirrors the backdrop
+        assert_eq!(get(&view, 8, 24), palette.rgb(0x0F));
+    }
+}
@@ -0,0 +1,248 @@
+use super::debug::DebugImage;
+use super::PPU;
+use std::fmt;
+
+// Per-frame log of register writes and interrupts, placed at the PPU dot
+// they happened on, for a Mesen-style event viewer
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum EventKind {
+    PPU_WRITE,
+    OAM_DMA,
+    APU_WRITE,
+    MAPPER_WRITE,
+    NMI,
+    IRQ,
+    SPRITE0_HIT,
+}
+
+impl EventKind {
+    // Which CPU writes get logged, PRG RAM and controller writes do not
+    pub fn from_write(addr: u16) -> Option<Self> {
+        match addr {
+            0x2000..=0x3FFF => Some(EventKind::PPU_WRITE),
+            0x4014 => Some(EventKind::OAM_DMA),
+            0x4000..=0x4015 | 0x4017 => Some(EventKind::APU_WRITE),
+            0x4020..=0x5FFF | 0x8000..=0xFFFF => Some(EventKind::MAPPER_WRITE),
+            _ => None,
+        }
+    }
+
+    fn color(self) -> (u8, u8, u8) {
+        match self {
+            EventKind::PPU_WRITE => (255, 80, 80),
+            EventKind::OAM_DMA => (255, 160, 0),
+            EventKind::APU_WRITE => (80, 220, 80),
+            EventKind::MAPPER_WRITE => (80, 160, 255),
+            EventKind::NMI => (255, 255, 80),
+            EventKind::IRQ => (255, 80, 255),
+            EventKind::SPRITE0_HIT => (80, 255, 255),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub kind: EventKind,
+    pub scanline: u16,
+    pub cycle: u16,
+    // address of the instruction running at the time
+    pub pc: u16,
+    // register and value written, 0 for interrupts and sprite 0 hit
+    pub addr: u16,
+    pub value: u8,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            EventKind::PPU_WRITE => {
+                let name = PPU_REGISTERS[self.addr as usize % 8];
+                write!(
+                    f,
+                    "{} (${:04X}) = ${:02X}",
+                    name, self.addr, self.value
+                )?
+            }
+            EventKind::OAM_DMA => write!(f, "OAMDMA = ${:02X}", self.value)?,
+            EventKind::APU_WRITE | EventKind::MAPPER_WRITE => {
+                write!(f, "${:04X} = ${:02X}", self.addr, self.value)?
+            }
+            EventKind::NMI => write!(f, "NMI")?,
+            EventKind::IRQ => write!(f, "IRQ")?,
+            EventKind::SPRITE0_HIT => write!(f, "sprite 0 hit")?,
+        }
+        write!(
+            f,
+            " at line {} dot {}, pc ${:04X}",
+            self.scanline, self.cycle, self.pc
+        )
+    }
+}
+
+const PPU_REGISTERS: [&str; 8] = [
+    "PPUCTRL",
+    "PPUMASK",
+    "PPUSTATUS",
+    "OAMADDR",
+    "OAMDATA",
+    "PPUSCROLL",
+    "PPUADDR",
+    "PPUDATA",
+];
+
+// Grid shading: visible picture, blanking, and the rest of the frame
+const VISIBLE_COLOR: (u8, u8, u8) = (56, 56, 56);
+const HBLANK_COLOR: (u8, u8, u8) = (32, 32, 32);
+const VBLANK_COLOR: (u8, u8, u8) = (16, 16, 40);
+
+pub struct EventLog {
+    // logging costs a little per write, so it only runs while viewed
+    pub enabled: bool,
+    // address of the CPU's current instruction, kept up to date by NNES
+    pub pc: u16,
+    current: Vec<Event>,
+    // events of the last finished frame
+    pub frame: Vec<Event>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        EventLog {
+            enabled: false,
+            pc: 0,
+            current: Vec::new(),
+            frame: Vec::new(),
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        std::mem::swap(&mut self.frame, &mut self.current);
+        self.current.clear();
+    }
+
+    // The last frame's event closest to a dot, within a few dots
+    pub fn near(&self, scanline: u16, cycle: u16) -> Option<&Event> {
+        self.frame
+            .iter()
+            .filter(|e| e.scanline.abs_diff(scanline) <= 1)
+            .filter(|e| e.cycle.abs_diff(cycle) <= 2)
+            .min_by_key(|e| {
+                e.scanline.abs_diff(scanline) * 4 + e.cycle.abs_diff(cycle)
+            })
+    }
+}
+
+impl PPU {
+    pub fn log_event(&mut self, kind: EventKind, addr: u16, value: u8) {
+        if !self.events.enabled {
+            return;
+        }
+        let event = Event {
+            kind,
+            scanline: self.scanline,
+            cycle: self.cycle,
+            pc: self.events.pc,
+            addr,
+            value,
+        };
+        self.events.current.push(event);
+    }
+
+    // The last frame as a 341 dot x scanlines grid, one pixel per dot
+    pub fn event_view(&self) -> DebugImage {
+        let lines = self.region.scanlines() as usize;
+        let mut image = DebugImage::new(341, lines);
+        let vblank = self.region.vblank_line() as usize;
+        for y in 0..lines {
+            for x in 0..341 {
+                let color = if (vblank..lines - 1).contains(&y) {
+                    VBLANK_COLOR
+                } else if y < 240 && (1..=256).contains(&x) {
+                    VISIBLE_COLOR
+                } else {
+                    HBLANK_COLOR
+                };
+                image.set(x, y, color);
+            }
+        }
+
+        // 2x2 markers, so single events are easy to spot
+        for event in &self.events.frame {
+            let (x, y) = (event.cycle as usize, event.scanline as usize);
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                if x + dx < 341 && y + dy < lines {
+                    image.set(x + dx, y + dy, event.kind.color());
+                }
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, EventKind, EventLog};
+
+    fn event(kind: EventKind, scanline: u16, cycle: u16) -> Event {
+        Event {
+            kind,
+            scanline,
+            cycle,
+            pc: 0x8000,
+            addr: 0,
+            value: 0,
+        }
+    }
+
+    #[test]
+    fn from_write() {
+        let kind = EventKind::from_write;
+        assert_eq!(kind(0x2000), Some(EventKind::PPU_WRITE));
+        assert_eq!(kind(0x3FFF), Some(EventKind::PPU_WRITE));
+        assert_eq!(kind(0x4014), Some(EventKind::OAM_DMA));
+        assert_eq!(kind(0x4000), Some(EventKind::APU_WRITE));
+        assert_eq!(kind(0x4015), Some(EventKind::APU_WRITE));
+        assert_eq!(kind(0x4017), Some(EventKind::APU_WRITE));
+        assert_eq!(kind(0x4020), Some(EventKind::MAPPER_WRITE));
+        assert_eq!(kind(0x5FFF), Some(EventKind::MAPPER_WRITE));
+        assert_eq!(kind(0x8000), Some(EventKind::MAPPER_WRITE));
+        assert_eq!(kind(0xFFFF), Some(EventKind::MAPPER_WRITE));
+        // RAM, controllers, the test registers and PRG RAM
+        for addr in [0x0000, 0x1FFF, 0x4016, 0x4018, 0x401F, 0x6000, 0x7FFF] {
+            assert_eq!(kind(addr), None, "${:04X}", addr);
+        }
+    }
+
+    #[test]
+    fn near_picks_the_closest_event_in_range() {
+        let mut log = EventLog::new();
+        log.frame = vec![
+            event(EventKind::PPU_WRITE, 100, 50),
+            event(EventKind::APU_WRITE, 101, 52),
+            event(EventKind::NMI, 241, 1),
+        ];
+        let kind = |scanline, cycle| log.near(scanline, cycle).map(|e| e.kind);
+        assert_eq!(kind(100, 50), Some(EventKind::PPU_WRITE));
+        assert_eq!(kind(100, 52), Some(EventKind::PPU_WRITE));
+        // a line away costs more than a dot
+        assert_eq!(kind(101, 51), Some(EventKind::APU_WRITE));
+        assert_eq!(kind(102, 52), Some(EventKind::APU_WRITE));
+        assert_eq!(kind(242, 3), Some(EventKind::NMI));
+        assert_eq!(kind(100, 47), None);
+        assert_eq!(kind(103, 52), None);
+        assert_eq!(kind(243, 1), None);
+    }
+
+    #[test]
+    fn near_only_sees_finished_frames() {
+        let mut log = EventLog::new();
+        log.current.push(event(EventKind::IRQ, 10, 10));
+        assert!(log.near(10, 10).is_none());
+        log.end_frame();
+        assert_eq!(log.near(10, 10).map(|e| e.kind), Some(EventKind::IRQ));
+        log.end_frame();
+        assert!(log.near(10, 10).is_none());
+    }
+}
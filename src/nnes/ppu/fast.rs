@@ -0,0 +1,406 @@
+use super::core::SpritePixel;
+use super::{PPU, PPUCTRL, PPUMASK, PPUSTATUS, VISIBLE_LINES};
+
+// Speed mode: a visible line is rendered in one go at its last dot. The
+// background is decoded a tile at a time into a row, the sprites loaded on
+// the previous line are drawn into another, and sprite evaluation for the
+// next line is a plain pass over OAM. Anything that could change the
+// picture mid-line (a PPU register access, a mapper write, OAM DMA) calls
+// catch_up() first, which runs the dots so far the accurate way and leaves
+// the rest of the line to the dot renderer.
+
+impl PPU {
+    // Whether the line starting now can be rendered at its last dot. The
+    // debug tools and mappers watching fetches need every dot.
+    pub fn can_render_fast(&self) -> bool {
+        self.fast_lines
+            && !self.watches_fetches
+            && !self.sources.enabled
+            && !self.events.enabled
+            && VISIBLE_LINES.contains(&self.scanline)
+            && self.rendering_enabled()
+    }
+
+    // Run the dots of the current line that were skipped so far, then
+    // render the rest of it dot by dot
+    pub fn catch_up(&mut self) {
+        if !self.fast_line {
+            return;
+        }
+        self.fast_line = false;
+        let cycle = self.cycle;
+        for dot in 0..cycle {
+            self.cycle = dot;
+            self.handle_render_lines();
+            self.handle_evaluation_lines();
+        }
+        self.cycle = cycle;
+    }
+
+    // Dots 0-340 of a visible line, leaving the same state behind as the
+    // dot renderer
+    pub fn render_line_fast(&mut self) {
+        let background = self.background_row();
+        let sprites = self.sprite_row();
+        let fine_x = self.x as usize;
+        for x in 0..256 {
+            self.output_pixel(x as u16, background[x + fine_x], sprites[x]);
+        }
+
+        self.evaluate_sprites();
+        self.increment_y();
+        self.copy_x();
+        self.load_sprites();
+
+        // dots 321-336: the next line's first 2 tiles
+        for _ in 0..2 {
+            self.fetch_nametable();
+            self.fetch_attribute();
+            self.fetch_tile_lo();
+            self.fetch_tile_hi();
+            self.increment_x();
+            self.pattern_lo <<= 8;
+            self.pattern_hi <<= 8;
+            self.attribute_lo <<= 8;
+            self.attribute_hi <<= 8;
+            self.store_tiles();
+        }
+    }
+
+    // 4 bit palette indices for the 2 tiles in the shift registers and the
+    // 32 fetched on this line, stepping v along the way. Screen x is at
+    // x + fine x.
+    fn background_row(&mut self) -> [u8; 272] {
+        let mut row = [0; 272];
+        let show = self.ppu_mask.contains(PPUMASK::SHOW_BACKGROUND);
+        if show {
+            for (i, pixel) in row[..16].iter_mut().enumerate() {
+                let bit = 0x8000 >> i;
+                let pattern = ((self.pattern_hi & bit != 0) as u8) << 1
+                    | (self.pattern_lo & bit != 0) as u8;
+                let attribute = ((self.attribute_hi & bit != 0) as u8) << 1
+                    | (self.attribute_lo & bit != 0) as u8;
+                if pattern != 0 {
+                    *pixel = attribute << 2 | pattern;
+                }
+            }
+        }
+        for tile in row[16..].chunks_exact_mut(8) {
+            self.fetch_nametable();
+            self.fetch_attribute();
+            self.fetch_tile_lo();
+            self.fetch_tile_hi();
+            self.increment_x();
+            if !show {
+                continue;
+            }
+            let lo = self.store.tile_lo_byte;
+            let hi = self.store.tile_hi_byte;
+            let attribute = self.store.attribute_byte;
+            for (i, pixel) in tile.iter_mut().enumerate() {
+                let pattern = (hi >> (7 - i) & 1) << 1 | lo >> (7 - i) & 1;
+                if pattern != 0 {
+                    *pixel = attribute << 2 | pattern;
+                }
+            }
+        }
+        row
+    }
+
+    // The sprites loaded for this line, the first opaque one at each x
+    fn sprite_row(&self) -> [SpritePixel; 256] {
+        let mut row = [SpritePixel::default(); 256];
+        if !self.ppu_mask.contains(PPUMASK::SHOW_SPRITES) {
+            return row;
+        }
+        let slots = (0..8)
+            .map(|i| {
+                let sprite = self.sprites[i];
+                let lo = self.sprite_pattern_lo[i];
+                let hi = self.sprite_pattern_hi[i];
+                (sprite, lo, hi, self.sprite_x_counter[i])
+            })
+            .chain((0..self.extra_sprites.len()).map(|i| {
+                let sprite = self.extra_sprites[i];
+                let lo = self.extra_pattern_lo[i];
+                let hi = self.extra_pattern_hi[i];
+                (sprite, lo, hi, self.extra_x_counter[i])
+            }));
+        for (slot, (sprite, lo, hi, x)) in slots.enumerate() {
+            let x = x as usize;
+            for i in 0..8.min(256 - x) {
+                let pattern = (hi >> (7 - i) & 1) << 1 | lo >> (7 - i) & 1;
+                if pattern == 0 || row[x + i].color != 0 {
+                    continue;
+                }
+                let mut pixel =
+                    SpritePixel::new(pattern, sprite.attributes, slot);
+                pixel.zero = slot == 0 && self.sprite_zero_line;
+                row[x + i] = pixel;
+            }
+        }
+        row
+    }
+
+    // Dots 1-256 of sprite evaluation for the next line. Each sprite's y is
+    // written to secondary OAM before its range check, and past 8 sprites
+    // the overflow search steps m along with n.
+    fn evaluate_sprites(&mut self) {
+        let height = if self.ppu_ctrl.contains(PPUCTRL::SPRITE_SIZE) {
+            16
+        } else {
+            8
+        };
+        let scanline = self.scanline;
+        let in_range =
+            |y: u8| scanline >= y as u16 && scanline < y as u16 + height;
+        self.store.sprite_height = height as u8;
+        self.secondary_oam.fill(0xFF);
+
+        let mut found = 0;
+        let mut n = 0;
+        let mut zero = false;
+        while n < 64 && found < 8 {
+            let y = self.oam[4 * n];
+            self.secondary_oam[4 * found] = y;
+            if in_range(y) {
+                self.secondary_oam[4 * found..4 * found + 4]
+                    .copy_from_slice(&self.oam[4 * n..4 * n + 4]);
+                zero |= n == 0;
+                found += 1;
+            }
+            n += 1;
+        }
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam[4 * n + m]) {
+                self.ppu_status.insert(PPUSTATUS::SPRITE_OVERFLOW);
+                break;
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
+        self.store.accepted_sprite = found as u8;
+        self.store.sprite_zero_next = zero;
+    }
+
+    // Dots 257-320: load the sprites found from secondary OAM and fetch
+    // their patterns. The first empty slot reads sprite 63's y, the rest
+    // read 0xFF.
+    fn load_sprites(&mut self) {
+        self.sprite_zero_line = self.store.sprite_zero_next;
+        self.fetch_extra_sprites();
+        let found = self.store.accepted_sprite as usize;
+        for slot in 0..8 {
+            let entry = &self.secondary_oam[4 * slot..4 * slot + 4];
+            let sprite = &mut self.sprites[slot];
+            sprite.y_coordinate = match slot.cmp(&found) {
+                std::cmp::Ordering::Less => entry[0],
+                std::cmp::Ordering::Equal => self.oam[4 * 63],
+                std::cmp::Ordering::Greater => 0xFF,
+            };
+            sprite.tile_number = entry[1];
+            sprite.attributes = entry[2];
+            sprite.x_coordinate = entry[3];
+            self.fetch_sprite_lo(slot);
+            self.fetch_sprite_hi(slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::Cartridge;
+    use crate::nnes::{RamInit, NNES};
+    use crate::region::Region;
+    use std::time::Instant;
+
+    const FRAMES: usize = 8;
+
+    // Fills the nametables from a table and copies a sprite table to OAM,
+    // then every ~11 lines reads PPUSTATUS and writes the scroll and the
+    // clipping bits from what it read. NMI switches between 8x8 and 8x16
+    // sprites every frame.
+    const PROGRAM: [u8; 0x93] = [
+        0x78, // $8000 SEI
+        0xD8, //       CLD
+        0xA2, 0xFF, // LDX #$FF
+        0x9A, //       TXS
+        0x2C, 0x02, 0x20, // $8005 BIT $2002, wait for 2 vblanks
+        0x10, 0xFB, //       BPL $8005
+        0x2C, 0x02, 0x20, // $800A BIT $2002
+        0x10, 0xFB, //       BPL $800A
+        0xA9, 0x3F, //       LDA #$3F, palette from $9000
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA9, 0x00, //       LDA #$00
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA2, 0x00, //       LDX #$00
+        0xBD, 0x00, 0x90, // $801B LDA $9000,X
+        0x8D, 0x07, 0x20, // STA $2007
+        0xE8, //             INX
+        0xE0, 0x20, //       CPX #$20
+        0xD0, 0xF5, //       BNE $801B
+        0xA9, 0x20, //       LDA #$20, nametables from $9100
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA9, 0x00, //       LDA #$00
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA0, 0x08, //       LDY #$08
+        0xA2, 0x00, //       LDX #$00
+        0x8A, //             $8034 TXA
+        0x5D, 0x00, 0x91, // EOR $9100,X
+        0x8D, 0x07, 0x20, // STA $2007
+        0xE8, //             INX
+        0xD0, 0xF6, //       BNE $8034
+        0x88, //             DEY
+        0xD0, 0xF3, //       BNE $8034
+        0xA2, 0x00, //       LDX #$00, sprites from $9200
+        0xBD, 0x00, 0x92, // $8043 LDA $9200,X
+        0x9D, 0x00, 0x02, // STA $0200,X
+        0xE8, //             INX
+        0xD0, 0xF7, //       BNE $8043
+        0xA9, 0x02, //       LDA #$02
+        0x8D, 0x14, 0x40, // STA $4014
+        0xA9, 0x80, //       LDA #$80
+        0x8D, 0x00, 0x20, // STA $2000
+        0xA9, 0x1E, //       LDA #$1E
+        0x8D, 0x01, 0x20, // STA $2001
+        0xA0, 0xFF, //       $805B LDY #$FF
+        0x88, //             $805D DEY
+        0xD0, 0xFD, //       BNE $805D
+        0xAD, 0x02, 0x20, // LDA $2002
+        0x29, 0x60, //       AND #$60, sprite 0 hit and overflow
+        0x65, 0x10, //       ADC $10
+        0x69, 0x03, //       ADC #$03
+        0x85, 0x10, //       STA $10
+        0x8D, 0x05, 0x20, // STA $2005
+        0x8D, 0x05, 0x20, // STA $2005
+        0x29, 0x06, //       AND #$06
+        0x09, 0x18, //       ORA #$18
+        0x8D, 0x01, 0x20, // STA $2001
+        0x4C, 0x5B, 0x80, // JMP $805B
+        0x48, //             $807B PHA, NMI
+        0xA9, 0x02, //       LDA #$02
+        0x8D, 0x14, 0x40, // STA $4014
+        0xE6, 0x11, //       INC $11
+        0xA5, 0x11, //       LDA $11
+        0x0A, 0x0A, 0x0A, 0x0A, 0x0A, // ASL x5
+        0x29, 0x20, //       AND #$20
+        0x09, 0x88, //       ORA #$88
+        0x8D, 0x00, 0x20, // STA $2000
+        0x68, //             PLA
+        0x40, //             RTI
+    ];
+
+    // NROM with PROGRAM, random tables and random CHR
+    fn test_rom() -> Vec<u8> {
+        let mut seed = 0x2C02u32;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        };
+        let mut prg = vec![0; 0x4000];
+        prg[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+        for i in 0..0x20 {
+            prg[0x1000 + i] = random() & 0x3F;
+        }
+        for i in 0..0x100 {
+            prg[0x1100 + i] = random();
+        }
+        for sprite in 0..64 {
+            let i = 0x1200 + sprite * 4;
+            // half the sprites share 40 lines, so those lines overflow
+            let y = random();
+            prg[i] = if sprite < 32 { 50 + y % 40 } else { y };
+            prg[i + 1] = random();
+            prg[i + 2] = random();
+            prg[i + 3] = random();
+        }
+        // NMI, reset and IRQ vectors
+        prg[0x3FFA..].copy_from_slice(&[0x7B, 0x80, 0x00, 0x80, 0x00, 0x80]);
+
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1];
+        rom.resize(16, 0);
+        rom.extend(prg);
+        rom.extend((0..0x2000).map(|_| random()));
+        rom
+    }
+
+    struct Run {
+        frames: Vec<Vec<u16>>,
+        fast_lines: usize,
+        fetches: Option<u32>,
+    }
+
+    // Renders the test ROM, counting the lines rendered fast and, with
+    // watch_fetches, the fetches a mapper watching them saw
+    fn run(
+        frames: usize,
+        fast: bool,
+        unlimited_sprites: bool,
+        watch_fetches: bool,
+    ) -> Run {
+        let mut cartridge = Cartridge::new(test_rom()).unwrap();
+        let fetches = watch_fetches.then(|| cartridge.count_ppu_fetches());
+        let mut nnes = NNES::new(cartridge, Region::NTSC);
+        nnes.power_on(RamInit::ZERO, RamInit::ZERO);
+        nnes.ppu.borrow_mut().fast_lines = fast;
+        nnes.ppu.borrow_mut().unlimited_sprites = unlimited_sprites;
+        let mut fast_lines = 0;
+        let frames = (0..frames)
+            .map(|_| {
+                for _ in 0..nnes.region.master_cycles_per_frame() {
+                    nnes.tick();
+                    let ppu = nnes.ppu.borrow();
+                    fast_lines += (ppu.fast_line && ppu.cycle == 1) as usize;
+                }
+                nnes.ppu.borrow().front.to_vec()
+            })
+            .collect();
+        Run {
+            frames,
+            fast_lines,
+            fetches: fetches.map(|fetches| fetches.get()),
+        }
+    }
+
+    #[test]
+    fn fast_lines_match_dot_rendering() {
+        for unlimited_sprites in [false, true] {
+            let dot = run(FRAMES, false, unlimited_sprites, false);
+            let fast = run(FRAMES, true, unlimited_sprites, false);
+            assert_eq!(dot.fast_lines, 0);
+            assert!(fast.fast_lines > 0);
+            let last = dot.frames.last().unwrap();
+            assert!(last.iter().any(|&color| color != last[0]));
+            for (frame, (dot, fast)) in
+                dot.frames.iter().zip(&fast.frames).enumerate()
+            {
+                assert!(dot == fast, "frame {} differs", frame);
+            }
+        }
+    }
+
+    #[test]
+    fn watched_fetches_fall_back_to_dot_rendering() {
+        let dot = run(FRAMES, false, false, true);
+        let fast = run(FRAMES, true, false, true);
+        assert_eq!(fast.fast_lines, 0);
+        assert!(dot.fetches.unwrap() > 0);
+        assert_eq!(dot.fetches, fast.fetches);
+        assert!(dot.frames == fast.frames);
+    }
+
+    // cargo test --release fast_lines_speed -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn fast_lines_speed() {
+        for fast in [false, true] {
+            let start = Instant::now();
+            run(600, fast, false, false);
+            let fps = 600.0 / start.elapsed().as_secs_f64();
+            println!("fast_lines {}: {:.0} fps", fast, fps);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use super::{PPU, PPUCTRL, PPUMASK, PPUSTATUS};
+use super::{PPU, PPUCTRL, PPUMASK, PPUSTATUS, VISIBLE_LINES};
 use crate::utils::{
     bit_0, bit_1, bit_2, bit_3, bit_4, bit_7, byte_from_bits, hi_byte, lo_byte,
 };
@@ -6,17 +6,10 @@ use crate::utils::{
 impl PPU {
     // 0x2000: PPUCTRL - W
     fn write_ppu_ctrl(&mut self, data: u8) {
-        let prev = self.ppu_ctrl.contains(PPUCTRL::NMI_ON_VBLANK);
+        // setting NMI_ON_VBLANK during VBlank fires an NMI, the PPU's next
+        // dot raises the output and the CPU sees it once NMI_DELAY passes
         self.ppu_ctrl = PPUCTRL::from_bits_truncate(data);
 
-        // fire an NMI if NMI_ON_VBLANK is set during VBlank
-        let now = self.ppu_ctrl.contains(PPUCTRL::NMI_ON_VBLANK)
-            && self.ppu_status.contains(PPUSTATUS::IS_VBLANK);
-        if !prev && now {
-            (self.on_nmi.as_mut())();
-            self.nmi_prev = true;
-        }
-
         // get nametable select from data[1:0]: ... NN ..... .....
         let nametable = (data as u16 & 0b11) << 10;
         // final address: ttt NN ttttt ttttt
@@ -39,7 +32,11 @@ impl PPU {
         let bit_1 = bit_1(self.open_bus);
         let bit_0 = bit_0(self.open_bus);
 
-        // side effects of reading PPUSTATUS
+        // side effects of reading PPUSTATUS, a read the dot before VBlank
+        // starts keeps the flag from being set this frame
+        if self.scanline == self.region.vblank_line() && self.cycle == 1 {
+            self.suppress_vblank = true;
+        }
         self.ppu_status.remove(PPUSTATUS::IS_VBLANK);
         self.w = 0;
         self.nmi_prev = false;
@@ -54,6 +51,28 @@ impl PPU {
 
     // 0x2004: OAMDATA - R
     fn read_oam_data(&mut self) -> u8 {
+        // while rendering, reads see whatever sprite evaluation is
+        // accessing on this dot
+        if self.is_rendering() {
+            return match self.cycle {
+                // secondary OAM clear reads as 0xFF
+                1..=64 => 0xFF,
+                // the primary OAM byte being evaluated
+                65..=256 => {
+                    let idx = 4 * self.store.curr_sprite
+                        + self.store.curr_sprite_byte;
+                    self.oam[idx as usize]
+                }
+                // the secondary OAM byte being fetched, x repeats 4 times
+                257..=320 => {
+                    let slot = (self.cycle - 257) / 8;
+                    let byte = u16::min((self.cycle - 257) % 8, 3);
+                    self.secondary_oam[(4 * slot + byte) as usize]
+                }
+                _ => self.secondary_oam[0],
+            };
+        }
+
         let mut data = self.oam[self.oam_addr as usize];
         if (self.oam_addr & 0b11) == 0b10 {
             data &= 0b11100011;
@@ -114,8 +133,13 @@ impl PPU {
             self.read_buffer = data;
             data = buf;
         } else {
-            // access palette directly
+            // access palette directly, grayscale applies here too
             self.read_buffer = self.mem_read(self.v - 0x1000);
+            if self.ppu_mask.contains(PPUMASK::GRAYSCALE) {
+                data &= 0x30;
+            }
+            // palette entries are 6 bits, the top 2 are open bus
+            data = (data & 0x3F) | (self.open_bus & 0xC0);
         }
         self.increment_v();
         data
@@ -129,18 +153,28 @@ impl PPU {
 
     // Public register APIs
     pub fn reg_read(&mut self, reg: u8) -> u8 {
-        let data = match reg {
-            2 => self.read_ppu_status(),
-            4 => self.read_oam_data(),
-            7 => self.read_ppu_data(),
-            _ => self.open_bus,
+        // reads with side effects, or of flags set mid-line, need the line
+        // rendered up to now
+        if matches!(reg, 2 | 4 | 7) {
+            self.catch_up();
+        }
+        self.decay_open_bus();
+        // bits the read drives, the rest come from open bus
+        let (data, driven) = match reg {
+            2 => (self.read_ppu_status(), 0xE0),
+            4 => (self.read_oam_data(), 0xFF),
+            7 if self.v & 0x3FFF >= 0x3F00 => (self.read_ppu_data(), 0x3F),
+            7 => (self.read_ppu_data(), 0xFF),
+            _ => (self.open_bus, 0),
         };
-        self.open_bus = data;
+        self.refresh_open_bus(data, driven);
         data
     }
 
     pub fn reg_write(&mut self, reg: u8, data: u8) {
+        self.catch_up();
         match reg {
+            0 | 1 | 5 | 6 if self.warming_up => {}
             0 => self.write_ppu_ctrl(data),
             1 => self.write_ppu_mask(data),
             3 => self.write_oam_addr(data),
@@ -150,12 +184,50 @@ impl PPU {
             7 => self.write_ppu_data(data),
             _ => {}
         }
-        self.open_bus = data;
+        self.refresh_open_bus(data, 0xFF);
+    }
+
+    pub fn handle_oam_addr_reset(&mut self) {
+        if self.is_rendering() && (257..=320).contains(&self.cycle) {
+            self.oam_addr = 0;
+        }
     }
 
     // Helpers
+    fn is_rendering(&self) -> bool {
+        self.rendering_enabled()
+            && (VISIBLE_LINES.contains(&self.scanline)
+                || self.scanline == self.pre_render_line())
+    }
+
+    fn decay_open_bus(&mut self) {
+        // ~600 ms worth of dots
+        let decay_dots = (self.region.master_clock_hz()
+            / self.region.ppu_divider() as f64
+            * 0.6) as u64;
+        for bit in 0..8 {
+            if self.total_cycles - self.open_bus_refresh[bit] > decay_dots {
+                self.open_bus &= !(1 << bit);
+            }
+        }
+    }
+
+    fn refresh_open_bus(&mut self, data: u8, driven: u8) {
+        self.open_bus = (self.open_bus & !driven) | (data & driven);
+        for bit in 0..8 {
+            if driven >> bit & 1 == 1 {
+                self.open_bus_refresh[bit] = self.total_cycles;
+            }
+        }
+    }
+
     fn increment_v(&mut self) {
-        if self.ppu_ctrl.contains(PPUCTRL::VRAM_INCREMENT) {
+        // accessing PPUDATA while rendering bumps coarse x and y at once,
+        // like the fetch pipeline does
+        if self.is_rendering() {
+            self.increment_x();
+            self.increment_y();
+        } else if self.ppu_ctrl.contains(PPUCTRL::VRAM_INCREMENT) {
             self.v = self.v.wrapping_add(32);
         } else {
             self.v = self.v.wrapping_add(1);
@@ -0,0 +1,90 @@
+use super::{PPU, PPUCTRL, PPUMASK, PPUSTATUS};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Palette RAM of a console that was just switched on, as captured by blargg
+const POWER_UP_PALETTE: [u8; 0x20] = [
+    0x09, 0x01, 0x00, 0x01, 0x00, 0x02, 0x02, 0x0D, 0x08, 0x10, 0x08, 0x24,
+    0x00, 0x00, 0x04, 0x2C, 0x09, 0x01, 0x34, 0x03, 0x00, 0x04, 0x00, 0x14,
+    0x08, 0x3A, 0x00, 0x02, 0x00, 0x20, 0x2C, 0x08,
+];
+
+// What OAM and palette RAM hold at power-on. Real RAM comes up in a
+// mostly random state, CONSOLE picks the closest known capture: the
+// palette above, and random OAM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum RamInit {
+    CONSOLE,
+    ZERO,
+    FF,
+    RANDOM,
+}
+
+impl RamInit {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "console" => Some(RamInit::CONSOLE),
+            "zero" => Some(RamInit::ZERO),
+            "ff" => Some(RamInit::FF),
+            "random" => Some(RamInit::RANDOM),
+            _ => None,
+        }
+    }
+
+    fn fill(self, ram: &mut [u8], console: Option<&[u8]>) {
+        match (self, console) {
+            (RamInit::CONSOLE, Some(console)) => ram.copy_from_slice(console),
+            (RamInit::ZERO, _) => ram.fill(0),
+            (RamInit::FF, _) => ram.fill(0xFF),
+            (RamInit::CONSOLE | RamInit::RANDOM, _) => {
+                // xorshift, seeded from the clock
+                let mut state = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(1, |d| d.as_nanos() as u64)
+                    | 1;
+                for byte in ram.iter_mut() {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    *byte = state as u8;
+                }
+            }
+        }
+    }
+}
+
+impl PPU {
+    pub fn power_on(&mut self, oam: RamInit, palette: RamInit) {
+        oam.fill(&mut self.oam, None);
+        palette.fill(&mut self.palette, Some(&POWER_UP_PALETTE));
+        // palette RAM is 6 bits wide
+        self.palette.iter_mut().for_each(|entry| *entry &= 0x3F);
+
+        self.ppu_status = PPUSTATUS::empty();
+        self.oam_addr = 0;
+        self.v = 0;
+        self.reset();
+    }
+
+    // The reset button clears the registers below, but leaves OAM, VRAM,
+    // palette RAM, PPUSTATUS, OAMADDR and v alone
+    pub fn reset(&mut self) {
+        self.ppu_ctrl = PPUCTRL::empty();
+        self.ppu_mask = PPUMASK::empty();
+        self.w = 0;
+        self.t = 0;
+        self.x = 0;
+        self.f = 0;
+        self.read_buffer = 0;
+        self.nmi_prev = false;
+        self.nmi_delay = 0;
+        self.suppress_vblank = false;
+        self.fast_line = false;
+
+        // PPUCTRL, PPUMASK, PPUSCROLL and PPUADDR ignore writes until the
+        // PPU reaches the pre-render line, ~29658 CPU cycles on NTSC
+        self.warming_up = true;
+        self.cycle = 0;
+        self.scanline = 0;
+    }
+}
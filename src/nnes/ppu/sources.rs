@@ -0,0 +1,257 @@
+use super::{PPU, PPUMASK};
+
+// Where each pixel of a frame came from: which CHR tile, which pixel of it
+// and which palette, so HD packs can swap in their own artwork. Recording
+// costs a little per pixel, so it only runs while a pack is in use.
+
+// A CHR tile: its offset in CHR ROM / 16, or its 16 bytes when the game
+// draws into CHR RAM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum TileKey {
+    ROM(u32),
+    RAM([u8; 16]),
+}
+
+// One layer of a pixel
+#[derive(Debug, Clone, Copy)]
+pub struct TileSource {
+    pub tile: TileKey,
+    // the tile's 4 palette entries, backdrop first, as 0xBB112233
+    pub palette: u32,
+    // pixel of the CHR tile, with sprite flipping undone
+    pub x: u8,
+    pub y: u8,
+    pub flip_h: bool,
+    pub flip_v: bool,
+    // 0 is transparent
+    pub pattern: u8,
+    // 9 bit color of this layer's pixel
+    pub color: u16,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PixelSource {
+    // 9 bit color
+    pub backdrop: u16,
+    // None while the background is hidden or clipped
+    pub background: Option<TileSource>,
+    // the opaque sprite pixel that won, if any
+    pub sprite: Option<TileSource>,
+    pub sprite_behind: bool,
+}
+
+pub struct SourceFrame {
+    // 256x240
+    pub pixels: Vec<PixelSource>,
+    // scroll at the top of the frame, in the 512x480 nametable space
+    pub scroll: (u16, u16),
+}
+
+impl SourceFrame {
+    fn new() -> Self {
+        SourceFrame {
+            pixels: vec![PixelSource::default(); 256 * 240],
+            scroll: (0, 0),
+        }
+    }
+}
+
+// A tile as fetched, for the pixels drawn from it later
+#[derive(Debug, Clone, Copy)]
+struct FetchedTile {
+    tile: TileKey,
+    // row of the CHR tile
+    row: u8,
+}
+
+const NO_TILE: FetchedTile = FetchedTile {
+    tile: TileKey::ROM(0),
+    row: 0,
+};
+
+pub struct SourceLog {
+    pub enabled: bool,
+    current: SourceFrame,
+    // sources of the last finished frame
+    pub frame: SourceFrame,
+    // the next background tile, then the 2 in the shift registers
+    next_tile: FetchedTile,
+    background: [FetchedTile; 2],
+    // tiles of the fetched sprites, and of the ones past the limit
+    sprites: [FetchedTile; 8],
+    extra: Vec<FetchedTile>,
+}
+
+impl SourceLog {
+    pub fn new() -> Self {
+        SourceLog {
+            enabled: false,
+            current: SourceFrame::new(),
+            frame: SourceFrame::new(),
+            next_tile: NO_TILE,
+            background: [NO_TILE; 2],
+            sprites: [NO_TILE; 8],
+            extra: Vec::new(),
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        std::mem::swap(&mut self.frame, &mut self.current);
+    }
+
+    // Called along with store_tiles()
+    pub fn store_tile(&mut self) {
+        self.background = [self.background[1], self.next_tile];
+    }
+}
+
+impl PPU {
+    // Called after fetch_tile_hi()
+    pub fn fetch_tile_source(&mut self) {
+        if self.sources.enabled {
+            self.sources.next_tile = self.fetched_tile(self.store.tile_addr);
+        }
+    }
+
+    // Called after fetch_sprite_lo(), or with slot 8+ for the sprites past
+    // the limit, in order
+    pub fn fetch_sprite_source(&mut self, slot: usize, addr: u16) {
+        if !self.sources.enabled {
+            return;
+        }
+        let fetched = self.fetched_tile(addr);
+        if slot < 8 {
+            self.sources.sprites[slot] = fetched;
+        } else {
+            self.sources.extra.truncate(slot - 8);
+            self.sources.extra.push(fetched);
+        }
+    }
+
+    // sprite is the slot (8+ past the limit) and pattern of the sprite
+    // pixel drawn, after clipping
+    pub fn record_source(
+        &mut self,
+        x: u16,
+        show_background: bool,
+        sprite: Option<(usize, u8)>,
+        sprite_behind: bool,
+    ) {
+        let idx = (self.scanline * 256 + x) as usize;
+        if idx == 0 {
+            self.sources.current.scroll = self.frame_scroll();
+        }
+
+        let background = show_background.then(|| {
+            let bit_mux = 0x8000 >> self.x;
+            let bit = |register: u16| (register & bit_mux != 0) as u8;
+            let pattern = bit(self.pattern_hi) << 1 | bit(self.pattern_lo);
+            let group = bit(self.attribute_hi) << 1 | bit(self.attribute_lo);
+            // the shift registers hold 2 tiles, and have shifted once per
+            // dot since the last reload
+            let column = self.x as u16 + (self.cycle - 1) % 8;
+            let fetched = self.sources.background[column as usize / 8];
+            self.tile_source(fetched, group, pattern, column as u8 % 8)
+        });
+
+        let sprite = sprite.map(|(slot, pattern)| {
+            let (sprite, fetched) = if slot < 8 {
+                (self.sprites[slot], self.sources.sprites[slot])
+            } else {
+                (self.extra_sprites[slot - 8], self.sources.extra[slot - 8])
+            };
+            let attributes = sprite.attributes;
+            let column = x.wrapping_sub(sprite.x_coordinate as u16) as u8 & 7;
+            let flip_h = attributes & 0b0100_0000 != 0;
+            let column = if flip_h { 7 - column } else { column };
+            let mut source = self.tile_source(
+                fetched,
+                4 + (attributes & 0b11),
+                pattern,
+                column,
+            );
+            source.flip_h = flip_h;
+            source.flip_v = attributes & 0b1000_0000 != 0;
+            source
+        });
+
+        self.sources.current.pixels[idx] = PixelSource {
+            backdrop: self.output_color(self.palette[0] & 0x3F),
+            background,
+            sprite,
+            sprite_behind,
+        };
+    }
+
+    // With rendering off only the backdrop shows
+    pub fn record_backdrop_source(&mut self, x: u16, color: u16) {
+        let idx = (self.scanline * 256 + x) as usize;
+        if idx == 0 {
+            self.sources.current.scroll = self.frame_scroll();
+        }
+        self.sources.current.pixels[idx] = PixelSource {
+            backdrop: color,
+            ..Default::default()
+        };
+    }
+
+    // Helpers
+    fn fetched_tile(&self, addr: u16) -> FetchedTile {
+        let tile = addr & !0xF;
+        let cartridge = self.cartridge.borrow();
+        let key = match cartridge.chr_offset(tile) {
+            Some(offset) if !cartridge.has_chr_ram() => {
+                TileKey::ROM((offset / 16) as u32)
+            }
+            _ => {
+                let mut data = [0; 16];
+                for (i, byte) in data.iter_mut().enumerate() {
+                    *byte = cartridge.ppu_read(tile + i as u16, &self.vram);
+                }
+                TileKey::RAM(data)
+            }
+        };
+        FetchedTile {
+            tile: key,
+            row: (addr & 7) as u8,
+        }
+    }
+
+    fn tile_source(
+        &self,
+        fetched: FetchedTile,
+        group: u8,
+        pattern: u8,
+        column: u8,
+    ) -> TileSource {
+        // entry 0 of every group shows the backdrop
+        let entry = |i: u8| {
+            let addr = match i {
+                0 => 0,
+                _ => self.get_palette_addr((group << 2 | i) as u16),
+            };
+            self.palette[addr as usize] & 0x3F
+        };
+        let palette =
+            u32::from_be_bytes([entry(0), entry(1), entry(2), entry(3)]);
+        TileSource {
+            tile: fetched.tile,
+            palette,
+            x: column,
+            y: fetched.row,
+            flip_h: false,
+            flip_v: false,
+            pattern,
+            color: self.output_color(entry(pattern)),
+        }
+    }
+
+    // the frame's starting scroll, none while the background is hidden
+    fn frame_scroll(&self) -> (u16, u16) {
+        if !self.ppu_mask.contains(PPUMASK::SHOW_BACKGROUND) {
+            return (0, 0);
+        }
+        self.start_scroll
+    }
+}
@@ -1,14 +1,10 @@
-// THIS FILE IS GENERATED AT BUILD TIME. ANY CHANGES MADE WILL BE LOST.
+pub mod ntsc;
 
-// THIS PROJECT CURRENTLY USES base.pal FROM
-// https://en.wikipedia.org/wiki/List_of_video_game_console_palettes.
-
-// TO CHANGE THE PALETTE (e.g., "test.pal"),
-// 1. COPY test.pal INTO /palettes/
-// 2. EDIT /scripts/load-palette.py TO SET `palette = "test"`
-
-// TODO: MAKE PALETTE SWAPPING EASIER.
+pub use ntsc::NtscSettings;
+use std::{fs, io, path::Path};
 
+// Built in 2C02 palette, base.pal from
+// https://en.wikipedia.org/wiki/List_of_video_game_console_palettes
 pub const NES_PALETTE: [(u8, u8, u8); 64] = [
     (97, 97, 97),
     (0, 0, 136),
@@ -75,3 +71,153 @@ pub const NES_PALETTE: [(u8, u8, u8); 64] = [
     (0, 0, 0),
     (0, 0, 0),
 ];
+
+// Each emphasis bit darkens the two channels it does not name. The NTSC
+// generator applies it to the signal during each emphasized channel's half
+// of the wave.
+pub(crate) const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+// RGB for all 512 PPU output colors: PPUMASK[7:5] emphasis on top of the
+// 6 bit palette index
+pub struct Palette {
+    pub name: String,
+    colors: Vec<(u8, u8, u8)>,
+}
+
+impl Palette {
+    pub fn builtin() -> Self {
+        Palette {
+            name: "built-in".to_string(),
+            colors: with_emphasis(&NES_PALETTE),
+        }
+    }
+
+    // .pal files hold 64 colors (192 B), or all 8 emphasis sets (1536 B)
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| {
+            format!("error: could not read {}: {}", path.display(), e)
+        })?;
+        let colors: Vec<(u8, u8, u8)> = data
+            .chunks_exact(3)
+            .map(|rgb| (rgb[0], rgb[1], rgb[2]))
+            .collect();
+        let colors = match data.len() {
+            192 => with_emphasis(&colors),
+            1536 => colors,
+            len => {
+                return Err(format!(
+                    "error: {} is {} B, expected 192 or 1536",
+                    path.display(),
+                    len
+                ))
+            }
+        };
+        Ok(Palette {
+            name: path.display().to_string(),
+            colors,
+        })
+    }
+
+    // Modeled on the 2C02's composite signal instead of a fixed table
+    pub fn generate(settings: &NtscSettings) -> Self {
+        Palette {
+            name: format!("generated {:?}", settings),
+            colors: ntsc::generate(settings),
+        }
+    }
+
+    // Always writes all 512 colors (1536 B)
+    pub fn export(&self, path: &Path) -> io::Result<()> {
+        let data: Vec<u8> = self
+            .colors
+            .iter()
+            .flat_map(|&(r, g, b)| [r, g, b])
+            .collect();
+        fs::write(path, data)
+    }
+
+    pub fn rgb(&self, color: u16) -> (u8, u8, u8) {
+        self.colors[color as usize & 0x1FF]
+    }
+}
+
+// Helpers
+fn with_emphasis(base: &[(u8, u8, u8)]) -> Vec<(u8, u8, u8)> {
+    let mut colors = Vec::with_capacity(512);
+    for emphasis in 0..8u8 {
+        // emphasis[0] is red, [1] green and [2] blue
+        let scale = |channel: u8| {
+            let others = emphasis & !(1 << channel);
+            EMPHASIS_ATTENUATION.powi(others.count_ones() as i32)
+        };
+        for &(r, g, b) in base {
+            colors.push((
+                (r as f32 * scale(0)).round() as u8,
+                (g as f32 * scale(1)).round() as u8,
+                (b as f32 * scale(2)).round() as u8,
+            ));
+        }
+    }
+    colors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Palette;
+    use std::{env, fs, path::PathBuf};
+
+    fn write_pal(name: &str, data: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "nnes-{}-{}.pal",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_64_colors_adds_emphasis() {
+        let path = write_pal("64", &[200; 192]);
+        let palette = Palette::load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(palette.colors.len(), 512);
+        assert_eq!(palette.rgb(0x00), (200, 200, 200));
+        // red emphasis darkens green and blue
+        assert_eq!(palette.rgb(0x40), (200, 149, 149));
+        // all 3 bits darken every channel twice
+        assert_eq!(palette.rgb(0x1C0), (111, 111, 111));
+    }
+
+    #[test]
+    fn load_512_colors_as_is() {
+        let data: Vec<u8> = (0..1536).map(|i| (i % 251) as u8).collect();
+        let path = write_pal("512", &data);
+        let palette = Palette::load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(palette.rgb(0x000), (0, 1, 2));
+        assert_eq!(palette.rgb(0x1FF), (27, 28, 29));
+    }
+
+    #[test]
+    fn load_rejects_other_sizes() {
+        for len in [0, 191, 193, 1535, 1537] {
+            let path = write_pal(&format!("bad{}", len), &vec![0; len]);
+            let error = Palette::load(&path).err().unwrap();
+            fs::remove_file(path).unwrap();
+            assert!(
+                error
+                    .ends_with(&format!("is {} B, expected 192 or 1536", len)),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn load_reports_missing_files() {
+        let path = env::temp_dir().join("nnes-missing.pal");
+        let error = Palette::load(&path).err().unwrap();
+        assert!(error.starts_with("error: could not read"));
+    }
+}
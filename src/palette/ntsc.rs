@@ -0,0 +1,187 @@
+use super::EMPHASIS_ATTENUATION;
+use std::f32::consts::PI;
+
+// 2C02 composite output, relative to sync. Luma 0-3 picks one of the
+// low/high pairs, the color's hue decides when the square wave is high.
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+
+#[derive(Debug, Clone, Copy)]
+pub struct NtscSettings {
+    // degrees added to every hue
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    // of the TV being matched, 2.2 leaves the decoded levels as they are
+    pub gamma: f32,
+}
+
+impl Default for NtscSettings {
+    fn default() -> Self {
+        NtscSettings {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+// Decode all 512 colors: sample one color burst cycle (12 PPU half-clocks)
+// of each square wave and demodulate it as YIQ
+pub fn generate(settings: &NtscSettings) -> Vec<(u8, u8, u8)> {
+    (0..512u16).map(|color| decode(color, settings)).collect()
+}
+
+// Level of the composite signal for a 9 bit color, normalized so black is 0
+// and white is 1. The phase counts PPU half-clocks, 12 per color cycle.
+pub fn signal(color: u16, phase: u16) -> f32 {
+    let hue = color & 0xF;
+    let emphasis = color >> 6;
+    // 0xE and 0xF are always black
+    let luma = if hue < 0xE {
+        (color >> 4) as usize & 0b11
+    } else {
+        1
+    };
+
+    // hue 0 stays high, 0xD-0xF stay low
+    let high = match hue {
+        0 => true,
+        0xD..=0xF => false,
+        _ => in_phase(hue, phase),
+    };
+    let mut level = if high {
+        SIGNAL_HIGH[luma]
+    } else {
+        SIGNAL_LOW[luma]
+    };
+
+    // emphasis[0] (red) lines up with hue 0xC, green with 4, blue with 8
+    if (emphasis & 0b001 != 0 && in_phase(0xC, phase))
+        || (emphasis & 0b010 != 0 && in_phase(0x4, phase))
+        || (emphasis & 0b100 != 0 && in_phase(0x8, phase))
+    {
+        level *= EMPHASIS_ATTENUATION;
+    }
+    (level - BLACK) / (WHITE - BLACK)
+}
+
+// (cos, sin) of the color subcarrier the TV demodulates against
+pub fn carrier(phase: u16, settings: &NtscSettings) -> (f32, f32) {
+    let angle = PI / 6.0 * (phase as f32 + settings.hue / 30.0);
+    (angle.cos(), angle.sin())
+}
+
+// Demodulated YIQ, before the picture controls, to RGB
+pub fn yiq_to_rgb(
+    y: f32,
+    i: f32,
+    q: f32,
+    settings: &NtscSettings,
+) -> (u8, u8, u8) {
+    let y = y * settings.contrast + settings.brightness;
+    let i = i * settings.saturation;
+    let q = q * settings.saturation;
+
+    // FCC YIQ to RGB
+    let to_byte = |c: f32| {
+        let c = f32::max(0.0, c).powf(2.2 / settings.gamma);
+        (c * 255.0).round().clamp(0.0, 255.0) as u8
+    };
+    (
+        to_byte(y + 0.946882 * i + 0.623557 * q),
+        to_byte(y - 0.274788 * i - 0.635691 * q),
+        to_byte(y - 1.108545 * i + 1.709007 * q),
+    )
+}
+
+// Helpers
+fn in_phase(hue: u16, phase: u16) -> bool {
+    (hue + phase + 8) % 12 < 6
+}
+
+fn decode(color: u16, settings: &NtscSettings) -> (u8, u8, u8) {
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let v = signal(color, phase) / 12.0;
+        let (cos, sin) = carrier(phase, settings);
+        y += v;
+        // x2 recovers the full chroma amplitude
+        i += 2.0 * v * cos;
+        q += 2.0 * v * sin;
+    }
+    yiq_to_rgb(y, i, q, settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, yiq_to_rgb, NtscSettings};
+
+    #[test]
+    fn gray_ramp_and_blacks() {
+        let colors = generate(&NtscSettings::default());
+        assert_eq!(colors.len(), 512);
+        assert_eq!(colors[0x00], (102, 102, 102));
+        assert_eq!(colors[0x10], (174, 174, 174));
+        assert_eq!(colors[0x20], (255, 255, 255));
+        assert_eq!(colors[0x30], (255, 255, 255));
+        assert_eq!(colors[0x2D], (78, 78, 78));
+        assert_eq!(colors[0x3D], (182, 182, 182));
+        // 0x0D is blacker than black, 0xE and 0xF are always black
+        for color in [0x0D, 0x1D, 0x0E, 0x1E, 0x0F, 0x3F] {
+            assert_eq!(colors[color], (0, 0, 0), "{:02X}", color);
+        }
+    }
+
+    #[test]
+    fn hues_and_emphasis() {
+        let colors = generate(&NtscSettings::default());
+        assert_eq!(colors[0x12], (62, 59, 255));
+        assert_eq!(colors[0x16], (208, 39, 17));
+        assert_eq!(colors[0x1A], (0, 164, 0));
+        // emphasis tints white toward the bits left out, all 3 darken it
+        assert_eq!(colors[0x060], (255, 187, 175));
+        assert_eq!(colors[0x0A0], (163, 250, 134));
+        assert_eq!(colors[0x120], (198, 197, 255));
+        assert_eq!(colors[0x1E0], (167, 167, 167));
+        assert_eq!(colors[0x1C0], (53, 53, 53));
+
+        // 4 hue steps of 30 degrees turn red into blue
+        let settings = NtscSettings {
+            hue: 120.0,
+            ..NtscSettings::default()
+        };
+        assert_eq!(generate(&settings)[0x16], colors[0x12]);
+    }
+
+    #[test]
+    fn yiq_to_rgb_picture_controls() {
+        let default = NtscSettings::default();
+        assert_eq!(yiq_to_rgb(0.5, 0.0, 0.0, &default), (128, 128, 128));
+        assert_eq!(yiq_to_rgb(0.5, 0.2, 0.0, &default), (176, 113, 71));
+        assert_eq!(yiq_to_rgb(0.5, 0.0, 0.2, &default), (159, 95, 215));
+
+        let gray = NtscSettings {
+            saturation: 0.0,
+            ..default
+        };
+        assert_eq!(yiq_to_rgb(0.5, 0.2, 0.0, &gray), (128, 128, 128));
+        let dim = NtscSettings {
+            contrast: 0.5,
+            brightness: 0.25,
+            ..default
+        };
+        assert_eq!(yiq_to_rgb(0.5, 0.0, 0.0, &dim), (128, 128, 128));
+        // a gamma 1.0 TV gets the levels a 2.2 one would show
+        let linear = NtscSettings {
+            gamma: 1.0,
+            ..default
+        };
+        assert_eq!(yiq_to_rgb(0.5, 0.0, 0.0, &linear), (55, 55, 55));
+    }
+}
@@ -0,0 +1,208 @@
+// TV system the console is built for. PAL and Dendy consoles run a slower
+// CPU against a faster PPU, with 312 scanlines per frame. The APU's noise
+// and DMC periods and frame counter steps differ by region too.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Region {
+    NTSC,
+    PAL,
+    // Famiclone timing: PAL's clocks and frame, but vblank starts late so
+    // the NMI lands 20 lines before the pre-render line, as on NTSC
+    DENDY,
+}
+
+impl Region {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ntsc" => Some(Region::NTSC),
+            "pal" => Some(Region::PAL),
+            "dendy" => Some(Region::DENDY),
+            _ => None,
+        }
+    }
+
+    // Master clock cycles per CPU cycle
+    pub fn cpu_divider(self) -> u64 {
+        match self {
+            Region::NTSC => 12,
+            Region::PAL => 16,
+            Region::DENDY => 15,
+        }
+    }
+
+    // Master clock cycles per PPU dot
+    pub fn ppu_divider(self) -> u64 {
+        match self {
+            Region::NTSC => 4,
+            Region::PAL | Region::DENDY => 5,
+        }
+    }
+
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::NTSC => 262,
+            Region::PAL | Region::DENDY => 312,
+        }
+    }
+
+    pub fn vblank_line(self) -> u16 {
+        match self {
+            Region::NTSC | Region::PAL => 241,
+            Region::DENDY => 291,
+        }
+    }
+
+    // Only NTSC drops a dot on odd frames
+    pub fn skips_odd_frame_dot(self) -> bool {
+        self == Region::NTSC
+    }
+
+    pub fn master_clock_hz(self) -> f64 {
+        match self {
+            Region::NTSC => 236.25e6 / 11.0,
+            Region::PAL | Region::DENDY => 26.601712e6,
+        }
+    }
+
+    // Master clock cycles per frame, averaged over the odd frame skip
+    pub fn master_cycles_per_frame(self) -> u64 {
+        let dots = self.scanlines() as f64 * 341.0
+            - if self.skips_odd_frame_dot() { 0.5 } else { 0.0 };
+        (dots * self.ppu_divider() as f64).round() as u64
+    }
+
+    // 60.0988 Hz NTSC, 50.0070 Hz PAL and Dendy
+    pub fn frame_rate(self) -> f64 {
+        self.master_clock_hz() / self.master_cycles_per_frame() as f64
+    }
+
+    // Noise timer periods in CPU cycles, indexed by $400E[3:0]. Dendy's APU
+    // uses the NTSC tables.
+    pub fn noise_periods(self) -> [u16; 16] {
+        match self {
+            Region::NTSC | Region::DENDY => [
+                4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016,
+                2034, 4068,
+            ],
+            Region::PAL => [
+                4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944,
+                1890, 3778,
+            ],
+        }
+    }
+
+    // DMC output periods in CPU cycles, indexed by $4010[3:0]
+    pub fn dmc_rates(self) -> [u16; 16] {
+        match self {
+            Region::NTSC | Region::DENDY => [
+                428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128,
+                106, 84, 72, 54,
+            ],
+            Region::PAL => [
+                398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118,
+                98, 78, 66, 50,
+            ],
+        }
+    }
+
+    // CPU cycles after a frame counter reset at which it steps. 4-step mode
+    // uses the first 4 and sets the frame IRQ on the last, 5-step mode skips
+    // the 4th.
+    pub fn frame_counter_steps(self) -> [u32; 5] {
+        match self {
+            Region::NTSC | Region::DENDY => [7457, 14913, 22371, 29829, 37281],
+            Region::PAL => [8313, 16627, 24939, 33253, 41565],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Region;
+
+    #[test]
+    fn dots_per_cpu_cycle() {
+        let ratio = |region: Region| {
+            region.cpu_divider() as f64 / region.ppu_divider() as f64
+        };
+        assert_eq!(ratio(Region::NTSC), 3.0);
+        assert_eq!(ratio(Region::PAL), 3.2);
+        assert_eq!(ratio(Region::DENDY), 3.0);
+    }
+
+    #[test]
+    fn master_cycles_per_frame() {
+        // 341 dots per line, NTSC drops half a dot on average
+        assert_eq!(Region::NTSC.master_cycles_per_frame(), 357_366);
+        assert_eq!(Region::PAL.master_cycles_per_frame(), 531_960);
+        assert_eq!(Region::DENDY.master_cycles_per_frame(), 531_960);
+    }
+
+    #[test]
+    fn frame_rate() {
+        let close = |a: f64, b: f64| (a - b).abs() < 0.0001;
+        assert!(close(Region::NTSC.frame_rate(), 60.0988));
+        assert!(close(Region::PAL.frame_rate(), 50.0070));
+        assert!(close(Region::DENDY.frame_rate(), 50.0070));
+    }
+
+    #[test]
+    fn vblank_lines_before_pre_render() {
+        let lines =
+            |region: Region| region.scanlines() - 1 - region.vblank_line();
+        assert_eq!(lines(Region::NTSC), 20);
+        assert_eq!(lines(Region::PAL), 70);
+        // Dendy keeps NTSC's NMI to pre-render distance
+        assert_eq!(lines(Region::DENDY), 20);
+    }
+
+    #[test]
+    fn noise_and_dmc_periods() {
+        assert_eq!(Region::NTSC.noise_periods()[15], 4068);
+        assert_eq!(Region::PAL.noise_periods()[15], 3778);
+        assert_eq!(Region::NTSC.dmc_rates()[0], 428);
+        assert_eq!(Region::PAL.dmc_rates()[0], 398);
+        for region in [Region::NTSC, Region::PAL, Region::DENDY] {
+            let noise = region.noise_periods();
+            assert!(noise.windows(2).all(|pair| pair[0] < pair[1]));
+            let dmc = region.dmc_rates();
+            assert!(dmc.windows(2).all(|pair| pair[0] > pair[1]));
+        }
+        assert_eq!(
+            Region::DENDY.noise_periods(),
+            Region::NTSC.noise_periods()
+        );
+        assert_eq!(Region::DENDY.dmc_rates(), Region::NTSC.dmc_rates());
+    }
+
+    #[test]
+    fn frame_counter_steps() {
+        // quarter frames at ~240 Hz on NTSC and ~200 Hz on PAL, with the
+        // 4-step sequence evenly spaced to within a cycle
+        let quarter_frame_hz = |region: Region| {
+            let cpu_hz =
+                region.master_clock_hz() / region.cpu_divider() as f64;
+            cpu_hz / region.frame_counter_steps()[0] as f64
+        };
+        assert!((quarter_frame_hz(Region::NTSC) - 240.0).abs() < 0.1);
+        assert!((quarter_frame_hz(Region::PAL) - 200.0).abs() < 0.1);
+        for region in [Region::NTSC, Region::PAL] {
+            let steps = region.frame_counter_steps();
+            for pair in steps[..4].windows(2) {
+                assert!(pair[1].abs_diff(pair[0]).abs_diff(steps[0]) <= 1);
+            }
+        }
+        assert_eq!(
+            Region::DENDY.frame_counter_steps(),
+            Region::NTSC.frame_counter_steps()
+        );
+    }
+
+    #[test]
+    fn from_name() {
+        assert_eq!(Region::from_name("ntsc"), Some(Region::NTSC));
+        assert_eq!(Region::from_name("pal"), Some(Region::PAL));
+        assert_eq!(Region::from_name("dendy"), Some(Region::DENDY));
+        assert_eq!(Region::from_name("secam"), None);
+    }
+}
@@ -0,0 +1,155 @@
+mod blend;
+mod crt;
+mod ntsc;
+mod scale;
+mod upscale;
+
+use crate::palette::{NtscSettings, Palette};
+pub use blend::{BlendMode, FrameBlend};
+pub use crt::{Crt, CrtMask, CrtSettings};
+pub use ntsc::{NtscFilter, NtscPreset, OUTPUT_WIDTH as NTSC_WIDTH};
+pub use scale::{Overscan, PixelAspect, ScaleMode};
+pub use upscale::Upscaler;
+
+// A finished frame and where in the window it goes
+pub struct Picture<'a> {
+    // RGB24
+    pub data: &'a [u8],
+    pub width: usize,
+    pub height: usize,
+    // (x, y, w, h)
+    pub rect: (i32, i32, u32, u32),
+}
+
+// Everything between the PPU framebuffer and the SDL texture:
+// palette lookup or NTSC filter -> frame blending -> overscan crop ->
+// CRT or upscaler
+pub struct Video {
+    pub filter: Option<NtscPreset>,
+    pub blend: FrameBlend,
+    pub crt_enabled: bool,
+    pub upscaler: Upscaler,
+    pub scale_mode: ScaleMode,
+    pub aspect: PixelAspect,
+    pub overscan: Overscan,
+
+    ntsc: NtscFilter,
+    crt: Crt,
+    rgb: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl Video {
+    pub fn new(ntsc: NtscSettings, crt: CrtSettings) -> Self {
+        Video {
+            filter: None,
+            blend: FrameBlend::new(BlendMode::OFF, 0.5),
+            crt_enabled: false,
+            upscaler: Upscaler::NEAREST,
+            scale_mode: ScaleMode::ASPECT,
+            aspect: PixelAspect::SQUARE,
+            overscan: Overscan::default(),
+
+            ntsc: NtscFilter::new(ntsc),
+            crt: Crt::new(crt),
+            rgb: vec![0; NTSC_WIDTH * 240 * 3],
+            output: Vec::new(),
+        }
+    }
+
+    // frame is the PPU's 256x240 9 bit colors
+    pub fn draw(
+        &mut self,
+        frame: &[u16],
+        frame_phase: u8,
+        palette: &Palette,
+        window: (u32, u32),
+    ) -> Picture<'_> {
+        // output pixels per NES pixel, horizontally
+        let x_scale = match self.filter {
+            Some(preset) => {
+                self.ntsc.apply(
+                    preset,
+                    frame,
+                    frame_phase,
+                    &mut self.rgb,
+                    NTSC_WIDTH * 3,
+                );
+                NTSC_WIDTH / 256
+            }
+            None => {
+                render(frame, palette, &mut self.rgb);
+                1
+            }
+        };
+
+        // blend whole frames, before any cropping, so the history stays
+        // valid when the overscan changes
+        self.blend.apply(&mut self.rgb[..256 * x_scale * 240 * 3]);
+
+        let (w, h) = self.overscan.size();
+        let cropped = self.overscan.crop(&self.rgb, x_scale, 1);
+        let src_w = w * x_scale;
+        let rect = scale::fit(self.scale_mode, self.aspect, (w, h), window);
+
+        if self.crt_enabled {
+            // the CRT draws at the final size itself
+            let out_w = usize::max(1, rect.2 as usize);
+            let out_h = usize::max(1, rect.3 as usize);
+            self.output.resize(out_w * out_h * 3, 0);
+            self.crt.apply(
+                &cropped,
+                (src_w, h),
+                &mut self.output,
+                (out_w, out_h),
+                out_w * 3,
+            );
+            Picture {
+                data: &self.output,
+                width: out_w,
+                height: out_h,
+                rect,
+            }
+        } else {
+            let n = self.upscaler.factor();
+            self.output = self.upscaler.apply(&cropped, src_w, h);
+            Picture {
+                data: &self.output,
+                width: src_w * n,
+                height: h * n,
+                rect,
+            }
+        }
+    }
+
+    // An HD pack frame, already scale times the NES size. The filters,
+    // blending and upscalers work on NES pixels, so only the crop and fit
+    // apply.
+    pub fn draw_hd(
+        &mut self,
+        rgb: &[u8],
+        scale: usize,
+        window: (u32, u32),
+    ) -> Picture<'_> {
+        let (w, h) = self.overscan.size();
+        let rect = scale::fit(self.scale_mode, self.aspect, (w, h), window);
+        self.output = self.overscan.crop(rgb, scale, scale);
+        Picture {
+            data: &self.output,
+            width: w * scale,
+            height: h * scale,
+            rect,
+        }
+    }
+}
+
+// Plain palette lookup of a 256x240 frame into RGB24
+pub fn render(frame: &[u16], palette: &Palette, buffer: &mut [u8]) {
+    for (i, &color) in frame.iter().enumerate() {
+        let (r, g, b) = palette.rgb(color);
+        let base = i * 3;
+        buffer[base] = r;
+        buffer[base + 1] = g;
+        buffer[base + 2] = b;
+    }
+}
@@ -0,0 +1,182 @@
+// Blends each frame with the ones before it, so sprites flickered at 30 Hz
+// show up steadily instead of strobing
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum BlendMode {
+    OFF,
+    // mix with the previous frame only: weight 0.5 turns 30 Hz flicker
+    // into a steady 50% blend
+    MIX,
+    // ghost like a slow LCD: every pixel moves part of the way to its new
+    // color each frame, a little faster when brightening than dimming
+    LCD,
+}
+
+impl BlendMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(BlendMode::OFF),
+            "mix" => Some(BlendMode::MIX),
+            "lcd" => Some(BlendMode::LCD),
+            _ => None,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            BlendMode::OFF => BlendMode::MIX,
+            BlendMode::MIX => BlendMode::LCD,
+            BlendMode::LCD => BlendMode::OFF,
+        }
+    }
+}
+
+// How much faster LCD pixels rise than fall
+const LCD_RISE_BOOST: f32 = 1.5;
+// Slowest LCD response, so weight 1 still lets pixels settle
+const LCD_MIN_RESPONSE: f32 = 0.05;
+
+pub struct FrameBlend {
+    pub mode: BlendMode,
+    // share of the previous frame (MIX) or of the old color (LCD), 0-1
+    pub weight: f32,
+    // the last frame as the PPU drew it, and as it was shown
+    prev_input: Vec<u8>,
+    prev_output: Vec<f32>,
+}
+
+impl FrameBlend {
+    pub fn new(mode: BlendMode, weight: f32) -> Self {
+        FrameBlend {
+            mode,
+            weight: weight.clamp(0.0, 1.0),
+            prev_input: Vec::new(),
+            prev_output: Vec::new(),
+        }
+    }
+
+    // Blends an RGB24 frame in place
+    pub fn apply(&mut self, frame: &mut [u8]) {
+        if self.mode == BlendMode::OFF {
+            self.prev_input.clear();
+            self.prev_output.clear();
+            return;
+        }
+        // nothing to blend with after a size change (e.g. NTSC filter
+        // toggled), start over from this frame
+        if self.prev_input.len() != frame.len() {
+            self.prev_input = frame.to_vec();
+            self.prev_output = frame.iter().map(|&c| c as f32).collect();
+            return;
+        }
+
+        let w = self.weight;
+        for ((c, prev_in), prev_out) in frame
+            .iter_mut()
+            .zip(self.prev_input.iter_mut())
+            .zip(self.prev_output.iter_mut())
+        {
+            let current = *c as f32;
+            let out = match self.mode {
+                BlendMode::MIX => current * (1.0 - w) + *prev_in as f32 * w,
+                BlendMode::LCD => {
+                    let response = if current > *prev_out {
+                        f32::min(1.0, (1.0 - w) * LCD_RISE_BOOST)
+                    } else {
+                        1.0 - w
+                    };
+                    let response = f32::max(LCD_MIN_RESPONSE, response);
+                    *prev_out + (current - *prev_out) * response
+                }
+                BlendMode::OFF => unreachable!(),
+            };
+            *prev_in = *c;
+            *prev_out = out;
+            *c = out.round() as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlendMode, FrameBlend};
+
+    // the outputs for a run of flat frames
+    fn run(blend: &mut FrameBlend, levels: &[u8]) -> Vec<u8> {
+        levels
+            .iter()
+            .map(|&level| {
+                let mut frame = [level; 6];
+                blend.apply(&mut frame);
+                assert!(frame.iter().all(|&c| c == frame[0]));
+                frame[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mix_weights() {
+        let flicker = [0, 200, 0, 200, 0];
+        let mut blend = FrameBlend::new(BlendMode::MIX, 0.0);
+        assert_eq!(run(&mut blend, &flicker), flicker);
+        // 30 Hz flicker turns into a steady half blend
+        let mut blend = FrameBlend::new(BlendMode::MIX, 0.5);
+        assert_eq!(run(&mut blend, &flicker), [0, 100, 100, 100, 100]);
+        // only the previous frame is shown
+        let mut blend = FrameBlend::new(BlendMode::MIX, 1.0);
+        assert_eq!(run(&mut blend, &flicker), [0, 0, 200, 0, 200]);
+    }
+
+    #[test]
+    fn lcd_weights() {
+        let steps = [0, 200, 200, 0, 0];
+        let mut blend = FrameBlend::new(BlendMode::LCD, 0.0);
+        assert_eq!(run(&mut blend, &steps), steps);
+        // rises by 3/4 of the way, falls by half
+        let mut blend = FrameBlend::new(BlendMode::LCD, 0.5);
+        assert_eq!(run(&mut blend, &steps), [0, 150, 188, 94, 47]);
+    }
+
+    #[test]
+    fn lcd_at_full_weight_still_settles() {
+        let mut blend = FrameBlend::new(BlendMode::LCD, 1.0);
+        let mut levels = vec![0];
+        levels.extend([255; 300]);
+        let out = run(&mut blend, &levels);
+        assert!(out[1] > 0 && out[1] < 255, "{}", out[1]);
+        assert_eq!(out[300], 255);
+        let out = run(&mut blend, &[0; 300]);
+        assert!(out[0] < 255);
+        assert_eq!(out[299], 0);
+    }
+
+    #[test]
+    fn off_and_size_changes_start_over() {
+        let mut blend = FrameBlend::new(BlendMode::MIX, 0.5);
+        run(&mut blend, &[200]);
+        blend.mode = BlendMode::OFF;
+        assert_eq!(run(&mut blend, &[0, 100]), [0, 100]);
+        blend.mode = BlendMode::MIX;
+        assert_eq!(run(&mut blend, &[40, 80]), [40, 60]);
+
+        let mut frame = [0; 12];
+        blend.apply(&mut frame);
+        assert_eq!(frame, [0; 12]);
+    }
+
+    #[test]
+    fn weight_is_clamped() {
+        assert_eq!(FrameBlend::new(BlendMode::MIX, -1.0).weight, 0.0);
+        assert_eq!(FrameBlend::new(BlendMode::MIX, 2.0).weight, 1.0);
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(BlendMode::from_name("lcd"), Some(BlendMode::LCD));
+        assert_eq!(BlendMode::from_name("ghost"), None);
+        assert_eq!(BlendMode::OFF.next(), BlendMode::MIX);
+        assert_eq!(BlendMode::MIX.next(), BlendMode::LCD);
+        assert_eq!(BlendMode::LCD.next(), BlendMode::OFF);
+    }
+}
@@ -0,0 +1,322 @@
+// Software CRT look, applied after the palette lookup (or NTSC filter) and
+// before the texture upload. Works at any output size.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum CrtMask {
+    NONE,
+    // vertical RGB stripes, Trinitron style
+    APERTURE_GRILLE,
+    // RGB triads, every other row offset by half a triad
+    SHADOW_MASK,
+}
+
+impl CrtMask {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(CrtMask::NONE),
+            "grille" => Some(CrtMask::APERTURE_GRILLE),
+            "shadow" => Some(CrtMask::SHADOW_MASK),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CrtSettings {
+    // how much darker the gaps between scanlines get, 0-1
+    pub scanlines: f32,
+    pub mask: CrtMask,
+    // how much the mask darkens the other two channels, 0-1
+    pub mask_strength: f32,
+    // barrel distortion, 0 is flat
+    pub curvature: f32,
+    // glow from bright areas into their neighbors
+    pub bloom: f32,
+    // darkening towards the corners, 0-1
+    pub vignette: f32,
+}
+
+impl Default for CrtSettings {
+    fn default() -> Self {
+        CrtSettings {
+            scanlines: 0.5,
+            mask: CrtMask::APERTURE_GRILLE,
+            mask_strength: 0.3,
+            curvature: 0.04,
+            bloom: 0.15,
+            vignette: 0.25,
+        }
+    }
+}
+
+// Where an output pixel reads the source from, precomputed per output size
+#[derive(Clone, Copy)]
+struct Tap {
+    // source pixels to blend between, and how far between them
+    lo: usize,
+    hi: usize,
+    t: f32,
+    // scanline and vignette darkening
+    gain: f32,
+}
+
+pub struct Crt {
+    settings: CrtSettings,
+    // one tap per output pixel, rebuilt when the sizes change. None falls
+    // outside the curved screen.
+    map: Vec<Option<Tap>>,
+    map_size: (usize, usize, usize, usize),
+    // 3x3 blur of the source, the light that bleeds out
+    glow: Vec<f32>,
+}
+
+impl Crt {
+    pub fn new(settings: CrtSettings) -> Self {
+        Crt {
+            settings,
+            map: Vec::new(),
+            map_size: (0, 0, 0, 0),
+            glow: Vec::new(),
+        }
+    }
+
+    // src is RGB24 src_w x src_h, dst is RGB24 dst_w x dst_h
+    pub fn apply(
+        &mut self,
+        src: &[u8],
+        (src_w, src_h): (usize, usize),
+        dst: &mut [u8],
+        (dst_w, dst_h): (usize, usize),
+        pitch: usize,
+    ) {
+        if self.map_size != (src_w, src_h, dst_w, dst_h) {
+            self.build_map(src_w, src_h, dst_w, dst_h);
+        }
+        let bloom = self.settings.bloom;
+        if bloom > 0.0 {
+            self.build_glow(src, src_w, src_h);
+        }
+
+        for y in 0..dst_h {
+            let row = &mut dst[y * pitch..y * pitch + dst_w * 3];
+            let taps = &self.map[y * dst_w..(y + 1) * dst_w];
+            for (x, (out, tap)) in
+                row.chunks_exact_mut(3).zip(taps).enumerate()
+            {
+                let Some(tap) = tap else {
+                    out.fill(0);
+                    continue;
+                };
+                let mask = self.mask_weights(x, y);
+                for c in 0..3 {
+                    let a = src[tap.lo * 3 + c] as f32;
+                    let b = src[tap.hi * 3 + c] as f32;
+                    let mut v = a + (b - a) * tap.t;
+                    if bloom > 0.0 {
+                        v += self.glow[tap.lo * 3 + c] * bloom;
+                    }
+                    v *= mask[c] * tap.gain;
+                    out[c] = v.round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+    }
+
+    // Helpers
+    fn build_map(
+        &mut self,
+        src_w: usize,
+        src_h: usize,
+        dst_w: usize,
+        dst_h: usize,
+    ) {
+        let k = self.settings.curvature;
+        self.map.clear();
+        for y in 0..dst_h {
+            for x in 0..dst_w {
+                // [-1, 1] from the center of the screen
+                let cx = (x as f32 + 0.5) / dst_w as f32 * 2.0 - 1.0;
+                let cy = (y as f32 + 0.5) / dst_h as f32 * 2.0 - 1.0;
+                let ux = cx * (1.0 + k * cy * cy);
+                let uy = cy * (1.0 + k * cx * cx);
+                if ux.abs() >= 1.0 || uy.abs() >= 1.0 {
+                    self.map.push(None);
+                    continue;
+                }
+                let sx = (ux + 1.0) / 2.0 * src_w as f32;
+                let sy = (uy + 1.0) / 2.0 * src_h as f32;
+
+                // scanlines: bright at the middle of each source line, dark
+                // at the edges
+                let line = usize::min(sy as usize, src_h - 1);
+                let dist = (sy - line as f32 - 0.5) * 2.0;
+                let beam = 1.0 - self.settings.scanlines * dist * dist;
+                let r2 = (ux * ux + uy * uy) / 2.0;
+                let shade = 1.0 - self.settings.vignette * r2;
+
+                let left = f32::max(0.0, sx - 0.5);
+                let lo = usize::min(left as usize, src_w - 1);
+                let hi = usize::min(lo + 1, src_w - 1);
+                self.map.push(Some(Tap {
+                    lo: line * src_w + lo,
+                    hi: line * src_w + hi,
+                    t: left - lo as f32,
+                    gain: beam * shade,
+                }));
+            }
+        }
+        self.map_size = (src_w, src_h, dst_w, dst_h);
+    }
+
+    fn build_glow(&mut self, src: &[u8], src_w: usize, src_h: usize) {
+        self.glow.resize(src.len(), 0.0);
+        for y in 0..src_h {
+            let rows = y.saturating_sub(1)..=usize::min(y + 1, src_h - 1);
+            for x in 0..src_w {
+                let cols = x.saturating_sub(1)..=usize::min(x + 1, src_w - 1);
+                for c in 0..3 {
+                    let mut sum = 0.0;
+                    for ny in rows.clone() {
+                        for nx in cols.clone() {
+                            sum += src[(ny * src_w + nx) * 3 + c] as f32;
+                        }
+                    }
+                    self.glow[(y * src_w + x) * 3 + c] = sum / 9.0;
+                }
+            }
+        }
+    }
+
+    fn mask_weights(&self, x: usize, y: usize) -> [f32; 3] {
+        let dim = 1.0 - self.settings.mask_strength;
+        let channel = match self.settings.mask {
+            CrtMask::NONE => return [1.0; 3],
+            CrtMask::APERTURE_GRILLE => x % 3,
+            CrtMask::SHADOW_MASK => (x + (y / 2 % 2) * 2) % 3,
+        };
+        let mut weights = [dim; 3];
+        weights[channel] = 1.0;
+        weights
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Crt, CrtMask, CrtSettings};
+
+    // every effect off
+    const FLAT: CrtSettings = CrtSettings {
+        scanlines: 0.0,
+        mask: CrtMask::NONE,
+        mask_strength: 0.0,
+        curvature: 0.0,
+        bloom: 0.0,
+        vignette: 0.0,
+    };
+
+    fn apply(
+        settings: CrtSettings,
+        src: &[u8],
+        src_size: (usize, usize),
+        dst_size: (usize, usize),
+    ) -> Vec<u8> {
+        let mut dst = vec![0; dst_size.0 * dst_size.1 * 3];
+        let pitch = dst_size.0 * 3;
+        Crt::new(settings).apply(src, src_size, &mut dst, dst_size, pitch);
+        dst
+    }
+
+    fn rgb(dst: &[u8], width: usize, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * width + x) * 3;
+        [dst[i], dst[i + 1], dst[i + 2]]
+    }
+
+    #[test]
+    fn flat_settings_copy_the_source() {
+        let src: Vec<u8> = (0..8 * 4 * 3).map(|i| (i * 5) as u8).collect();
+        assert_eq!(apply(FLAT, &src, (8, 4), (8, 4)), src);
+    }
+
+    #[test]
+    fn scanlines_darken_between_lines() {
+        let settings = CrtSettings {
+            scanlines: 1.0,
+            ..FLAT
+        };
+        // 4 output rows per source line
+        let dst = apply(settings, &[160; 4 * 2 * 3], (4, 2), (4, 8));
+        let column: Vec<u8> = (0..8).map(|y| rgb(&dst, 4, 1, y)[0]).collect();
+        assert_eq!(column, [70, 150, 150, 70, 70, 150, 150, 70]);
+    }
+
+    #[test]
+    fn masks_keep_one_channel_per_column() {
+        let settings = CrtSettings {
+            mask: CrtMask::APERTURE_GRILLE,
+            mask_strength: 0.3,
+            ..FLAT
+        };
+        let dst = apply(settings, &[100; 6 * 4 * 3], (6, 4), (6, 4));
+        assert_eq!(rgb(&dst, 6, 0, 0), [100, 70, 70]);
+        assert_eq!(rgb(&dst, 6, 1, 0), [70, 100, 70]);
+        assert_eq!(rgb(&dst, 6, 2, 3), [70, 70, 100]);
+
+        // every other pair of rows shifts by 2 columns
+        let settings = CrtSettings {
+            mask: CrtMask::SHADOW_MASK,
+            ..settings
+        };
+        let dst = apply(settings, &[100; 6 * 4 * 3], (6, 4), (6, 4));
+        assert_eq!(rgb(&dst, 6, 0, 1), [100, 70, 70]);
+        assert_eq!(rgb(&dst, 6, 0, 2), [70, 70, 100]);
+        assert_eq!(rgb(&dst, 6, 1, 3), [100, 70, 70]);
+    }
+
+    #[test]
+    fn curvature_and_vignette_fade_the_edges() {
+        let settings = CrtSettings {
+            curvature: 0.1,
+            ..FLAT
+        };
+        let dst = apply(settings, &[200; 16 * 16 * 3], (16, 16), (16, 16));
+        assert_eq!(rgb(&dst, 16, 0, 0), [0; 3]);
+        assert_eq!(rgb(&dst, 16, 8, 8), [200; 3]);
+
+        let settings = CrtSettings {
+            vignette: 0.5,
+            ..FLAT
+        };
+        let dst = apply(settings, &[200; 16 * 16 * 3], (16, 16), (16, 16));
+        let center = rgb(&dst, 16, 8, 8)[0];
+        let edge = rgb(&dst, 16, 0, 8)[0];
+        let corner = rgb(&dst, 16, 0, 0)[0];
+        assert!(center >= 199, "{}", center);
+        assert!(corner < edge && edge < center, "{} {}", corner, edge);
+    }
+
+    #[test]
+    fn bloom_spreads_bright_pixels() {
+        let mut src = vec![0; 5 * 5 * 3];
+        src[(2 * 5 + 2) * 3..(2 * 5 + 3) * 3].fill(180);
+        assert_eq!(rgb(&apply(FLAT, &src, (5, 5), (5, 5)), 5, 1, 2), [0; 3]);
+
+        let settings = CrtSettings { bloom: 1.0, ..FLAT };
+        let dst = apply(settings, &src, (5, 5), (5, 5));
+        // a ninth of the pixel reaches each neighbor
+        assert_eq!(rgb(&dst, 5, 1, 2), [20; 3]);
+        assert_eq!(rgb(&dst, 5, 2, 2), [200; 3]);
+        assert_eq!(rgb(&dst, 5, 0, 2), [0; 3]);
+    }
+
+    #[test]
+    fn mask_names() {
+        assert_eq!(CrtMask::from_name("none"), Some(CrtMask::NONE));
+        assert_eq!(
+            CrtMask::from_name("grille"),
+            Some(CrtMask::APERTURE_GRILLE)
+        );
+        assert_eq!(CrtMask::from_name("shadow"), Some(CrtMask::SHADOW_MASK));
+        assert_eq!(CrtMask::from_name("slot"), None);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::palette::ntsc::{carrier, signal, yiq_to_rgb, NtscSettings};
+
+// The PPU outputs 8 samples of the composite signal per dot, at 12 per color
+// cycle, so a pixel is 2/3 of a color cycle wide
+const SAMPLES_PER_DOT: usize = 8;
+const SAMPLES_PER_CYCLE: usize = 12;
+const DOTS_PER_LINE: usize = 341;
+// Decode 2 output pixels per dot
+const SAMPLES_PER_OUTPUT: usize = 4;
+// Half of the luma windows: S-Video keeps luma sharp, a black and white set
+// filters a little more but still passes some of the chroma as dots
+const LUMA_RADIUS: usize = 2;
+const MONOCHROME_RADIUS: usize = 4;
+const LINE_SAMPLES: usize = 256 * SAMPLES_PER_DOT;
+// Each line is padded by half a color cycle on both sides
+const PAD: usize = SAMPLES_PER_CYCLE / 2;
+
+pub const OUTPUT_WIDTH: usize = LINE_SAMPLES / SAMPLES_PER_OUTPUT;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum NtscPreset {
+    // luma and chroma share one signal: fringing, dot crawl, color blending
+    COMPOSITE,
+    // separate luma and chroma: sharp, no dot crawl, chroma still blends
+    SVIDEO,
+    // every pixel decoded on its own, no artifacts
+    RGB,
+    // a black and white set, chroma shows up as a fine dot pattern
+    MONOCHROME,
+}
+
+impl NtscPreset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "composite" => Some(NtscPreset::COMPOSITE),
+            "svideo" => Some(NtscPreset::SVIDEO),
+            "rgb" => Some(NtscPreset::RGB),
+            "mono" => Some(NtscPreset::MONOCHROME),
+            _ => None,
+        }
+    }
+
+    // Cycles composite -> S-Video -> RGB -> monochrome -> off
+    pub fn next(preset: Option<Self>) -> Option<Self> {
+        match preset {
+            None => Some(NtscPreset::COMPOSITE),
+            Some(NtscPreset::COMPOSITE) => Some(NtscPreset::SVIDEO),
+            Some(NtscPreset::SVIDEO) => Some(NtscPreset::RGB),
+            Some(NtscPreset::RGB) => Some(NtscPreset::MONOCHROME),
+            Some(NtscPreset::MONOCHROME) => None,
+        }
+    }
+}
+
+// Re-encodes each frame as the PPU's composite signal and decodes it the way
+// a TV would
+pub struct NtscFilter {
+    settings: NtscSettings,
+    // signal level of every 9 bit color at each phase of the color cycle
+    levels: Vec<[f32; SAMPLES_PER_CYCLE]>,
+    // what each color decodes to on its own
+    yiq: Vec<(f32, f32, f32)>,
+    carrier: [(f32, f32); SAMPLES_PER_CYCLE],
+
+    // one line of samples, plus the luma of the pixel each belongs to
+    line: Vec<f32>,
+    line_luma: Vec<f32>,
+}
+
+impl NtscFilter {
+    pub fn new(settings: NtscSettings) -> Self {
+        let mut carrier_table = [(0.0, 0.0); SAMPLES_PER_CYCLE];
+        for (phase, entry) in carrier_table.iter_mut().enumerate() {
+            *entry = carrier(phase as u16, &settings);
+        }
+
+        let mut levels = Vec::with_capacity(512);
+        let mut yiq = Vec::with_capacity(512);
+        for color in 0..512u16 {
+            let mut level = [0.0; SAMPLES_PER_CYCLE];
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for phase in 0..SAMPLES_PER_CYCLE {
+                let v = signal(color, phase as u16);
+                let (cos, sin) = carrier_table[phase];
+                level[phase] = v;
+                y += v / SAMPLES_PER_CYCLE as f32;
+                i += 2.0 * v * cos / SAMPLES_PER_CYCLE as f32;
+                q += 2.0 * v * sin / SAMPLES_PER_CYCLE as f32;
+            }
+            levels.push(level);
+            yiq.push((y, i, q));
+        }
+
+        NtscFilter {
+            settings,
+            levels,
+            yiq,
+            carrier: carrier_table,
+            line: vec![0.0; LINE_SAMPLES + 2 * PAD],
+            line_luma: vec![0.0; LINE_SAMPLES + 2 * PAD],
+        }
+    }
+
+    // frame holds 256x240 9 bit colors, buffer is RGB24 OUTPUT_WIDTH x 240.
+    // frame_phase is the dot the frame started on mod 3, which moves with
+    // the odd frame dot skip and makes the artifacts crawl.
+    pub fn apply(
+        &mut self,
+        preset: NtscPreset,
+        frame: &[u16],
+        frame_phase: u8,
+        buffer: &mut [u8],
+        pitch: usize,
+    ) {
+        for (y, pixels) in frame.chunks_exact(256).enumerate() {
+            // each line is 341 dots later than the last, and the first
+            // visible pixel is dot 1
+            let dot = frame_phase as usize + y * DOTS_PER_LINE + 1;
+            let start_phase = dot * SAMPLES_PER_DOT % SAMPLES_PER_CYCLE;
+            self.encode_line(pixels, start_phase);
+
+            let row = &mut buffer[y * pitch..];
+            for x in 0..OUTPUT_WIDTH {
+                // sample in the middle of the output pixel
+                let center = PAD + x * SAMPLES_PER_OUTPUT + 2;
+                let phase = (start_phase + center + SAMPLES_PER_CYCLE - PAD)
+                    % SAMPLES_PER_CYCLE;
+                let (luma, i, q) = match preset {
+                    NtscPreset::COMPOSITE => {
+                        let (i, q) = self.demodulate(center, phase, false);
+                        (self.average(&self.line, center, PAD), i, q)
+                    }
+                    NtscPreset::SVIDEO => {
+                        let (i, q) = self.demodulate(center, phase, true);
+                        let luma =
+                            self.average(&self.line_luma, center, LUMA_RADIUS);
+                        (luma, i, q)
+                    }
+                    NtscPreset::RGB => {
+                        let color =
+                            pixels[x * SAMPLES_PER_OUTPUT / SAMPLES_PER_DOT];
+                        self.yiq[color as usize & 0x1FF]
+                    }
+                    NtscPreset::MONOCHROME => (
+                        self.average(&self.line, center, MONOCHROME_RADIUS),
+                        0.0,
+                        0.0,
+                    ),
+                };
+                let (r, g, b) = yiq_to_rgb(luma, i, q, &self.settings);
+                row[x * 3] = r;
+                row[x * 3 + 1] = g;
+                row[x * 3 + 2] = b;
+            }
+        }
+    }
+
+    // Helpers
+    fn encode_line(&mut self, pixels: &[u16], start_phase: usize) {
+        // the edges hold the border pixels so the windows stay whole
+        for k in 0..self.line.len() {
+            let sample = k.clamp(PAD, PAD + LINE_SAMPLES - 1) - PAD;
+            let color = pixels[sample / SAMPLES_PER_DOT] as usize & 0x1FF;
+            let phase = (start_phase + k + SAMPLES_PER_CYCLE - PAD)
+                % SAMPLES_PER_CYCLE;
+            self.line[k] = self.levels[color][phase];
+            self.line_luma[k] = self.yiq[color].0;
+        }
+    }
+
+    fn average(&self, samples: &[f32], center: usize, radius: usize) -> f32 {
+        let window = &samples[center - radius..center + radius];
+        window.iter().sum::<f32>() / window.len() as f32
+    }
+
+    // I and Q over the color cycle around center, from the chroma alone
+    // (S-Video) or from the full composite signal
+    fn demodulate(
+        &self,
+        center: usize,
+        phase: usize,
+        separate: bool,
+    ) -> (f32, f32) {
+        let (mut i, mut q) = (0.0, 0.0);
+        for offset in 0..SAMPLES_PER_CYCLE {
+            let k = center - PAD + offset;
+            let mut v = self.line[k];
+            if separate {
+                v -= self.line_luma[k];
+            }
+            let sample_phase =
+                (phase + SAMPLES_PER_CYCLE - PAD + offset) % SAMPLES_PER_CYCLE;
+            let (cos, sin) = self.carrier[sample_phase];
+            i += v * cos;
+            q += v * sin;
+        }
+        let scale = 2.0 / SAMPLES_PER_CYCLE as f32;
+        (i * scale, q * scale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NtscFilter, NtscPreset, OUTPUT_WIDTH};
+    use crate::palette::ntsc::{generate, NtscSettings};
+
+    fn decode(preset: NtscPreset, frame: &[u16], frame_phase: u8) -> Vec<u8> {
+        let mut filter = NtscFilter::new(NtscSettings::default());
+        let pitch = OUTPUT_WIDTH * 3;
+        let mut buffer = vec![0; pitch * 240];
+        filter.apply(preset, frame, frame_phase, &mut buffer, pitch);
+        buffer
+    }
+
+    fn rgb(buffer: &[u8], x: usize, y: usize) -> (u8, u8, u8) {
+        let i = (y * OUTPUT_WIDTH + x) * 3;
+        (buffer[i], buffer[i + 1], buffer[i + 2])
+    }
+
+    fn close(a: (u8, u8, u8), b: (u8, u8, u8)) -> bool {
+        a.0.abs_diff(b.0) <= 1
+            && a.1.abs_diff(b.1) <= 1
+            && a.2.abs_diff(b.2) <= 1
+    }
+
+    #[test]
+    fn flat_colors_decode_to_the_palette() {
+        let palette = generate(&NtscSettings::default());
+        for color in [0x00, 0x16, 0x2A, 0x12, 0x30, 0x0F, 0x0E6] {
+            let frame = vec![color; 256 * 240];
+            for preset in
+                [NtscPreset::COMPOSITE, NtscPreset::SVIDEO, NtscPreset::RGB]
+            {
+                let buffer = decode(preset, &frame, 0);
+                for (x, y) in [(0, 0), (100, 37), (511, 239)] {
+                    let got = rgb(&buffer, x, y);
+                    let want = palette[color as usize];
+                    assert!(
+                        close(got, want),
+                        "{:?} {:03X}: {:?} != {:?}",
+                        preset,
+                        color,
+                        got,
+                        want
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn monochrome_drops_the_color() {
+        let palette = generate(&NtscSettings::default());
+        let buffer = decode(NtscPreset::MONOCHROME, &vec![0x10; 256 * 240], 0);
+        assert!(close(rgb(&buffer, 200, 100), palette[0x10]));
+        let buffer = decode(NtscPreset::MONOCHROME, &vec![0x16; 256 * 240], 0);
+        for x in 100..112 {
+            let (r, g, b) = rgb(&buffer, x, 100);
+            assert!(r == g && g == b, "{:?}", (r, g, b));
+        }
+    }
+
+    #[test]
+    fn composite_fringes_and_crawls_where_rgb_does_not() {
+        // 1 pixel wide red and white stripes
+        let frame: Vec<u16> = (0..256 * 240)
+            .map(|i| if i % 2 == 0 { 0x16 } else { 0x30 })
+            .collect();
+        let palette = generate(&NtscSettings::default());
+
+        let rgb_frame = decode(NtscPreset::RGB, &frame, 0);
+        assert_eq!(rgb(&rgb_frame, 0, 0), palette[0x16]);
+        assert_eq!(rgb(&rgb_frame, 2, 0), palette[0x30]);
+        assert_eq!(decode(NtscPreset::RGB, &frame, 1), rgb_frame);
+
+        let composite = decode(NtscPreset::COMPOSITE, &frame, 0);
+        assert!(!close(rgb(&composite, 100, 50), palette[0x16]));
+        assert!(!close(rgb(&composite, 102, 50), palette[0x30]));
+        // the artifacts move with the frame's starting dot
+        assert_ne!(decode(NtscPreset::COMPOSITE, &frame, 1), composite);
+    }
+
+    #[test]
+    fn presets() {
+        assert_eq!(NtscPreset::from_name("svideo"), Some(NtscPreset::SVIDEO));
+        assert_eq!(
+            NtscPreset::from_name("mono"),
+            Some(NtscPreset::MONOCHROME)
+        );
+        assert_eq!(NtscPreset::from_name("pal"), None);
+        let mut preset = None;
+        let mut seen = Vec::new();
+        loop {
+            preset = NtscPreset::next(preset);
+            match preset {
+                Some(preset) => seen.push(preset),
+                None => break,
+            }
+        }
+        assert_eq!(
+            seen,
+            [
+                NtscPreset::COMPOSITE,
+                NtscPreset::SVIDEO,
+                NtscPreset::RGB,
+                NtscPreset::MONOCHROME
+            ]
+        );
+    }
+}
@@ -0,0 +1,219 @@
+// How the NES picture is cropped and fit into the window
+
+use crate::region::Region;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum ScaleMode {
+    // fill the window, ignoring aspect
+    STRETCH,
+    // as large as fits, keeping the pixel aspect
+    ASPECT,
+    // as large as fits at a whole multiple of the NES height
+    INTEGER,
+}
+
+impl ScaleMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "stretch" => Some(ScaleMode::STRETCH),
+            "aspect" => Some(ScaleMode::ASPECT),
+            "integer" => Some(ScaleMode::INTEGER),
+            _ => None,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            ScaleMode::STRETCH => ScaleMode::ASPECT,
+            ScaleMode::ASPECT => ScaleMode::INTEGER,
+            ScaleMode::INTEGER => ScaleMode::STRETCH,
+        }
+    }
+}
+
+// Width of one NES pixel relative to its height on a TV
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum PixelAspect {
+    SQUARE,
+    // 8:7
+    NTSC,
+    // 2950000:2128137, PAL dots are wider
+    PAL,
+}
+
+impl PixelAspect {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "square" => Some(PixelAspect::SQUARE),
+            "ntsc" => Some(PixelAspect::NTSC),
+            "pal" => Some(PixelAspect::PAL),
+            _ => None,
+        }
+    }
+
+    // The pixel shape of the TV a region's games were drawn for
+    pub fn from_region(region: Region) -> Self {
+        match region {
+            Region::NTSC => PixelAspect::NTSC,
+            Region::PAL | Region::DENDY => PixelAspect::PAL,
+        }
+    }
+
+    pub fn ratio(self) -> f32 {
+        match self {
+            PixelAspect::SQUARE => 1.0,
+            PixelAspect::NTSC => 8.0 / 7.0,
+            PixelAspect::PAL => 2950000.0 / 2128137.0,
+        }
+    }
+}
+
+// NES pixels hidden at each edge. NTSC TVs typically lost 8 lines at the
+// top and bottom.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Overscan {
+    // "top,bottom,left,right"
+    pub fn parse(value: &str) -> Option<Self> {
+        let edges: Vec<usize> = value
+            .split(',')
+            .map(|edge| edge.trim().parse().ok())
+            .collect::<Option<_>>()?;
+        let [top, bottom, left, right] = edges[..] else {
+            return None;
+        };
+        if top + bottom >= 240 || left + right >= 256 {
+            return None;
+        }
+        Some(Overscan {
+            top,
+            bottom,
+            left,
+            right,
+        })
+    }
+
+    // Visible size in NES pixels
+    pub fn size(&self) -> (usize, usize) {
+        (256 - self.left - self.right, 240 - self.top - self.bottom)
+    }
+
+    // Crops an RGB24 frame of 256 * x_scale x 240 * y_scale
+    pub fn crop(&self, src: &[u8], x_scale: usize, y_scale: usize) -> Vec<u8> {
+        let pitch = 256 * x_scale * 3;
+        let (w, h) = self.size();
+        let start = self.left * x_scale * 3;
+        let mut out = Vec::with_capacity(w * x_scale * h * y_scale * 3);
+        let lines = src.chunks_exact(pitch).skip(self.top * y_scale);
+        for line in lines.take(h * y_scale) {
+            out.extend_from_slice(&line[start..start + w * x_scale * 3]);
+        }
+        out
+    }
+}
+
+// Rectangle (x, y, w, h) in the window for a w x h NES pixel picture
+pub fn fit(
+    mode: ScaleMode,
+    aspect: PixelAspect,
+    (w, h): (usize, usize),
+    (window_w, window_h): (u32, u32),
+) -> (i32, i32, u32, u32) {
+    let display_w = w as f32 * aspect.ratio();
+    let display_h = h as f32;
+    let (out_w, out_h) = match mode {
+        ScaleMode::STRETCH => return (0, 0, window_w, window_h),
+        ScaleMode::ASPECT => {
+            let scale = f32::min(
+                window_w as f32 / display_w,
+                window_h as f32 / display_h,
+            );
+            (display_w * scale, display_h * scale)
+        }
+        ScaleMode::INTEGER => {
+            let fits = f32::min(
+                window_w as f32 / display_w,
+                window_h as f32 / display_h,
+            );
+            let scale = f32::max(1.0, fits.floor());
+            (display_w * scale, display_h * scale)
+        }
+    };
+    let (out_w, out_h) = (out_w.round() as u32, out_h.round() as u32);
+    (
+        (window_w as i32 - out_w as i32) / 2,
+        (window_h as i32 - out_h as i32) / 2,
+        out_w,
+        out_h,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Overscan, PixelAspect};
+    use crate::region::Region;
+
+    #[test]
+    fn pixel_aspect_follows_the_region() {
+        assert_eq!(PixelAspect::from_region(Region::NTSC), PixelAspect::NTSC);
+        assert_eq!(PixelAspect::from_region(Region::PAL), PixelAspect::PAL);
+        assert_eq!(PixelAspect::from_region(Region::DENDY), PixelAspect::PAL);
+    }
+
+    #[test]
+    fn overscan_parse() {
+        let overscan = Overscan::parse("8, 8 ,0,4").unwrap();
+        assert_eq!(
+            overscan,
+            Overscan {
+                top: 8,
+                bottom: 8,
+                left: 0,
+                right: 4,
+            }
+        );
+        assert_eq!(overscan.size(), (252, 224));
+        assert_eq!(Overscan::parse("0,0,0,0"), Some(Overscan::default()));
+    }
+
+    #[test]
+    fn overscan_parse_rejects_bad_values() {
+        for value in [
+            "",
+            "8,8,0",
+            "8,8,0,0,0",
+            "8,8,x,0",
+            "-1,0,0,0",
+            // nothing left to show
+            "120,120,0,0",
+            "0,0,128,128",
+        ] {
+            assert_eq!(Overscan::parse(value), None, "{}", value);
+        }
+        assert!(Overscan::parse("119,120,127,128").is_some());
+    }
+
+    #[test]
+    fn overscan_crop() {
+        // 2x wide, 1x tall frame where each pixel holds its x, y
+        let mut src = Vec::new();
+        for y in 0..240 {
+            for x in 0..512 {
+                src.extend([(x / 2) as u8, y as u8, 0]);
+            }
+        }
+        let overscan = Overscan::parse("8,8,4,0").unwrap();
+        let out = overscan.crop(&src, 2, 1);
+        assert_eq!(out.len(), 252 * 2 * 224 * 3);
+        assert_eq!(out[..3], [4, 8, 0]);
+        assert_eq!(out[out.len() - 3..], [255, 231, 0]);
+    }
+}
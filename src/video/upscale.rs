@@ -0,0 +1,751 @@
+// Pixel art upscalers, all on RGB24 frames
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Upscaler {
+    NEAREST,
+    // EPX/AdvMAME, exact
+    SCALE2X,
+    SCALE3X,
+    // Maxim Stepin's hqx
+    HQ2X,
+    HQ3X,
+    HQ4X,
+    // Hyllian's xBR level 1 at 2x
+    XBR,
+}
+
+impl Upscaler {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nearest" => Some(Upscaler::NEAREST),
+            "scale2x" => Some(Upscaler::SCALE2X),
+            "scale3x" => Some(Upscaler::SCALE3X),
+            "hq2x" => Some(Upscaler::HQ2X),
+            "hq3x" => Some(Upscaler::HQ3X),
+            "hq4x" => Some(Upscaler::HQ4X),
+            "xbr" => Some(Upscaler::XBR),
+            _ => None,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            Upscaler::NEAREST => Upscaler::SCALE2X,
+            Upscaler::SCALE2X => Upscaler::SCALE3X,
+            Upscaler::SCALE3X => Upscaler::HQ2X,
+            Upscaler::HQ2X => Upscaler::HQ3X,
+            Upscaler::HQ3X => Upscaler::HQ4X,
+            Upscaler::HQ4X => Upscaler::XBR,
+            Upscaler::XBR => Upscaler::NEAREST,
+        }
+    }
+
+    pub fn factor(self) -> usize {
+        match self {
+            Upscaler::NEAREST => 1,
+            Upscaler::SCALE2X | Upscaler::HQ2X | Upscaler::XBR => 2,
+            Upscaler::SCALE3X | Upscaler::HQ3X => 3,
+            Upscaler::HQ4X => 4,
+        }
+    }
+
+    // src is RGB24 w x h, returns RGB24 at factor() times the size
+    pub fn apply(self, src: &[u8], w: usize, h: usize) -> Vec<u8> {
+        match self {
+            // SDL scales the texture itself
+            Upscaler::NEAREST => src.to_vec(),
+            Upscaler::SCALE2X => upscale(src, w, h, scale2x),
+            Upscaler::SCALE3X => upscale(src, w, h, scale3x),
+            Upscaler::HQ2X => upscale(src, w, h, hq2x),
+            Upscaler::HQ3X => upscale(src, w, h, hq3x),
+            Upscaler::HQ4X => upscale(src, w, h, hq4x),
+            Upscaler::XBR => upscale(src, w, h, xbr),
+        }
+    }
+}
+
+// Runs an upscaler that turns each pixel into an N x N block
+fn upscale<const N: usize>(
+    src: &[u8],
+    w: usize,
+    h: usize,
+    block: fn(&Image, usize, usize) -> [[u32; N]; N],
+) -> Vec<u8> {
+    let image = Image {
+        pixels: src
+            .chunks_exact(3)
+            .map(|p| u32::from_be_bytes([0, p[0], p[1], p[2]]))
+            .collect(),
+        w,
+        h,
+    };
+    let mut out = vec![0u32; w * N * h * N];
+    for y in 0..h {
+        for x in 0..w {
+            for (j, row) in block(&image, x, y).iter().enumerate() {
+                let start = (y * N + j) * w * N + x * N;
+                out[start..start + N].copy_from_slice(row);
+            }
+        }
+    }
+    out.iter()
+        .flat_map(|p| {
+            let [_, r, g, b] = p.to_be_bytes();
+            [r, g, b]
+        })
+        .collect()
+}
+
+struct Image {
+    pixels: Vec<u32>,
+    w: usize,
+    h: usize,
+}
+
+impl Image {
+    // edges repeat the border pixels
+    fn get(&self, x: usize, y: usize, dx: isize, dy: isize) -> u32 {
+        let x = (x as isize + dx).clamp(0, self.w as isize - 1) as usize;
+        let y = (y as isize + dy).clamp(0, self.h as isize - 1) as usize;
+        self.pixels[y * self.w + x]
+    }
+}
+
+//  A B C
+//  D E F
+//  G H I
+fn scale2x(image: &Image, x: usize, y: usize) -> [[u32; 2]; 2] {
+    let p = |dx, dy| image.get(x, y, dx, dy);
+    let (b, d, e, f, h) = (p(0, -1), p(-1, 0), p(0, 0), p(1, 0), p(0, 1));
+    if b == h || d == f {
+        return [[e; 2]; 2];
+    }
+    [
+        [if d == b { d } else { e }, if b == f { f } else { e }],
+        [if d == h { d } else { e }, if h == f { f } else { e }],
+    ]
+}
+
+fn scale3x(image: &Image, x: usize, y: usize) -> [[u32; 3]; 3] {
+    let p = |dx, dy| image.get(x, y, dx, dy);
+    let (a, b, c) = (p(-1, -1), p(0, -1), p(1, -1));
+    let (d, e, f) = (p(-1, 0), p(0, 0), p(1, 0));
+    let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+    if b == h || d == f {
+        return [[e; 3]; 3];
+    }
+    let pick = |cond: bool, p: u32| if cond { p } else { e };
+    [
+        [
+            pick(d == b, d),
+            pick((d == b && e != c) || (b == f && e != a), b),
+            pick(b == f, f),
+        ],
+        [
+            pick((d == b && e != g) || (d == h && e != a), d),
+            e,
+            pick((b == f && e != i) || (h == f && e != c), f),
+        ],
+        [
+            pick(d == h, d),
+            pick((d == h && e != i) || (h == f && e != g), h),
+            pick(h == f, f),
+        ],
+    ]
+}
+
+// hqx works out one corner of the block at a time, on the neighborhood
+// turned so that corner is at the top left. Each [a, b, c, d] maps an
+// offset (dx, dy) in the turned frame to (a * dx + b * dy, c * dx + d * dy)
+const MIRRORS: [[isize; 4]; 4] =
+    [[1, 0, 0, 1], [-1, 0, 0, 1], [1, 0, 0, -1], [-1, 0, 0, -1]];
+// hq3x rotates instead, so that the edge pixel after each corner is a
+// different one
+const ROTATIONS: [[isize; 4]; 4] =
+    [[1, 0, 0, 1], [0, -1, 1, 0], [0, 1, -1, 0], [-1, 0, 0, -1]];
+
+// Neighbor patterns shared by the hqx rules, as (mask, bits) pairs
+const SHARP: &[(u8, u8)] = &[(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)];
+const DIAGONAL: &[(u8, u8)] = &[
+    (0x6f, 0x2a),
+    (0x5b, 0x0a),
+    (0xbf, 0x3a),
+    (0xdf, 0x5a),
+    (0x9f, 0x8a),
+    (0xcf, 0x8a),
+    (0xef, 0x4e),
+    (0x3f, 0x0e),
+    (0xfb, 0x5a),
+    (0xbb, 0x8a),
+    (0x7f, 0x5a),
+    (0xaf, 0x8a),
+    (0xeb, 0x8a),
+];
+const EDGE_UP: &[(u8, u8)] = &[(0xbf, 0x37), (0xdb, 0x13)];
+const EDGE_LEFT: &[(u8, u8)] = &[(0xdb, 0x49), (0xef, 0x6d)];
+const NEAR_UP: &[(u8, u8)] =
+    &[(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)];
+const NEAR_LEFT: &[(u8, u8)] =
+    &[(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)];
+const SLOPE_UP: &[(u8, u8)] = &[(0xbf, 0x8f), (0x7e, 0x0e)];
+const SLOPE_LEFT: &[(u8, u8)] = &[(0x7e, 0x2a), (0xef, 0xab)];
+const BEVEL: &[(u8, u8)] = &[
+    (0x4f, 0x4b),
+    (0x9f, 0x1b),
+    (0x2f, 0x0b),
+    (0xbe, 0x0a),
+    (0xee, 0x0a),
+    (0x7e, 0x0a),
+    (0xeb, 0x4b),
+    (0x3b, 0x1b),
+];
+// the corner pixel bleeds into the top row or the left column
+const CORNER_TOP: &[(u8, u8)] = &[
+    (0xf3, 0x62),
+    (0x67, 0x66),
+    (0x37, 0x36),
+    (0xf3, 0xf2),
+    (0xd7, 0xd6),
+    (0xd7, 0x16),
+    (0x0b, 0x02),
+];
+const CORNER_LEFT: &[(u8, u8)] = &[
+    (0xf9, 0x68),
+    (0x6d, 0x6c),
+    (0x3d, 0x3c),
+    (0xf9, 0xf8),
+    (0xdd, 0xdc),
+    (0xdd, 0x1c),
+    (0x0b, 0x08),
+];
+
+//  w0 w1 w2
+//  w3 w4 w5
+//  w6 w7 w8
+struct Frame {
+    w: [u32; 9],
+    // one bit per neighbor, w0 first, set where it differs from w4
+    k: u8,
+    turn: [isize; 4],
+}
+
+impl Frame {
+    fn new(image: &Image, x: usize, y: usize, turn: [isize; 4]) -> Self {
+        let [a, b, c, d] = turn;
+        let mut w = [0; 9];
+        for (n, p) in w.iter_mut().enumerate() {
+            let (dx, dy) = (n as isize % 3 - 1, n as isize / 3 - 1);
+            *p = image.get(x, y, a * dx + b * dy, c * dx + d * dy);
+        }
+        let k = [0, 1, 2, 3, 5, 6, 7, 8]
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, n)| !similar(w[4], w[n]))
+            .fold(0, |k, (bit, _)| k | 1 << bit);
+        Frame { w, k, turn }
+    }
+
+    fn p(&self, mask: u8, bits: u8) -> bool {
+        self.k & mask == bits
+    }
+
+    fn any(&self, patterns: &[(u8, u8)]) -> bool {
+        patterns.iter().any(|&(mask, bits)| self.p(mask, bits))
+    }
+
+    fn diff(&self, a: usize, b: usize) -> bool {
+        !similar(self.w[a], self.w[b])
+    }
+
+    // weighted average of neighbors, the weights add up to 1 << shift
+    fn mix(&self, weights: &[(usize, u32)], shift: u32) -> u32 {
+        let channel = |c: u32| {
+            let sum: u32 = weights
+                .iter()
+                .map(|&(n, weight)| (self.w[n] >> c & 0xff) * weight)
+                .sum();
+            (sum >> shift) << c
+        };
+        channel(16) | channel(8) | channel(0)
+    }
+
+    // stores pixel (i, j) of the block as seen in this frame
+    fn put<const N: usize>(
+        &self,
+        block: &mut [[u32; N]; N],
+        i: usize,
+        j: usize,
+        p: u32,
+    ) {
+        let [a, b, c, d] = self.turn;
+        let n = N as isize;
+        let (u, v) = (2 * i as isize + 1 - n, 2 * j as isize + 1 - n);
+        let col = (a * u + b * v + n - 1) / 2;
+        let row = (c * u + d * v + n - 1) / 2;
+        block[row as usize][col as usize] = p;
+    }
+}
+
+fn hq2x(image: &Image, x: usize, y: usize) -> [[u32; 2]; 2] {
+    let mut block = [[0; 2]; 2];
+    for turn in MIRRORS {
+        let frame = Frame::new(image, x, y, turn);
+        frame.put(&mut block, 0, 0, hq2x_corner(&frame));
+    }
+    block
+}
+
+fn hq3x(image: &Image, x: usize, y: usize) -> [[u32; 3]; 3] {
+    let mut block = [[image.get(x, y, 0, 0); 3]; 3];
+    for turn in ROTATIONS {
+        let frame = Frame::new(image, x, y, turn);
+        frame.put(&mut block, 0, 0, hq3x_corner(&frame));
+        frame.put(&mut block, 1, 0, hq3x_edge(&frame));
+    }
+    block
+}
+
+fn hq4x(image: &Image, x: usize, y: usize) -> [[u32; 4]; 4] {
+    let mut block = [[0; 4]; 4];
+    for turn in MIRRORS {
+        let frame = Frame::new(image, x, y, turn);
+        for (j, row) in hq4x_corner(&frame).into_iter().enumerate() {
+            for (i, p) in row.into_iter().enumerate() {
+                frame.put(&mut block, i, j, p);
+            }
+        }
+    }
+    block
+}
+
+// The rules below are the hqNx lookup tables in the compact form FFmpeg
+// uses, tried in order
+fn hq2x_corner(f: &Frame) -> u32 {
+    if f.any(EDGE_UP) && f.diff(1, 5) {
+        f.mix(&[(4, 3), (3, 1)], 2)
+    } else if f.any(EDGE_LEFT) && f.diff(7, 3) {
+        f.mix(&[(4, 3), (1, 1)], 2)
+    } else if f.any(SHARP) && f.diff(3, 1) {
+        f.w[4]
+    } else if f.any(DIAGONAL) && f.diff(3, 1) {
+        f.mix(&[(4, 3), (0, 1)], 2)
+    } else if f.p(0x0b, 0x08) {
+        f.mix(&[(4, 2), (0, 1), (1, 1)], 2)
+    } else if f.p(0x0b, 0x02) {
+        f.mix(&[(4, 2), (0, 1), (3, 1)], 2)
+    } else if f.p(0x2f, 0x2f) {
+        f.mix(&[(4, 14), (3, 1), (1, 1)], 4)
+    } else if f.any(EDGE_UP) {
+        f.mix(&[(4, 5), (1, 2), (3, 1)], 3)
+    } else if f.any(EDGE_LEFT) {
+        f.mix(&[(4, 5), (3, 2), (1, 1)], 3)
+    } else if f.any(NEAR_LEFT) {
+        f.mix(&[(4, 3), (3, 1)], 2)
+    } else if f.any(NEAR_UP) {
+        f.mix(&[(4, 3), (1, 1)], 2)
+    } else if f.any(SLOPE_UP) || f.any(SLOPE_LEFT) {
+        f.mix(&[(4, 2), (3, 3), (1, 3)], 3)
+    } else if f.any(&[
+        (0xfb, 0x6a),
+        (0x6f, 0x6e),
+        (0x3f, 0x3e),
+        (0xfb, 0xfa),
+        (0xdf, 0xde),
+        (0xdf, 0x1e),
+    ]) {
+        f.mix(&[(4, 3), (0, 1)], 2)
+    } else if f.p(0x0a, 0x00) || f.any(BEVEL) {
+        f.mix(&[(4, 2), (3, 1), (1, 1)], 2)
+    } else {
+        f.mix(&[(4, 6), (3, 1), (1, 1)], 3)
+    }
+}
+
+fn hq3x_corner(f: &Frame) -> u32 {
+    if f.any(EDGE_UP) && f.diff(1, 5) {
+        f.mix(&[(4, 3), (3, 1)], 2)
+    } else if f.any(EDGE_LEFT) && f.diff(7, 3) {
+        f.mix(&[(4, 3), (1, 1)], 2)
+    } else if f.any(SHARP) && f.diff(3, 1) {
+        f.w[4]
+    } else if f.any(DIAGONAL) && f.diff(3, 1) {
+        f.mix(&[(4, 3), (0, 1)], 2)
+    } else if f.any(NEAR_UP) {
+        f.mix(&[(4, 3), (1, 1)], 2)
+    } else if f.any(NEAR_LEFT) {
+        f.mix(&[(4, 3), (3, 1)], 2)
+    } else if f.any(SLOPE_UP) || f.any(SLOPE_LEFT) {
+        f.mix(&[(3, 1), (1, 1)], 1)
+    } else if f.any(BEVEL) {
+        f.mix(&[(4, 2), (3, 7), (1, 7)], 4)
+    } else if f.any(CORNER_TOP) || f.any(CORNER_LEFT) {
+        f.mix(&[(4, 3), (0, 1)], 2)
+    } else {
+        f.mix(&[(4, 2), (3, 1), (1, 1)], 2)
+    }
+}
+
+// the pixel right of the corner
+fn hq3x_edge(f: &Frame) -> u32 {
+    let sharp_up = f.any(&[
+        (0xfe, 0xde),
+        (0x9e, 0x16),
+        (0xda, 0x12),
+        (0x17, 0x16),
+        (0x5b, 0x12),
+        (0xbb, 0x12),
+    ]) && f.diff(1, 5);
+    let sharp_left = f.any(&[
+        (0x0f, 0x0b),
+        (0x5e, 0x0a),
+        (0xfb, 0x7b),
+        (0x3b, 0x0b),
+        (0xbe, 0x0a),
+        (0x7a, 0x0a),
+    ]) && f.diff(3, 1);
+
+    if sharp_up || sharp_left {
+        f.w[4]
+    } else if f.any(SLOPE_UP) || f.any(EDGE_UP) {
+        f.mix(&[(1, 3), (4, 1)], 2)
+    } else if f.any(&[
+        (0x02, 0x00),
+        (0x7c, 0x28),
+        (0xed, 0xa9),
+        (0xf5, 0xb4),
+        (0xd9, 0x90),
+    ]) {
+        f.mix(&[(4, 3), (1, 1)], 2)
+    } else if f.any(&[
+        (0x4f, 0x4b),
+        (0xfb, 0x7b),
+        (0xfe, 0x7e),
+        (0x9f, 0x1b),
+        (0x2f, 0x0b),
+        (0xbe, 0x0a),
+        (0x7e, 0x0a),
+        (0xfb, 0x4b),
+        (0xfb, 0xdb),
+        (0xfe, 0xde),
+        (0xfe, 0x56),
+        (0x57, 0x56),
+        (0x97, 0x16),
+        (0x3f, 0x1e),
+        (0xdb, 0x12),
+        (0xbb, 0x12),
+    ]) {
+        f.mix(&[(4, 7), (1, 1)], 3)
+    } else {
+        f.w[4]
+    }
+}
+
+// the top left 2 x 2 of the block
+fn hq4x_corner(f: &Frame) -> [[u32; 2]; 2] {
+    let edge_up = f.any(EDGE_UP) && f.diff(1, 5);
+    let edge_left = f.any(EDGE_LEFT) && f.diff(7, 3);
+    let diagonal = f.any(DIAGONAL) && f.diff(3, 1);
+    let sharp_edges =
+        f.any(&[(0x0f, 0x0b), (0x2b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)])
+            && f.diff(3, 1);
+
+    let corner = if edge_up {
+        f.mix(&[(4, 5), (3, 3)], 3)
+    } else if edge_left {
+        f.mix(&[(4, 5), (1, 3)], 3)
+    } else if f.any(SHARP) && f.diff(3, 1) {
+        f.w[4]
+    } else if diagonal {
+        f.mix(&[(4, 5), (0, 3)], 3)
+    } else if f.any(EDGE_LEFT) {
+        f.mix(&[(4, 3), (3, 1)], 2)
+    } else if f.any(EDGE_UP) {
+        f.mix(&[(4, 3), (1, 1)], 2)
+    } else if f.any(NEAR_LEFT) {
+        f.mix(&[(4, 5), (3, 3)], 3)
+    } else if f.any(NEAR_UP) {
+        f.mix(&[(4, 5), (1, 3)], 3)
+    } else if f.any(&[
+        (0x0f, 0x0b),
+        (0x5e, 0x0a),
+        (0x2b, 0x0b),
+        (0xbe, 0x0a),
+        (0x7a, 0x0a),
+        (0xee, 0x0a),
+    ]) {
+        f.mix(&[(1, 1), (3, 1)], 1)
+    } else if f.any(CORNER_TOP) || f.any(CORNER_LEFT) {
+        f.mix(&[(4, 5), (0, 3)], 3)
+    } else {
+        f.mix(&[(4, 2), (1, 1), (3, 1)], 2)
+    };
+
+    let top = if edge_up {
+        f.mix(&[(4, 7), (3, 1)], 3)
+    } else if sharp_edges {
+        f.w[4]
+    } else if diagonal {
+        f.mix(&[(4, 3), (0, 1)], 2)
+    } else if f.p(0x2f, 0x2f) {
+        f.w[4]
+    } else if f.p(0x0a, 0x00) {
+        f.mix(&[(4, 5), (1, 2), (3, 1)], 3)
+    } else if f.p(0x0b, 0x08) {
+        f.mix(&[(4, 5), (1, 2), (0, 1)], 3)
+    } else if f.p(0x0b, 0x09) {
+        f.mix(&[(4, 5), (1, 3)], 3)
+    } else if f.any(EDGE_UP) {
+        f.mix(&[(1, 3), (4, 1)], 2)
+    } else if f.any(SLOPE_LEFT) {
+        f.mix(&[(1, 2), (4, 1), (3, 1)], 2)
+    } else if f.any(SLOPE_UP) {
+        f.mix(&[(1, 5), (3, 3)], 3)
+    } else if f.any(NEAR_LEFT) {
+        f.mix(&[(4, 7), (3, 1)], 3)
+    } else if f.any(CORNER_TOP) {
+        f.mix(&[(4, 3), (0, 1)], 2)
+    } else if f.any(BEVEL) {
+        f.mix(&[(1, 1), (4, 1)], 1)
+    } else {
+        f.mix(&[(4, 3), (1, 1)], 2)
+    };
+
+    let left = if edge_left {
+        f.mix(&[(4, 7), (1, 1)], 3)
+    } else if sharp_edges {
+        f.w[4]
+    } else if diagonal {
+        f.mix(&[(4, 3), (0, 1)], 2)
+    } else if f.p(0x2f, 0x2f) {
+        f.w[4]
+    } else if f.p(0x0a, 0x00) {
+        f.mix(&[(4, 5), (3, 2), (1, 1)], 3)
+    } else if f.p(0x0b, 0x02) {
+        f.mix(&[(4, 5), (3, 2), (0, 1)], 3)
+    } else if f.p(0x0b, 0x03) {
+        f.mix(&[(4, 5), (3, 3)], 3)
+    } else if f.any(EDGE_LEFT) {
+        f.mix(&[(3, 3), (4, 1)], 2)
+    } else if f.any(SLOPE_UP) {
+        f.mix(&[(3, 2), (4, 1), (1, 1)], 2)
+    } else if f.any(SLOPE_LEFT) {
+        f.mix(&[(3, 5), (1, 3)], 3)
+    } else if f.any(NEAR_UP) {
+        f.mix(&[(4, 7), (1, 1)], 3)
+    } else if f.any(CORNER_LEFT) {
+        f.mix(&[(4, 3), (0, 1)], 2)
+    } else if f.any(BEVEL) {
+        f.mix(&[(3, 1), (4, 1)], 1)
+    } else {
+        f.mix(&[(4, 3), (3, 1)], 2)
+    };
+
+    let inner =
+        if f.any(&[(0x7f, 0x2b), (0xef, 0xab), (0xbf, 0x8f), (0x7f, 0x0f)])
+            && f.diff(3, 1)
+        {
+            f.w[4]
+        } else if diagonal {
+            f.mix(&[(4, 7), (0, 1)], 3)
+        } else if f.p(0x0b, 0x03) {
+            f.mix(&[(4, 7), (3, 1)], 3)
+        } else if f.p(0x0b, 0x09) {
+            f.mix(&[(4, 7), (1, 1)], 3)
+        } else if f.p(0x0a, 0x00) || f.any(SLOPE_UP) || f.any(SLOPE_LEFT) {
+            f.mix(&[(4, 6), (3, 1), (1, 1)], 3)
+        } else if f.any(CORNER_TOP) || f.any(CORNER_LEFT) {
+            f.mix(&[(4, 7), (0, 1)], 3)
+        } else {
+            f.w[4]
+        };
+
+    [[corner, top], [left, inner]]
+}
+
+fn xbr(image: &Image, x: usize, y: usize) -> [[u32; 2]; 2] {
+    let e = image.get(x, y, 0, 0);
+    let mut block = [[e; 2]; 2];
+    for (idx, (sx, sy)) in
+        [(-1, -1), (1, -1), (-1, 1), (1, 1)].into_iter().enumerate()
+    {
+        // neighbors as seen from the bottom right corner, mirrored for the
+        // others
+        let p = |dx: isize, dy: isize| image.get(x, y, dx * sx, dy * sy);
+        let (b, c, d, f) = (p(0, -1), p(1, -1), p(-1, 0), p(1, 0));
+        let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+        let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+
+        // total contrast along each diagonal, the edge runs along the
+        // smoother one
+        let along = distance(e, c)
+            + distance(e, g)
+            + distance(i, f4)
+            + distance(i, h5)
+            + 4 * distance(h, f);
+        let across = distance(h, d)
+            + distance(h, i5)
+            + distance(f, i4)
+            + distance(f, b)
+            + 4 * distance(e, i);
+        if along < across {
+            let new = if distance(e, f) <= distance(e, h) {
+                f
+            } else {
+                h
+            };
+            block[idx / 2][idx % 2] = blend(e, new, 0.5);
+        }
+    }
+    block
+}
+
+// Helpers
+// The integer YUV approximation from hq2x
+fn yuv(p: u32) -> (i32, i32, i32) {
+    let [_, r, g, b] = p.to_be_bytes();
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    ((r + g + b) / 4, (r - b) / 4, (2 * g - r - b) / 8)
+}
+
+// hqx thresholds: Y 48, U 7, V 6
+fn similar(a: u32, b: u32) -> bool {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    (ya - yb).abs() <= 48 && (ua - ub).abs() <= 7 && (va - vb).abs() <= 6
+}
+
+fn distance(a: u32, b: u32) -> i32 {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    48 * (ya - yb).abs() + 7 * (ua - ub).abs() + 6 * (va - vb).abs()
+}
+
+fn blend(a: u32, b: u32, t: f32) -> u32 {
+    let a = a.to_be_bytes();
+    let b = b.to_be_bytes();
+    let mix = |c: usize| {
+        (a[c] as f32 + (b[c] as f32 - a[c] as f32) * t).round() as u8
+    };
+    u32::from_be_bytes([0, mix(1), mix(2), mix(3)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Upscaler;
+
+    const LIGHT: u32 = 0xf0c8a0;
+    const DARK: u32 = 0x102030;
+
+    // upscales a 3 x 3 image and returns the block of its center pixel
+    fn center_block(upscaler: Upscaler, pixels: [u32; 9]) -> Vec<Vec<u32>> {
+        let src: Vec<u8> = pixels
+            .iter()
+            .flat_map(|p| p.to_be_bytes()[1..].to_vec())
+            .collect();
+        let n = upscaler.factor();
+        let out = upscaler.apply(&src, 3, 3);
+        (n..2 * n)
+            .map(|y| {
+                (n..2 * n)
+                    .map(|x| {
+                        let i = (y * 3 * n + x) * 3;
+                        u32::from_be_bytes([0, out[i], out[i + 1], out[i + 2]])
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hqx_keeps_flat_areas() {
+        for upscaler in [Upscaler::HQ2X, Upscaler::HQ3X, Upscaler::HQ4X] {
+            let n = upscaler.factor();
+            assert_eq!(
+                center_block(upscaler, [LIGHT; 9]),
+                vec![vec![LIGHT; n]; n]
+            );
+        }
+    }
+
+    // The expected blocks below are the entries of the original hqNx case
+    // tables for these patterns
+
+    #[test]
+    fn hqx_lone_pixel() {
+        // case 255 with all neighbors alike, only the corners take in some
+        // of the neighbors, (14c + l + u) / 16 at 2x and (2c + l + u) / 4
+        // at 3x and 4x
+        let mut pixels = [LIGHT; 9];
+        pixels[4] = DARK;
+        let (c, c2, c4) = (DARK, 0x2c353e, 0x807468);
+        assert_eq!(center_block(Upscaler::HQ2X, pixels), [[c2, c2], [c2, c2]]);
+        assert_eq!(
+            center_block(Upscaler::HQ3X, pixels),
+            [[c4, c, c4], [c, c, c], [c4, c, c4]]
+        );
+        assert_eq!(
+            center_block(Upscaler::HQ4X, pixels),
+            [[c4, c, c, c4], [c, c, c, c], [c, c, c, c], [c4, c, c, c4]]
+        );
+    }
+
+    #[test]
+    fn hqx_diagonal() {
+        // case 19: the top left, top and right neighbors are dark and
+        // alike, so an edge cuts across the top right of the block
+        let pixels =
+            [DARK, DARK, LIGHT, LIGHT, LIGHT, DARK, LIGHT, LIGHT, LIGHT];
+        let (l, d) = (LIGHT, DARK);
+        // 3 parts light to 1 dark, and 1 light to 3 dark
+        let (ld, dl) = (0xb89e84, 0x484a4c);
+        assert_eq!(center_block(Upscaler::HQ2X, pixels), [[ld, dl], [l, l]]);
+        assert_eq!(
+            center_block(Upscaler::HQ3X, pixels),
+            [[ld, dl, d], [l, l, ld], [l, l, l]]
+        );
+        assert_eq!(
+            center_block(Upscaler::HQ4X, pixels),
+            [[ld, dl, d, d], [l, l, ld, dl], [l, l, l, l], [l, l, l, l]]
+        );
+    }
+
+    #[test]
+    fn scale2x_edges() {
+        let (l, d) = (LIGHT, DARK);
+        // the top and left neighbors match, so the top left corner takes
+        // their color
+        let pixels = [l, d, l, d, l, l, l, l, l];
+        assert_eq!(center_block(Upscaler::SCALE2X, pixels), [[d, l], [l, l]]);
+        // but not when the top and bottom neighbors match as well
+        let pixels = [l, d, l, d, l, l, l, d, l];
+        assert_eq!(center_block(Upscaler::SCALE2X, pixels), [[l, l], [l, l]]);
+    }
+
+    #[test]
+    fn scale3x_edges() {
+        let (l, d) = (LIGHT, DARK);
+        // the corner follows the same rule as scale2x, the edge between
+        // only when the far corner differs from the center
+        let pixels = [l, d, l, d, l, l, l, l, l];
+        assert_eq!(
+            center_block(Upscaler::SCALE3X, pixels),
+            [[d, l, l], [l, l, l], [l, l, l]]
+        );
+        let pixels = [l, d, d, d, l, l, l, l, l];
+        assert_eq!(
+            center_block(Upscaler::SCALE3X, pixels),
+            [[d, d, l], [l, l, l], [l, l, l]]
+        );
+    }
+
+    #[test]
+    fn xbr_diagonal() {
+        // the top left, top and left neighbors are dark, so the top left
+        // corner is blended halfway to dark and the rest stays light
+        let (l, d) = (LIGHT, DARK);
+        let pixels = [d, d, l, d, l, l, l, l, l];
+        let half = 0x807468;
+        assert_eq!(center_block(Upscaler::XBR, pixels), [[half, l], [l, l]]);
+    }
+}